@@ -0,0 +1,29 @@
+use std::process::Command;
+
+// Plugin libraries share trait objects with the host, so `dylib::RUSTC_VERSION`
+// and `dylib::FEATURES` record the compiler and the features that shape our
+// types for the two to be checked against each other
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if std::env::var_os("CARGO_FEATURE_DYLIB_PLUGINS").is_none() {
+        return;
+    }
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=IRC_LIB_RUSTC_VERSION={}", version.trim());
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| {
+            let feature = key.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=IRC_LIB_FEATURES={}", features.join(","));
+}
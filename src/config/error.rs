@@ -0,0 +1,28 @@
+use derive_more::From;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, From)]
+pub enum Error {
+    // -- Externals
+    #[from]
+    Io(std::io::Error),
+    /// The file isn't valid TOML or YAML, or doesn't have the expected shape.
+    Parse(String),
+    /// The file's extension is neither `.toml`, `.yaml` nor `.yml`.
+    UnknownFormat(String),
+    /// `field` has a value that can't be used, for `reason`.
+    Invalid { field: String, reason: String },
+}
+
+// region:    --- Error Boilerplate
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        write!(fmt, "{self:?}")
+    }
+}
+
+impl std::error::Error for Error {}
+
+// endregion: --- Error Boilerplate
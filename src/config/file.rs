@@ -0,0 +1,478 @@
+//! Reads a `Config` from a TOML or YAML file:
+//!
+//! ```toml
+//! server = "irc.libera.chat:6697"
+//! tls = true
+//! nick = "rusty"
+//! alt_nicks = ["rusty_", "rusty__"]
+//! user = "rusty"
+//! realname = "Rusty the bot"
+//! capabilities = ["away-notify", "multi-prefix"]
+//! channels = ["#rust", { name = "#secret", key_env = "SECRET_KEY" }]
+//! join_after_login_secs = 10
+//! rejoin_on_kick_secs = 30
+//! watch = ["ferris"]
+//!
+//! [sasl]
+//! account = "rusty"
+//! # or set IRC_SASL_PASSWORD
+//! password_env = "LIBERA_PASSWORD"
+//!
+//! [flood_limit]
+//! burst = 5
+//! interval_secs = 2
+//!
+//! [reconnect]
+//! attempts = 10
+//! delay_secs = 5
+//! max_delay_secs = 300
+//! ```
+//!
+//! Secrets can stay out of the file: `IRC_PASSWORD`, `IRC_SASL_PASSWORD` and
+//! `IRC_NICKSERV_PASSWORD` override `password`, `sasl.password` and
+//! `nickserv_password`, and the `*_env` keys name another variable to read
+//! instead.
+
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::Config;
+use super::error::{Error, Result};
+
+const PASSWORD_VAR: &str = "IRC_PASSWORD";
+const SASL_PASSWORD_VAR: &str = "IRC_SASL_PASSWORD";
+const NICKSERV_PASSWORD_VAR: &str = "IRC_NICKSERV_PASSWORD";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    server: String,
+    #[serde(default)]
+    tls: bool,
+    nick: String,
+    #[serde(default)]
+    alt_nicks: Vec<String>,
+    user: Option<String>,
+    realname: Option<String>,
+    password: Option<String>,
+    password_env: Option<String>,
+    sasl: Option<SaslFile>,
+    nickserv_password: Option<String>,
+    nickserv_password_env: Option<String>,
+    #[serde(default)]
+    channels: Vec<ChannelFile>,
+    join_after_login_secs: Option<f64>,
+    rejoin_on_kick_secs: Option<f64>,
+    #[serde(default)]
+    watch: Vec<String>,
+    ison_interval_secs: Option<f64>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+    flood_limit: Option<FloodLimitFile>,
+    reconnect: Option<ReconnectFile>,
+    max_plugin_failures: Option<usize>,
+    request_timeout_secs: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SaslFile {
+    account: String,
+    password: Option<String>,
+    password_env: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ChannelFile {
+    Name(String),
+    Keyed(KeyedChannelFile),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyedChannelFile {
+    name: String,
+    key: Option<String>,
+    key_env: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FloodLimitFile {
+    burst: u32,
+    interval_secs: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReconnectFile {
+    attempts: u32,
+    delay_secs: Option<f64>,
+    max_delay_secs: Option<f64>,
+}
+
+impl Config {
+    /// Reads a config file, TOML or YAML depending on its extension.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Config::from_toml(&source),
+            Some("yaml" | "yml") => Config::from_yaml(&source),
+            _ => Err(Error::UnknownFormat(path.display().to_string())),
+        }
+    }
+
+    pub fn from_toml(source: &str) -> Result<Config> {
+        let file = toml::from_str(source).map_err(|e| Error::Parse(e.to_string()))?;
+        load(file, &|name| std::env::var(name).ok())
+    }
+
+    pub fn from_yaml(source: &str) -> Result<Config> {
+        let file = serde_yaml::from_str(source).map_err(|e| Error::Parse(e.to_string()))?;
+        load(file, &|name| std::env::var(name).ok())
+    }
+}
+
+// `env` looks variables up, so tests don't have to touch the real environment
+fn load(file: File, env: &dyn Fn(&str) -> Option<String>) -> Result<Config> {
+    if file.tls && !cfg!(feature = "tls") {
+        return Err(invalid("tls", "needs the `tls` feature"));
+    }
+
+    let mut config = Config::new(&file.server);
+    config.tls = file.tls;
+
+    config = config.nick(&file.nick);
+    for nick in &file.alt_nicks {
+        config = config.alt_nick(nick);
+    }
+    if let Some(user) = &file.user {
+        config = config.user(user);
+    }
+    if let Some(realname) = &file.realname {
+        config = config.realname(realname);
+    }
+
+    let password = secret(
+        "password",
+        file.password,
+        file.password_env.as_deref(),
+        PASSWORD_VAR,
+        env,
+    )?;
+    if let Some(password) = password {
+        config = config.password(&password);
+    }
+
+    if let Some(sasl) = file.sasl {
+        let password = secret(
+            "sasl.password",
+            sasl.password,
+            sasl.password_env.as_deref(),
+            SASL_PASSWORD_VAR,
+            env,
+        )?
+        .ok_or_else(|| invalid("sasl.password", "is required"))?;
+        config = config.sasl(&sasl.account, &password);
+    }
+
+    let nickserv = secret(
+        "nickserv_password",
+        file.nickserv_password,
+        file.nickserv_password_env.as_deref(),
+        NICKSERV_PASSWORD_VAR,
+        env,
+    )?;
+    if let Some(password) = nickserv {
+        config = config.nickserv(&password);
+    }
+
+    for (index, channel) in file.channels.into_iter().enumerate() {
+        let field = format!("channels[{index}]");
+        config = match channel {
+            ChannelFile::Name(name) => config.channel(&name),
+            ChannelFile::Keyed(channel) => {
+                let key_field = format!("{field}.key");
+                match secret(&key_field, channel.key, channel.key_env.as_deref(), "", env)? {
+                    Some(key) => config.channel_with_key(&channel.name, &key),
+                    None => config.channel(&channel.name),
+                }
+            }
+        };
+    }
+
+    if let Some(timeout) = file.join_after_login_secs {
+        config = config.join_after_login(seconds("join_after_login_secs", timeout)?);
+    }
+    if let Some(delay) = file.rejoin_on_kick_secs {
+        config = config.rejoin_on_kick(seconds("rejoin_on_kick_secs", delay)?);
+    }
+
+    for nick in &file.watch {
+        config = config.watch(nick);
+    }
+    if let Some(interval) = file.ison_interval_secs {
+        config = config.ison_interval(seconds("ison_interval_secs", interval)?);
+    }
+
+    for capability in &file.capabilities {
+        config = config.capability(capability);
+    }
+
+    if let Some(flood_limit) = file.flood_limit {
+        let interval = seconds("flood_limit.interval_secs", flood_limit.interval_secs)?;
+        config = config.flood_limit(flood_limit.burst, interval);
+    }
+
+    if let Some(reconnect) = file.reconnect {
+        let defaults = config.reconnect;
+        let delay = reconnect
+            .delay_secs
+            .map(|delay| seconds("reconnect.delay_secs", delay))
+            .transpose()?
+            .unwrap_or(defaults.delay);
+        let max_delay = reconnect
+            .max_delay_secs
+            .map(|delay| seconds("reconnect.max_delay_secs", delay))
+            .transpose()?
+            .unwrap_or(defaults.max_delay.max(delay));
+        config = config.reconnect(reconnect.attempts, delay, max_delay);
+    }
+
+    if let Some(max) = file.max_plugin_failures {
+        config = config.max_plugin_failures(max);
+    }
+    if let Some(timeout) = file.request_timeout_secs {
+        config = config.request_timeout(seconds("request_timeout_secs", timeout)?);
+    }
+
+    // The same rules as for a `Config` built in code
+    config.validate()?;
+    Ok(config)
+}
+
+fn invalid(field: &str, reason: &str) -> Error {
+    Error::Invalid {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+// The variable wins over the file, and one named in the file must be set
+// unless the file has a value to fall back on
+fn secret(
+    field: &str,
+    value: Option<String>,
+    var: Option<&str>,
+    default_var: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<Option<String>> {
+    let name = var.unwrap_or(default_var);
+    if let Some(value) = (!name.is_empty()).then(|| env(name)).flatten() {
+        return Ok(Some(value));
+    }
+    if var.is_some() && value.is_none() {
+        return Err(invalid(
+            &format!("{field}_env"),
+            &format!("{name} is not set"),
+        ));
+    }
+
+    Ok(value)
+}
+
+fn seconds(field: &str, seconds: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| invalid(field, "must be a positive number of seconds"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FloodLimit, ReconnectPolicy, Sasl, Secret};
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    fn toml_config(source: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<Config> {
+        load(toml::from_str(source).unwrap(), env)
+    }
+
+    fn invalid_field(result: Result<Config>) -> String {
+        match result {
+            Err(Error::Invalid { field, .. }) => field,
+            other => panic!("expected a validation error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_toml() {
+        let config = toml_config(
+            r##"
+            server = "irc.example.com:6667"
+            nick = "rusty"
+            alt_nicks = ["rusty_"]
+            realname = "Rusty the bot"
+            password = "correct horse"
+            capabilities = ["away-notify"]
+            channels = ["#rust", { name = "#secret", key = "hunter2" }]
+            nickserv_password = "hunter3"
+            join_after_login_secs = 10
+            rejoin_on_kick_secs = 2.5
+            watch = ["ferris"]
+            ison_interval_secs = 30
+
+            [sasl]
+            account = "rusty"
+            password = "from the file"
+
+            [flood_limit]
+            burst = 5
+            interval_secs = 0.5
+
+            [reconnect]
+            attempts = 3
+            "##,
+            &no_env,
+        )
+        .unwrap();
+
+        assert_eq!(config.server, "irc.example.com:6667");
+        assert_eq!(config.alt_nicks, vec!["rusty_"]);
+        assert_eq!(config.realname, "Rusty the bot");
+        assert_eq!(config.password, Some(Secret("correct horse".to_string())));
+        assert_eq!(config.capabilities, vec!["away-notify"]);
+        assert_eq!(config.channels.len(), 2);
+        assert_eq!(config.keys["#secret"], Secret("hunter2".to_string()));
+        assert_eq!(config.nickserv, Some(Secret("hunter3".to_string())));
+        assert_eq!(config.join_after_login, Some(Duration::from_secs(10)));
+        assert_eq!(config.rejoin_on_kick, Some(Duration::from_millis(2500)));
+        assert_eq!(config.watch, vec!["ferris"]);
+        assert_eq!(config.ison_interval, Duration::from_secs(30));
+        assert_eq!(
+            config.sasl,
+            Some(Sasl {
+                account: "rusty".to_string(),
+                password: Secret("from the file".to_string()),
+            })
+        );
+        assert_eq!(
+            config.flood_limit,
+            Some(FloodLimit {
+                burst: 5,
+                interval: Duration::from_millis(500),
+            })
+        );
+        assert_eq!(
+            config.reconnect,
+            ReconnectPolicy {
+                attempts: 3,
+                ..ReconnectPolicy::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_yaml() {
+        let config = Config::from_yaml(
+            "
+            server: irc.example.com:6667
+            nick: rusty
+            channels:
+              - '#rust'
+              - name: '#secret'
+                key: hunter2
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(config.nick, "rusty");
+        assert_eq!(config.channels.len(), 2);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env = |name: &str| match name {
+            "IRC_SASL_PASSWORD" => Some("from the environment".to_string()),
+            "KEY" => Some("key-from-env".to_string()),
+            _ => None,
+        };
+        let config = toml_config(
+            r##"
+            server = "irc.example.com:6667"
+            nick = "rusty"
+            channels = [{ name = "#secret", key_env = "KEY" }]
+            sasl = { account = "rusty", password = "from the file" }
+            "##,
+            &env,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.sasl.unwrap().password,
+            Secret("from the environment".to_string())
+        );
+        assert_eq!(config.keys["#secret"], Secret("key-from-env".to_string()));
+
+        assert_eq!(
+            invalid_field(toml_config(
+                r#"
+                server = "irc.example.com:6667"
+                nick = "rusty"
+                password_env = "MISSING"
+                "#,
+                &env,
+            )),
+            "password_env"
+        );
+    }
+
+    #[test]
+    fn test_validation_names_the_field() {
+        let valid = ("irc.example.com:6667", "rusty");
+        for ((server, nick), extra, field) in [
+            (("irc.example.com", "rusty"), "", "server"),
+            (("irc.example.com:6667", "1rusty"), "", "nick"),
+            (valid, r#"alt_nicks = ["ok", "not ok"]"#, "alt_nicks"),
+            (valid, r##"channels = ["#ok", "nope"]"##, "channels"),
+            (valid, r##"channels = [{ name = "#a,b" }]"##, "channels"),
+            (valid, r#"watch = ["a b"]"#, "watch"),
+            (valid, r#"sasl = { account = "rusty" }"#, "sasl.password"),
+            (
+                valid,
+                "flood_limit = { burst = 0, interval_secs = 1 }",
+                "flood_limit",
+            ),
+            (
+                valid,
+                "reconnect = { attempts = 1, delay_secs = -1 }",
+                "reconnect.delay_secs",
+            ),
+        ] {
+            let source = format!("server = {server:?}\nnick = {nick:?}\n{extra}");
+            assert_eq!(invalid_field(toml_config(&source, &no_env)), field);
+        }
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let error = Config::from_toml(
+            r#"
+            server = "irc.example.com:6667"
+            nick = "rusty"
+            nickname = "typo"
+            "#,
+        )
+        .unwrap_err();
+
+        assert!(matches!(error, Error::Parse(message) if message.contains("nickname")));
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::connection::{Connection, IrcConnection};
+use crate::server::{Channel, casefold, commands};
+use crate::{IrcPlugin, OutgoingMiddleware, Server};
+use error::{Error, Result};
+
+pub(crate) mod error;
+#[cfg(feature = "config-file")]
+mod file;
+
+#[derive(Debug)]
+pub struct Config {
+    pub(crate) server: String,
+    pub(crate) tls: bool,
+    pub(crate) nick: String,
+    pub(crate) alt_nicks: Vec<String>,
+    pub(crate) user: String,
+    pub(crate) realname: String,
+    pub(crate) password: Option<Secret>,
+    pub(crate) sasl: Option<Sasl>,
+    pub(crate) nickserv: Option<Secret>,
+    pub(crate) channels: HashMap<String, Channel>,
+    pub(crate) keys: HashMap<String, Secret>,
+    pub(crate) join_after_login: Option<Duration>,
+    pub(crate) rejoin_on_kick: Option<Duration>,
+    pub(crate) watch: Vec<String>,
+    pub(crate) ison_interval: Duration,
+    pub(crate) capabilities: Vec<String>,
+    pub(crate) flood_limit: Option<FloodLimit>,
+    pub(crate) reconnect: ReconnectPolicy,
+    pub(crate) plugins: Vec<Box<dyn IrcPlugin>>,
+    pub(crate) max_plugin_failures: usize,
+    pub(crate) middleware: Vec<Box<dyn OutgoingMiddleware>>,
+    pub(crate) request_timeout: Duration,
+    // Problems builder methods can't report themselves, returned by `build`
+    errors: Vec<Error>,
+}
+
+/// A credential, kept out of `Debug` output.
+#[derive(Clone, PartialEq)]
+pub(crate) struct Secret(pub(crate) String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Credentials for SASL PLAIN.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Sasl {
+    pub(crate) account: String,
+    pub(crate) password: Secret,
+}
+
+/// Lets `burst` messages out at once, then one every `interval`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FloodLimit {
+    pub(crate) burst: u32,
+    pub(crate) interval: Duration,
+}
+
+/// How often to reconnect after losing the connection, doubling the delay
+/// after each failed attempt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ReconnectPolicy {
+    pub(crate) attempts: u32,
+    pub(crate) delay: Duration,
+    pub(crate) max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// How long to wait before attempt number `attempt`, counting from 0, if
+    /// there is one.
+    pub(crate) fn delay(&self, attempt: u32) -> Option<Duration> {
+        (attempt < self.attempts)
+            .then(|| self.delay.saturating_mul(2u32.saturating_pow(attempt)))
+            .map(|delay| delay.min(self.max_delay))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            attempts: 0,
+            delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Config {
+    pub fn new(server: &str) -> Self {
+        Config {
+            server: server.to_owned(),
+            tls: false,
+            nick: "User".to_owned(),
+            alt_nicks: Vec::new(),
+            user: "rusty".to_owned(),
+            realname: "rusty".to_owned(),
+            password: None,
+            sasl: None,
+            nickserv: None,
+            channels: HashMap::new(),
+            keys: HashMap::new(),
+            join_after_login: None,
+            rejoin_on_kick: None,
+            watch: Vec::new(),
+            ison_interval: Duration::from_secs(60),
+            capabilities: Vec::new(),
+            flood_limit: None,
+            reconnect: ReconnectPolicy::default(),
+            plugins: Vec::new(),
+            max_plugin_failures: 3,
+            middleware: Vec::new(),
+            request_timeout: Duration::from_secs(30),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Connects over TLS, checking the certificate against the Mozilla root store.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+
+        self
+    }
+
+    pub fn nick(mut self, nick: &str) -> Self {
+        self.nick = nick.to_owned();
+
+        self
+    }
+
+    /// Nicks to try in order when `nick` is taken or refused. After the last
+    /// one, underscores are appended up to the server's `NICKLEN` before
+    /// giving up with `NicksExhausted`.
+    pub fn alt_nick(mut self, nick: &str) -> Self {
+        self.alt_nicks.push(nick.to_owned());
+
+        self
+    }
+
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = user.to_owned();
+
+        self
+    }
+
+    pub fn realname(mut self, realname: &str) -> Self {
+        self.realname = realname.to_owned();
+
+        self
+    }
+
+    /// The server password, sent as `PASS` before registering.
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(Secret(password.to_owned()));
+
+        self
+    }
+
+    /// Logs in to `account` with SASL PLAIN while registering.
+    pub fn sasl(mut self, account: &str, password: &str) -> Self {
+        self.sasl = Some(Sasl {
+            account: account.to_owned(),
+            password: Secret(password.to_owned()),
+        });
+
+        self
+    }
+
+    /// Identifies to NickServ as `nick` with `password` once registered, for
+    /// networks or accounts without SASL.
+    pub fn nickserv(mut self, password: &str) -> Self {
+        self.nickserv = Some(Secret(password.to_owned()));
+
+        self
+    }
+
+    /// Joins `channel` once registered. Its name has to start with one of
+    /// `#&+!`, an invalid one makes `build` fail.
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.add_channel(channel);
+
+        self
+    }
+
+    /// Joins a channel protected by `key`.
+    pub fn channel_with_key(mut self, channel: &str, key: &str) -> Self {
+        if let Some(name) = self.add_channel(channel) {
+            self.keys.insert(name, Secret(key.to_owned()));
+        }
+
+        self
+    }
+
+    /// Holds the channels back until services log us in, through SASL or
+    /// `nickserv`, so we join with our cloak and account. Joins anyway if that
+    /// hasn't happened `timeout` after registering.
+    pub fn join_after_login(mut self, timeout: Duration) -> Self {
+        self.join_after_login = Some(timeout);
+
+        self
+    }
+
+    /// Joins a channel again `delay` after being kicked from it.
+    pub fn rejoin_on_kick(mut self, delay: Duration) -> Self {
+        self.rejoin_on_kick = Some(delay);
+
+        self
+    }
+
+    /// Reports when `nick` comes online or goes offline, see `Server::watch`.
+    pub fn watch(mut self, nick: &str) -> Self {
+        self.watch.push(nick.to_owned());
+
+        self
+    }
+
+    /// How often watched nicks are polled with `ISON` on servers without
+    /// `MONITOR`. Defaults to 60 seconds.
+    pub fn ison_interval(mut self, interval: Duration) -> Self {
+        self.ison_interval = interval;
+
+        self
+    }
+
+    fn add_channel(&mut self, channel: &str) -> Option<String> {
+        let parsed = commands::valid_channel(channel)
+            .ok()
+            .and_then(|channel| channel.parse::<Channel>().ok());
+        let Some(channel) = parsed else {
+            self.errors.push(Error::Invalid {
+                field: "channels".to_string(),
+                reason: format!("{channel:?} is not a valid channel name"),
+            });
+            return None;
+        };
+
+        let name = casefold(&channel.name);
+        self.channels.insert(name.clone(), channel);
+        Some(name)
+    }
+
+    /// Requests an IRCv3 capability such as `away-notify` while registering,
+    /// if the server offers it.
+    pub fn capability(mut self, capability: &str) -> Self {
+        self.capabilities.push(capability.to_owned());
+
+        self
+    }
+
+    /// Holds back messages sent faster than `burst` at once and one every
+    /// `interval` after that, so the server doesn't disconnect us for flooding.
+    pub fn flood_limit(mut self, burst: u32, interval: Duration) -> Self {
+        self.flood_limit = Some(FloodLimit { burst, interval });
+
+        self
+    }
+
+    /// Reconnects up to `attempts` times after losing the connection, waiting
+    /// `delay` and doubling it after every failure up to `max_delay`. Doesn't
+    /// reconnect by default.
+    pub fn reconnect(mut self, attempts: u32, delay: Duration, max_delay: Duration) -> Self {
+        self.reconnect = ReconnectPolicy {
+            attempts,
+            delay,
+            max_delay,
+        };
+
+        self
+    }
+
+    pub fn register_plugin(mut self, plugin: impl IrcPlugin + 'static) -> Self {
+        self.plugins.push(Box::new(plugin));
+
+        self
+    }
+
+    /// Appends a step to the chain outgoing messages go through, in registration order.
+    pub fn middleware(mut self, middleware: impl OutgoingMiddleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+
+        self
+    }
+
+    /// How many panics a plugin gets before it is disabled and unloaded.
+    /// Defaults to 3.
+    pub fn max_plugin_failures(mut self, max: usize) -> Self {
+        self.max_plugin_failures = max;
+
+        self
+    }
+
+    /// How long requests such as `Client::whois` wait for an answer. Defaults to 30 seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+
+        self
+    }
+
+    /// Checks the configuration and creates the `Server`, which connects once it runs.
+    pub fn build(self) -> Result<Server> {
+        let connection = Connection::new(self.tls);
+        self.build_with(Box::new(connection))
+    }
+
+    pub(crate) fn build_with(mut self, connection: Box<dyn IrcConnection>) -> Result<Server> {
+        self.validate()?;
+        Ok(Server::new(self, connection))
+    }
+
+    // Reports the first problem found, naming the builder method's field
+    fn validate(&mut self) -> Result<()> {
+        if !self.errors.is_empty() {
+            return Err(self.errors.remove(0));
+        }
+
+        valid_address(&self.server)?;
+
+        for (field, nick) in std::iter::once(("nick", &self.nick))
+            .chain(self.alt_nicks.iter().map(|nick| ("alt_nicks", nick)))
+            .chain(self.watch.iter().map(|nick| ("watch", nick)))
+        {
+            commands::valid_nick(nick)
+                .map_err(|_| invalid(field, format!("{nick:?} is not a valid nick")))?;
+        }
+        for (index, nick) in self.alt_nicks.iter().enumerate() {
+            if *nick == self.nick || self.alt_nicks[..index].contains(nick) {
+                return Err(invalid("alt_nicks", format!("{nick:?} is listed twice")));
+            }
+        }
+
+        commands::valid_word(&self.user).map_err(|_| invalid("user", "must be one word"))?;
+        valid_line("realname", &self.realname)?;
+        if let Some(password) = &self.password {
+            valid_line("password", &password.0)?;
+        }
+
+        match &self.sasl {
+            Some(sasl) => {
+                commands::valid_word(&sasl.account)
+                    .map_err(|_| invalid("sasl.account", "must be one word"))?;
+                valid_line("sasl.password", &sasl.password.0)?;
+            }
+            None if self.capabilities.iter().any(|cap| cap == "sasl") => {
+                return Err(invalid(
+                    "capabilities",
+                    "sasl is requested without credentials from `sasl`",
+                ));
+            }
+            None => (),
+        }
+        if let Some(password) = &self.nickserv {
+            commands::valid_word(&password.0)
+                .map_err(|_| invalid("nickserv", "must be one word"))?;
+        }
+
+        for capability in &self.capabilities {
+            commands::valid_word(capability).map_err(|_| {
+                invalid(
+                    "capabilities",
+                    format!("{capability:?} is not a valid capability"),
+                )
+            })?;
+        }
+        for (channel, key) in &self.keys {
+            commands::valid_word(&key.0).map_err(|_| {
+                invalid(
+                    "channels",
+                    format!("the key for {channel} must be one word"),
+                )
+            })?;
+        }
+
+        if let Some(limit) = self.flood_limit
+            && (limit.burst == 0 || limit.interval.is_zero())
+        {
+            return Err(invalid(
+                "flood_limit",
+                "needs a burst and interval above zero",
+            ));
+        }
+        if self.reconnect.attempts > 0 && self.reconnect.max_delay < self.reconnect.delay {
+            return Err(invalid(
+                "reconnect",
+                "max_delay must not be shorter than delay",
+            ));
+        }
+        if self.request_timeout.is_zero() {
+            return Err(invalid("request_timeout", "must be above zero"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks `address` is `host:port`, with IPv6 hosts in brackets.
+fn valid_address(address: &str) -> Result<()> {
+    let valid = address.rsplit_once(':').is_some_and(|(host, port)| {
+        let valid_host = match host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+        {
+            Some(ipv6) => ipv6.parse::<std::net::Ipv6Addr>().is_ok(),
+            None => !host.is_empty() && !host.contains([' ', '/', ':']),
+        };
+        valid_host && port.parse::<u16>().is_ok_and(|port| port != 0)
+    });
+    if !valid {
+        return Err(invalid("server", format!("{address:?} is not host:port")));
+    }
+
+    Ok(())
+}
+
+fn valid_line(field: &str, text: &str) -> Result<()> {
+    if text.is_empty() || text.contains(['\r', '\n', '\0']) {
+        return Err(invalid(field, "must be a single, non empty line"));
+    }
+    Ok(())
+}
+
+fn invalid(field: &str, reason: impl Into<String>) -> Error {
+    Error::Invalid {
+        field: field.to_string(),
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = Config::new("irc.example.com:6667")
+            .nick("rusty")
+            .user("rusty")
+            .channel("#channel")
+            .channel("#other");
+
+        assert_eq!(config.server, "irc.example.com:6667");
+        assert_eq!(config.nick, "rusty");
+        assert_eq!(config.user, "rusty");
+        assert_eq!(config.channels.len(), 2);
+
+        assert!(config.build().is_ok());
+    }
+
+    fn invalid_field(config: Config) -> String {
+        match config.build() {
+            Err(Error::Invalid { field, .. }) => field,
+            other => panic!("expected a validation error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validation() {
+        let valid = || Config::new("irc.example.com:6667").nick("rusty");
+        assert!(valid().build().is_ok());
+        assert!(Config::new("[::1]:6697").nick("rusty").build().is_ok());
+
+        for server in [
+            "irc.example.com",
+            ":6667",
+            "irc.example.com:0",
+            "a b:1",
+            "::1:6667",
+        ] {
+            assert_eq!(
+                invalid_field(Config::new(server).nick("rusty")),
+                "server",
+                "{server:?} should be rejected"
+            );
+        }
+        for nick in ["", "1rusty", "#rusty", "rus ty", "rusty!u@h"] {
+            assert_eq!(invalid_field(valid().nick(nick)), "nick", "{nick:?}");
+        }
+        for channel in ["rust", "#", "#a b", "#a,b", "#bell\u{7}"] {
+            assert_eq!(
+                invalid_field(valid().channel(channel)),
+                "channels",
+                "{channel:?}"
+            );
+        }
+        assert!(
+            valid()
+                .channel("&local")
+                .channel("+modeless")
+                .build()
+                .is_ok()
+        );
+
+        assert_eq!(invalid_field(valid().alt_nick("rusty")), "alt_nicks");
+        assert_eq!(invalid_field(valid().user("two words")), "user");
+        assert_eq!(
+            invalid_field(valid().channel_with_key("#secret", "two words")),
+            "channels"
+        );
+        assert_eq!(invalid_field(valid().capability("sasl")), "capabilities");
+        assert_eq!(invalid_field(valid().nickserv("two words")), "nickserv");
+        assert_eq!(invalid_field(valid().watch("a,b")), "watch");
+        assert_eq!(
+            invalid_field(valid().flood_limit(0, Duration::from_secs(1))),
+            "flood_limit"
+        );
+        assert_eq!(
+            invalid_field(valid().reconnect(1, Duration::from_secs(10), Duration::from_secs(1))),
+            "reconnect"
+        );
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        let policy = ReconnectPolicy {
+            attempts: 4,
+            delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(12),
+        };
+
+        assert_eq!(policy.delay(0), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(1), Some(Duration::from_secs(10)));
+        assert_eq!(policy.delay(3), Some(Duration::from_secs(12)));
+        assert_eq!(policy.delay(4), None);
+        assert_eq!(ReconnectPolicy::default().delay(0), None);
+    }
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let config = Config::new("irc.example.com")
+            .password("hunter2")
+            .sasl("rusty", "hunter3")
+            .nickserv("hunter4");
+
+        let debug = format!("{config:?}");
+        assert!(!debug.contains("hunter"));
+    }
+}
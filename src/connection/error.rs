@@ -12,8 +12,16 @@ pub enum Error {
     Io(std::io::Error),
     #[from]
     MessageParsing(MessageError),
+    #[cfg(feature = "tls")]
+    #[from]
+    Tls(rustls::Error),
+    InvalidServerName(String),
+    /// TLS was asked for without the `tls` feature.
+    TlsUnavailable,
     NotConnected,
     ConnectionClosed,
+    /// The server refused every nick we could register with.
+    NicksExhausted,
 }
 
 // region:    --- Error Boilerplate
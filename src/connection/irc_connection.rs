@@ -1,15 +1,20 @@
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
 use std::{
-    io::{BufRead, BufReader, Write},
+    io::{BufRead, BufReader, Read, Write},
     time::Duration,
 };
 
 use super::error::{Error, Result};
 use crate::message::IrcMessage;
 
+// How long connecting and the TLS handshake may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub(crate) struct Connection {
-    socket: Option<BufReader<TcpStream>>,
+    tls: bool,
+    timeout: Duration,
+    socket: Option<BufReader<Stream>>,
     buffer: String,
 }
 
@@ -21,8 +26,10 @@ pub trait IrcConnection: Send + core::fmt::Debug {
 }
 
 impl Connection {
-    pub(crate) fn new() -> Connection {
+    pub(crate) fn new(tls: bool) -> Connection {
         Connection {
+            tls,
+            timeout: CONNECT_TIMEOUT,
             socket: None,
             buffer: String::new(),
         }
@@ -31,8 +38,20 @@ impl Connection {
 
 impl IrcConnection for Connection {
     fn connect(&mut self, address: String) -> Result<()> {
-        let stream = TcpStream::connect(address)?;
-        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+        debug!(%address, tls = self.tls, "connecting");
+        let stream = connect_tcp(&address, self.timeout)?;
+        // A server that stops answering mid-write is as good as gone
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let stream = if self.tls {
+            Stream::tls(stream, &address)?
+        } else {
+            Stream::Plain(stream)
+        };
+        // Only once the handshake is done, it can't be resumed after a timeout
+        stream
+            .tcp()
+            .set_read_timeout(Some(Duration::from_millis(100)))?;
         self.socket = Some(BufReader::new(stream));
         Ok(())
     }
@@ -40,8 +59,9 @@ impl IrcConnection for Connection {
     fn send_message(&mut self, message: &str) -> Result<()> {
         match &mut self.socket {
             Some(stream) => {
+                trace!(line = %crate::trace::redact(message), "sending");
                 let bytes = &[message.as_bytes(), b"\r\n"].concat();
-                stream.get_ref().write_all(bytes)?;
+                stream.get_mut().write_all(bytes)?;
                 Ok(())
             }
             _ => Err(Error::NotConnected),
@@ -57,10 +77,12 @@ impl IrcConnection for Connection {
                 match stream.read_line(&mut self.buffer) {
                     Ok(0) => {
                         // Connection closed
+                        debug!("connection closed by peer");
                         self.socket = None;
                         Err(Error::ConnectionClosed)
                     }
                     Ok(_) => {
+                        trace!(line = %crate::trace::redact(&self.buffer), "received");
                         let msg: IrcMessage = self.buffer.as_str().parse()?;
                         Ok(Some(msg))
                     }
@@ -70,7 +92,10 @@ impl IrcConnection for Connection {
                                 // Timed out, return so we can do stuff
                                 Ok(None)
                             }
-                            _ => Err(e.into()),
+                            _ => {
+                                warn!(error = %e, "failed reading from socket");
+                                Err(e.into())
+                            }
                         }
                     }
                 }
@@ -79,3 +104,172 @@ impl IrcConnection for Connection {
         }
     }
 }
+
+// Tries each address the name resolves to in turn
+fn connect_tcp(address: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut error = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error
+        .unwrap_or_else(|| std::io::ErrorKind::AddrNotAvailable.into())
+        .into())
+}
+
+#[derive(Debug)]
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    #[cfg(feature = "tls")]
+    fn tls(mut stream: TcpStream, address: &str) -> Result<Stream> {
+        use std::sync::{Arc, OnceLock};
+
+        static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+        let config = CONFIG.get_or_init(|| {
+            let roots =
+                rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            Arc::new(
+                rustls::ClientConfig::builder_with_provider(provider)
+                    .with_safe_default_protocol_versions()
+                    .expect("ring supports the default protocol versions")
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+        });
+
+        let host = address
+            .rsplit_once(':')
+            .map_or(address, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(|_| Error::InvalidServerName(host.to_string()))?;
+        let mut connection = rustls::ClientConnection::new(config.clone(), name)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+
+        Ok(Stream::Tls(Box::new(rustls::StreamOwned::new(
+            connection, stream,
+        ))))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn tls(_stream: TcpStream, _address: &str) -> Result<Stream> {
+        Err(Error::TlsUnavailable)
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_connect_times_out_on_silent_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Accepts, then never says a word
+        let mut connection = Connection::new(true);
+        connection.timeout = Duration::from_millis(200);
+        let started = std::time::Instant::now();
+        let result = connection.connect(address);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        #[cfg(feature = "tls")]
+        assert!(matches!(result, Err(Error::Io(e)) if matches!(
+            e.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        )));
+        #[cfg(not(feature = "tls"))]
+        assert!(matches!(result, Err(Error::TlsUnavailable)));
+        drop(listener);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_handshake_fails_on_plain_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b":irc.example.com NOTICE * :Looking up your hostname\r\n");
+        });
+
+        let result = Connection::new(true).connect(address);
+        assert!(matches!(result, Err(Error::Tls(_) | Error::Io(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_plain_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(b":irc.example.com NOTICE * :hello\r\n")
+                .unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        });
+
+        let mut connection = Connection::new(false);
+        connection.connect(address).unwrap();
+        connection.send_message("NICK rusty").unwrap();
+        let message = loop {
+            if let Some(message) = connection.read().unwrap() {
+                break message;
+            }
+        };
+        assert_eq!(message.to_string(), ":irc.example.com NOTICE * :hello");
+        assert_eq!(server.join().unwrap(), "NICK rusty\r\n");
+    }
+}
@@ -1,6 +1,7 @@
 pub(crate) mod error;
 mod irc_connection;
 mod negotiator;
+pub(crate) mod sasl;
 
 pub(crate) use irc_connection::*;
 pub(crate) use negotiator::Negotiator as ConnectionNegotiator;
@@ -1,23 +1,127 @@
-use crate::{Config, server::Channel};
+use std::collections::{HashMap, VecDeque};
+
+use crate::Config;
+use crate::config::Secret;
+use crate::message::MAX_LINE;
 
 pub struct Negotiator {
-    channels: std::collections::hash_map::IntoIter<String, Channel>,
+    joins: VecDeque<(String, Option<Secret>)>,
+    max_targets: Option<usize>,
     done: bool,
-    messages: std::vec::IntoIter<String>,
+    messages: VecDeque<String>,
+    // Capabilities to request once the server lists what it offers
+    wanted: Option<Vec<String>>,
 }
 
 impl Negotiator {
     pub fn new(config: &Config) -> Self {
+        let mut messages = VecDeque::new();
+        if let Some(password) = &config.password {
+            messages.push_back(format!("PASS :{}", password.0));
+        }
+        messages.push_back("CAP LS 302".to_string());
+        messages.push_back(format!("USER {} 0 * :{}", config.user, config.realname));
+        messages.push_back(format!("NICK {}", config.nick));
+
+        let mut wanted = config.capabilities.clone();
+        if config.sasl.is_some() && !wanted.iter().any(|cap| cap == "sasl") {
+            wanted.push("sasl".to_string());
+        }
+
+        // Keyed channels go first, so their keys line up in a shared `JOIN`
+        let mut joins: Vec<_> = config
+            .channels
+            .iter()
+            .map(|(name, channel)| (channel.to_string(), config.keys.get(name).cloned()))
+            .collect();
+        joins
+            .sort_by(|(a, a_key), (b, b_key)| b_key.is_some().cmp(&a_key.is_some()).then(a.cmp(b)));
+
         Negotiator {
-            channels: config.channels.clone().into_iter(),
+            joins: joins.into(),
+            max_targets: None,
             done: false,
-            messages: vec![
-                "CAP LS 302".to_string(),
-                format!("USER {} 0 * None", config.user),
-                format!("NICK {}", config.nick),
-                "CAP END".to_string(),
-            ]
-            .into_iter(),
+            messages,
+            wanted: Some(wanted),
+        }
+    }
+
+    /// Whether the next step joins channels, which has to wait until we are registered.
+    pub fn joins_next(&mut self) -> bool {
+        self.messages.is_empty() && self.wanted.is_none() && !self.joins.is_empty()
+    }
+
+    /// Whether negotiation waits for the server's answer to `CAP LS`.
+    pub fn awaits_caps(&self) -> bool {
+        self.messages.is_empty() && self.wanted.is_some()
+    }
+
+    /// Requests those of the wanted capabilities the server lists, ending
+    /// capability negotiation unless SASL has to log in first.
+    pub fn listed(&mut self, available: &HashMap<String, String>) {
+        let Some(wanted) = self.wanted.take() else {
+            return;
+        };
+        let (requested, missing): (Vec<String>, Vec<String>) = wanted
+            .into_iter()
+            .partition(|cap| available.contains_key(cap));
+        if !missing.is_empty() {
+            warn!(capabilities = %missing.join(" "), "server doesn't offer capabilities");
+        }
+
+        let sasl = requested.iter().any(|cap| cap == "sasl");
+        if !requested.is_empty() {
+            self.messages
+                .push_back(format!("CAP REQ :{}", requested.join(" ")));
+        }
+        // With SASL, `Server::authenticate` sends it once the login is done
+        if !sasl {
+            self.messages.push_back("CAP END".to_string());
+        }
+    }
+
+    /// Gives up on capabilities once a server that doesn't know `CAP`
+    /// registers us without listing any.
+    pub fn skip_caps(&mut self) {
+        if self.wanted.take().is_some_and(|wanted| !wanted.is_empty()) {
+            warn!("server doesn't support capabilities");
+        }
+    }
+
+    /// Caps how many channels go in one `JOIN`, as the server's `TARGMAX` says.
+    pub fn limit_joins(&mut self, max_targets: Option<usize>) {
+        self.max_targets = max_targets;
+    }
+
+    // As many channels as fit in one line and the server's target limit
+    fn next_join(&mut self) -> String {
+        let limit = self.max_targets.unwrap_or(usize::MAX).max(1);
+        let mut channels = Vec::new();
+        let mut keys = Vec::new();
+        let mut length = "JOIN ".len();
+
+        while channels.len() < limit
+            && let Some((channel, key)) = self.joins.front()
+        {
+            // Each name and key comes with a separator
+            let added = channel.len() + 1 + key.as_ref().map_or(0, |key| key.0.len() + 1);
+            if !channels.is_empty() && length + added > MAX_LINE {
+                break;
+            }
+            length += added;
+
+            let Some((channel, key)) = self.joins.pop_front() else {
+                break;
+            };
+            channels.push(channel);
+            keys.extend(key.map(|key| key.0));
+        }
+
+        debug!(channels = %channels.join(","), "joining");
+        if keys.is_empty() {
+            format!("JOIN {}", channels.join(","))
+        } else {
+            format!("JOIN {} {}", channels.join(","), keys.join(","))
         }
     }
 }
@@ -30,14 +134,20 @@ impl Iterator for Negotiator {
             return None;
         }
 
-        if let Some(n) = self.messages.next() {
+        if let Some(n) = self.messages.pop_front() {
+            debug!(step = %crate::trace::redact(&n), "negotiating");
             return Some(n);
         }
 
-        if let Some((_, n)) = self.channels.next() {
-            return Some(format!("JOIN {}", n));
+        if self.wanted.is_some() {
+            return None;
         }
 
+        if !self.joins.is_empty() {
+            return Some(self.next_join());
+        }
+
+        debug!("negotiation finished");
         self.done = true;
         None
     }
@@ -58,12 +168,88 @@ mod tests {
         let mut negotiator = Negotiator::new(&config);
 
         assert_eq!(negotiator.next(), Some("CAP LS 302".to_string()));
-        assert_eq!(negotiator.next(), Some("USER rusty 0 * None".to_string()));
+        assert_eq!(negotiator.next(), Some("USER rusty 0 * :rusty".to_string()));
         assert_eq!(negotiator.next(), Some("NICK rusty".to_string()));
+        assert!(negotiator.awaits_caps());
+        assert_eq!(negotiator.next(), None);
+
+        negotiator.listed(&HashMap::new());
         assert_eq!(negotiator.next(), Some("CAP END".to_string()));
-        //assert that it joins the correct channels, in any order
-        assert!(matches!(negotiator.next(), Some(n) if n == "JOIN #channel" || n == "JOIN #other"));
-        assert!(matches!(negotiator.next(), Some(n) if n == "JOIN #channel" || n == "JOIN #other"));
+        assert_eq!(negotiator.next(), Some("JOIN #channel,#other".to_string()));
         assert_eq!(negotiator.next(), None);
     }
+
+    #[test]
+    fn test_negotiator_with_credentials() {
+        let config = Config::new("irc.example.com")
+            .nick("rusty")
+            .realname("Rusty the bot")
+            .password("correct horse")
+            .sasl("rusty", "hunter3")
+            .capability("away-notify")
+            .channel_with_key("#secret", "key");
+
+        let mut negotiator = Negotiator::new(&config);
+
+        assert_eq!(
+            negotiator.by_ref().collect::<Vec<_>>(),
+            vec![
+                "PASS :correct horse",
+                "CAP LS 302",
+                "USER rusty 0 * :Rusty the bot",
+                "NICK rusty",
+            ]
+        );
+
+        // Only what the server offers is requested
+        negotiator.listed(&HashMap::from([
+            ("sasl".to_string(), "PLAIN".to_string()),
+            ("multi-prefix".to_string(), String::new()),
+        ]));
+        assert_eq!(
+            negotiator.collect::<Vec<_>>(),
+            vec!["CAP REQ :sasl", "JOIN #secret key"]
+        );
+    }
+
+    #[test]
+    fn test_negotiator_without_cap() {
+        let config = Config::new("irc.example.com")
+            .capability("away-notify")
+            .channel("#channel");
+
+        let mut negotiator = Negotiator::new(&config);
+        assert_eq!(negotiator.by_ref().count(), 3);
+        assert!(!negotiator.joins_next());
+
+        negotiator.skip_caps();
+        assert!(!negotiator.awaits_caps());
+        assert_eq!(negotiator.next(), Some("JOIN #channel".to_string()));
+    }
+
+    #[test]
+    fn test_joins_are_batched() {
+        let mut config = Config::new("irc.example.com")
+            .channel("#open")
+            .channel_with_key("#b", "key2")
+            .channel_with_key("#a", "key1");
+        for n in 0..60 {
+            config = config.channel(&format!("#channel-number-{n:02}"));
+        }
+
+        let mut negotiator = Negotiator::new(&config);
+        negotiator.listed(&HashMap::new());
+        while !negotiator.joins_next() {
+            negotiator.next();
+        }
+        negotiator.limit_joins(Some(2));
+        assert_eq!(negotiator.next(), Some("JOIN #a,#b key1,key2".to_string()));
+
+        negotiator.limit_joins(None);
+        let joins: Vec<String> = negotiator.collect();
+        assert_eq!(joins.len(), 3);
+        assert!(joins.iter().all(|join| join.len() <= MAX_LINE));
+        assert!(joins[0].starts_with("JOIN #channel-number-00,#channel-number-01,"));
+        assert!(joins[2].ends_with(",#open"));
+    }
 }
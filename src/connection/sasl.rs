@@ -0,0 +1,64 @@
+//! The client side of SASL PLAIN, the only mechanism we offer.
+
+const CHUNK: usize = 400;
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The `AUTHENTICATE` lines answering the server's `AUTHENTICATE +`.
+pub(crate) fn plain(account: &str, password: &str) -> Vec<String> {
+    let payload = base64(format!("{account}\0{account}\0{password}").as_bytes());
+
+    // Split in 400 byte chunks, with a `+` to end a payload filling the last one
+    let mut lines: Vec<String> = payload
+        .as_bytes()
+        .chunks(CHUNK)
+        .map(|chunk| format!("AUTHENTICATE {}", String::from_utf8_lossy(chunk)))
+        .collect();
+    if payload.len().is_multiple_of(CHUNK) {
+        lines.push("AUTHENTICATE +".to_string());
+    }
+    lines
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(
+            plain("jilles", "sesame"),
+            vec!["AUTHENTICATE amlsbGVzAGppbGxlcwBzZXNhbWU="]
+        );
+
+        // 300 bytes encode to exactly 400
+        let lines = plain("a", &"x".repeat(296));
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], "AUTHENTICATE +");
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    ffi::{CStr, c_char, c_void},
+    path::{Path, PathBuf},
+};
+
+use libloading::{Library, Symbol};
+
+use super::error::{Error, Result};
+use crate::{Flow, IrcMessage, IrcPlugin, PluginFilter, Server};
+
+/// Bumped whenever `PluginDeclaration` changes shape.
+pub const ABI_VERSION: u32 = 3;
+
+/// The `irc_lib` version plugins are built against, NUL terminated.
+pub const LIB_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+
+/// The compiler plugins are built with, as `rustc --version` puts it, NUL
+/// terminated. Trait objects only have the same layout across one compiler.
+pub const RUSTC_VERSION: &str = concat!(env!("IRC_LIB_RUSTC_VERSION"), "\0");
+
+/// The `irc_lib` features plugins are built with, comma separated and NUL
+/// terminated. Some of them add enum variants, changing the layout of types
+/// the two share.
+pub const FEATURES: &str = concat!(env!("IRC_LIB_FEATURES"), "\0");
+
+/// Name of the static every plugin library exports.
+pub const DECLARATION_SYMBOL: &[u8] = b"IRC_LIB_PLUGIN\0";
+
+/// What a plugin library exports so the host can check it is compatible
+/// before creating the plugin.
+#[repr(C)]
+pub struct PluginDeclaration {
+    pub abi_version: u32,
+    pub lib_version: *const c_char,
+    pub rustc_version: *const c_char,
+    pub features: *const c_char,
+    /// Returns a `Box<Box<dyn IrcPlugin>>` turned into a raw pointer, or null
+    /// if creating the plugin panicked.
+    pub create: unsafe extern "C" fn() -> *mut c_void,
+}
+
+// Only ever points at static data
+unsafe impl Sync for PluginDeclaration {}
+
+/// Exports `IRC_LIB_PLUGIN` from a plugin library, creating the plugin with `$constructor`.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($constructor:expr) => {
+        #[unsafe(no_mangle)]
+        pub static IRC_LIB_PLUGIN: $crate::dylib::PluginDeclaration =
+            $crate::dylib::PluginDeclaration {
+                abi_version: $crate::dylib::ABI_VERSION,
+                lib_version: $crate::dylib::LIB_VERSION.as_ptr() as *const ::std::ffi::c_char,
+                rustc_version: $crate::dylib::RUSTC_VERSION.as_ptr() as *const ::std::ffi::c_char,
+                features: $crate::dylib::FEATURES.as_ptr() as *const ::std::ffi::c_char,
+                create: {
+                    // A panic must not unwind across `extern "C"`, that aborts the host
+                    unsafe extern "C" fn create() -> *mut ::std::ffi::c_void {
+                        ::std::panic::catch_unwind(|| {
+                            let plugin: ::std::boxed::Box<dyn $crate::IrcPlugin> =
+                                ::std::boxed::Box::new($constructor);
+                            ::std::boxed::Box::into_raw(::std::boxed::Box::new(plugin))
+                                as *mut ::std::ffi::c_void
+                        })
+                        .unwrap_or(::std::ptr::null_mut())
+                    }
+                    create
+                },
+            };
+    };
+}
+
+/// A plugin living in a shared library, which is unloaded when this is dropped.
+///
+/// Removing it from a server also drops the timers it scheduled and the
+/// plugins it registered, before their code goes away with the library.
+#[derive(Debug)]
+pub struct DylibPlugin {
+    // Declared before `library` so the plugin is dropped while its code is still loaded
+    plugin: Box<dyn IrcPlugin>,
+    path: PathBuf,
+    library: Library,
+}
+
+impl DylibPlugin {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        // SAFETY: loading runs the library's initialisers, we trust plugin libraries
+        // as much as the host binary itself
+        let library = unsafe { Library::new(path)? };
+
+        let plugin = unsafe {
+            let declaration: Symbol<*const PluginDeclaration> = library.get(DECLARATION_SYMBOL)?;
+            let declaration = &**declaration;
+            check_declaration(declaration)?;
+
+            let raw = (declaration.create)() as *mut Box<dyn IrcPlugin>;
+            if raw.is_null() {
+                return Err(Error::CreateFailed);
+            }
+            *Box::from_raw(raw)
+        };
+
+        Ok(DylibPlugin {
+            plugin,
+            path: path.to_owned(),
+            library,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn library(&self) -> &Library {
+        &self.library
+    }
+}
+
+fn check_declaration(declaration: &PluginDeclaration) -> Result<()> {
+    if declaration.abi_version != ABI_VERSION {
+        return Err(Error::AbiMismatch {
+            expected: ABI_VERSION,
+            found: declaration.abi_version,
+        });
+    }
+
+    // SAFETY: the ABI version matched, so these are NUL terminated strings
+    let found = unsafe { CStr::from_ptr(declaration.lib_version) }.to_string_lossy();
+    let expected = LIB_VERSION.trim_end_matches('\0');
+    if found != expected {
+        return Err(Error::VersionMismatch {
+            expected: expected.to_string(),
+            found: found.into_owned(),
+        });
+    }
+
+    let found = unsafe { CStr::from_ptr(declaration.rustc_version) }.to_string_lossy();
+    let expected = RUSTC_VERSION.trim_end_matches('\0');
+    if found != expected {
+        return Err(Error::CompilerMismatch {
+            expected: expected.to_string(),
+            found: found.into_owned(),
+        });
+    }
+
+    let found = unsafe { CStr::from_ptr(declaration.features) }.to_string_lossy();
+    let expected = FEATURES.trim_end_matches('\0');
+    if found != expected {
+        return Err(Error::FeatureMismatch {
+            expected: expected.to_string(),
+            found: found.into_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+impl IrcPlugin for DylibPlugin {
+    fn message(&mut self, server: &Server, message: &IrcMessage) -> Flow {
+        self.plugin.message(server, message)
+    }
+
+    fn priority(&self) -> i32 {
+        self.plugin.priority()
+    }
+
+    fn filter(&self) -> Option<PluginFilter> {
+        self.plugin.filter()
+    }
+
+    fn name(&self) -> &str {
+        self.plugin.name()
+    }
+
+    fn on_load(&mut self, server: &Server) {
+        self.plugin.on_load(server)
+    }
+
+    fn on_unload(&mut self, server: &Server) {
+        self.plugin.on_unload(server)
+    }
+
+    fn on_register(&mut self, server: &Server) {
+        self.plugin.on_register(server)
+    }
+
+    fn on_join(&mut self, server: &Server, channel: &str) {
+        self.plugin.on_join(server, channel)
+    }
+
+    fn on_part(&mut self, server: &Server, channel: &str) {
+        self.plugin.on_part(server, channel)
+    }
+
+    fn on_presence(&mut self, server: &Server, nick: &str, online: bool) {
+        self.plugin.on_presence(server, nick, online)
+    }
+
+    fn on_outgoing(&mut self, server: &Server, message: &IrcMessage) {
+        self.plugin.on_outgoing(server, message)
+    }
+
+    fn on_disconnect(&mut self, server: &Server) {
+        self.plugin.on_disconnect(server)
+    }
+
+    fn on_shutdown(&mut self, server: &Server) {
+        self.plugin.on_shutdown(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn never_called() -> *mut c_void {
+        std::ptr::null_mut()
+    }
+
+    #[test]
+    fn test_check_declaration() {
+        let matching = PluginDeclaration {
+            abi_version: ABI_VERSION,
+            lib_version: LIB_VERSION.as_ptr() as *const c_char,
+            rustc_version: RUSTC_VERSION.as_ptr() as *const c_char,
+            features: FEATURES.as_ptr() as *const c_char,
+            create: never_called,
+        };
+        assert!(check_declaration(&matching).is_ok());
+
+        let old_abi = PluginDeclaration {
+            abi_version: ABI_VERSION + 1,
+            ..matching
+        };
+        assert!(matches!(
+            check_declaration(&old_abi),
+            Err(Error::AbiMismatch { found, .. }) if found == ABI_VERSION + 1
+        ));
+
+        let old_lib = PluginDeclaration {
+            lib_version: c"0.0.1".as_ptr(),
+            ..matching
+        };
+        assert!(matches!(
+            check_declaration(&old_lib),
+            Err(Error::VersionMismatch { found, .. }) if found == "0.0.1"
+        ));
+
+        let other_compiler = PluginDeclaration {
+            rustc_version: c"rustc 1.0.0".as_ptr(),
+            ..matching
+        };
+        assert!(matches!(
+            check_declaration(&other_compiler),
+            Err(Error::CompilerMismatch { found, .. }) if found == "rustc 1.0.0"
+        ));
+
+        let other_features = PluginDeclaration {
+            features: c"dylib-plugins,tls-but-not-quite".as_ptr(),
+            ..matching
+        };
+        assert!(matches!(
+            check_declaration(&other_features),
+            Err(Error::FeatureMismatch { found, .. }) if found == "dylib-plugins,tls-but-not-quite"
+        ));
+    }
+
+    #[derive(Debug)]
+    struct Broken;
+
+    impl IrcPlugin for Broken {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            Flow::Continue
+        }
+    }
+
+    fn broken() -> Broken {
+        panic!("broken plugin")
+    }
+
+    crate::declare_plugin!(broken());
+
+    #[test]
+    fn test_constructor_panics_are_contained() {
+        assert!(check_declaration(&IRC_LIB_PLUGIN).is_ok());
+        assert!(unsafe { (IRC_LIB_PLUGIN.create)() }.is_null());
+    }
+
+    #[test]
+    fn test_load_missing_library() {
+        assert!(matches!(
+            DylibPlugin::load("/nonexistent/libplugin.so"),
+            Err(Error::Load(_))
+        ));
+    }
+}
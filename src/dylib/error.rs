@@ -0,0 +1,42 @@
+use derive_more::From;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, From)]
+pub enum Error {
+    // -- Externals
+    #[from]
+    Load(libloading::Error),
+    AbiMismatch {
+        expected: u32,
+        found: u32,
+    },
+    VersionMismatch {
+        expected: String,
+        found: String,
+    },
+    /// Built by another `rustc` than the host.
+    CompilerMismatch {
+        expected: String,
+        found: String,
+    },
+    /// Built with other `irc_lib` features than the host.
+    FeatureMismatch {
+        expected: String,
+        found: String,
+    },
+    /// The plugin's constructor panicked.
+    CreateFailed,
+}
+
+// region:    --- Error Boilerplate
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        write!(fmt, "{self:?}")
+    }
+}
+
+impl std::error::Error for Error {}
+
+// endregion: --- Error Boilerplate
@@ -0,0 +1,35 @@
+//! Loading `IrcPlugin`s from shared libraries, behind the `dylib-plugins` feature.
+//!
+//! A plugin library is a `cdylib` depending on the same `irc_lib` version
+//! with the same features, built with the same compiler, that declares its
+//! plugin with [`declare_plugin!`](crate::declare_plugin):
+//!
+//! ```rust,ignore
+//! #[derive(Debug, Default)]
+//! struct Quotes;
+//! impl irc_lib::IrcPlugin for Quotes { /* ... */ }
+//!
+//! irc_lib::declare_plugin!(Quotes::default());
+//! ```
+//!
+//! The host then loads it like any other plugin, and can reload it once the
+//! library on disk has been replaced:
+//!
+//! ```rust,no_run
+//! # fn run(client: &irc_lib::Client) -> irc_lib::Result<()> {
+//! use irc_lib::dylib::DylibPlugin;
+//!
+//! client.register_plugin(DylibPlugin::load("plugins/libquotes.so")?)?;
+//!
+//! client.replace_plugin("Quotes", || {
+//!     Ok(Box::new(DylibPlugin::load("plugins/libquotes.so")?))
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod dylib_plugin;
+mod error;
+
+pub use dylib_plugin::*;
+pub use error::Error;
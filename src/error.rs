@@ -0,0 +1,37 @@
+use derive_more::From;
+
+pub use crate::config::error::Error as ConfigError;
+pub use crate::connection::error::Error as ConnectionError;
+pub use crate::message::Error as MessageError;
+pub use crate::server::error::Error as ServerError;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Any error the library can surface to the host application.
+#[derive(Debug, From)]
+pub enum Error {
+    #[from]
+    Config(ConfigError),
+    #[from]
+    Connection(ConnectionError),
+    #[from]
+    Server(ServerError),
+    #[from]
+    Message(MessageError),
+    #[cfg(feature = "dylib-plugins")]
+    #[from]
+    Dylib(crate::dylib::Error),
+    WorkerPanicked,
+}
+
+// region:    --- Error Boilerplate
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        write!(fmt, "{self:?}")
+    }
+}
+
+impl std::error::Error for Error {}
+
+// endregion: --- Error Boilerplate
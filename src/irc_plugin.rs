@@ -1,7 +1,183 @@
-use crate::{Server, message::IrcMessage};
+use crate::{
+    Server,
+    message::{Command, IrcMessage, Prefix},
+};
 
 use std::fmt::Debug;
 
+/// A plugin owned by the connection's worker thread.
+///
+/// Plugins are only ever called from that thread, so they get `&mut self` and
+/// can keep state without any locking.
 pub trait IrcPlugin: Debug + Send {
-    fn message(&self, server: &Server, message: &IrcMessage);
+    /// Handles an incoming message. Returning [`Flow::Consume`] hides it from
+    /// lower priority plugins and the `Client`.
+    fn message(&mut self, server: &Server, message: &IrcMessage) -> Flow;
+
+    /// Plugins with a higher priority see messages first. Defaults to 0, ties
+    /// keep registration order.
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Restricts which messages reach `message`. Read once, when the plugin is registered.
+    fn filter(&self) -> Option<PluginFilter> {
+        None
+    }
+
+    /// Identifies the plugin in failure reports. Defaults to the type name.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Called when the plugin starts receiving events: when the worker starts,
+    /// or when it is registered or enabled on a running client.
+    fn on_load(&mut self, _server: &Server) {}
+
+    /// Called when the plugin is removed or disabled on a running client,
+    /// including when it is disabled for panicking too often.
+    fn on_unload(&mut self, _server: &Server) {}
+
+    /// Called once the server has welcomed us (`001`), or right after
+    /// `on_load` when registered on an already welcomed connection.
+    fn on_register(&mut self, _server: &Server) {}
+
+    /// Called when we join a channel.
+    fn on_join(&mut self, _server: &Server, _channel: &str) {}
+
+    /// Called when we leave a channel, either by parting or being kicked.
+    fn on_part(&mut self, _server: &Server, _channel: &str) {}
+
+    /// Called when a watched nick comes online or goes offline, see `Server::watch`.
+    fn on_presence(&mut self, _server: &Server, _nick: &str, _online: bool) {}
+
+    /// Called before a message queued through the `Client` or `Server::send_message` is sent.
+    fn on_outgoing(&mut self, _server: &Server, _message: &IrcMessage) {}
+
+    /// Called when an established connection is lost or closed.
+    fn on_disconnect(&mut self, _server: &Server) {}
+
+    /// Called last, right before the worker thread exits.
+    fn on_shutdown(&mut self, _server: &Server) {}
+}
+
+/// What should happen to a message after a plugin has seen it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Flow {
+    #[default]
+    Continue,
+    Consume,
+}
+
+/// Declares which messages a plugin cares about.
+///
+/// Every list that isn't empty must match, and a list matches when any of its
+/// entries does. Channels and senders are compared case-insensitively.
+///
+/// ```rust
+/// use irc_lib::PluginFilter;
+/// use irc_lib::message::Command;
+///
+/// let filter = PluginFilter::new()
+///     .command(Command::PrivMsg)
+///     .channel("#ops");
+///
+/// assert!(filter.matches(&":nick!u@h PRIVMSG #OPS :hello".parse()?));
+/// assert!(!filter.matches(&":nick!u@h PRIVMSG #chat :hello".parse()?));
+/// # Ok::<(), irc_lib::message::Error>(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PluginFilter {
+    commands: Vec<Command>,
+    channels: Vec<String>,
+    senders: Vec<String>,
+}
+
+impl PluginFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Only messages to or about this channel, whatever its prefix.
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.channels.push(channel.to_owned());
+        self
+    }
+
+    /// Only messages from this nick.
+    pub fn sender(mut self, nick: &str) -> Self {
+        self.senders.push(nick.to_owned());
+        self
+    }
+
+    pub fn matches(&self, message: &IrcMessage) -> bool {
+        let command_matches = self.commands.is_empty() || self.commands.contains(&message.command);
+
+        // only `#` parses as a channel, so `&local` and friends are found
+        // as the first argument
+        let first = message.args().into_iter().next();
+        let channel_matches = self.channels.is_empty()
+            || message
+                .get_channel()
+                .into_iter()
+                .chain(&first)
+                .any(|channel| {
+                    self.channels
+                        .iter()
+                        .any(|wanted| wanted.eq_ignore_ascii_case(channel))
+                });
+
+        let sender_matches = self.senders.is_empty()
+            || match &message.prefix {
+                Some(Prefix::User { nick, .. }) => self
+                    .senders
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(nick)),
+                _ => false,
+            };
+
+        command_matches && channel_matches && sender_matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_matches() {
+        let privmsg: IrcMessage = ":nick!u@h PRIVMSG #chan :hi".parse().unwrap();
+        let join: IrcMessage = ":other!u@h JOIN #chan".parse().unwrap();
+        let ping: IrcMessage = "PING :123".parse().unwrap();
+
+        assert!(PluginFilter::new().matches(&ping));
+
+        let commands = PluginFilter::new()
+            .command(Command::PrivMsg)
+            .command(Command::Join);
+        assert!(commands.matches(&privmsg));
+        assert!(commands.matches(&join));
+        assert!(!commands.matches(&ping));
+
+        let channel = PluginFilter::new().channel("#CHAN");
+        assert!(channel.matches(&privmsg));
+        assert!(!channel.matches(&ping));
+
+        let local = PluginFilter::new().channel("&local");
+        let to_local: IrcMessage = ":nick!u@h PRIVMSG &Local :hi".parse().unwrap();
+        let mode: IrcMessage = ":nick!u@h MODE &local +o other".parse().unwrap();
+        assert!(local.matches(&to_local));
+        assert!(local.matches(&mode));
+        assert!(!local.matches(&privmsg));
+
+        let sender = PluginFilter::new().command(Command::PrivMsg).sender("Nick");
+        assert!(sender.matches(&privmsg));
+        assert!(!sender.matches(&join));
+        assert!(!PluginFilter::new().sender("nick").matches(&ping));
+    }
 }
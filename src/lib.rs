@@ -1,12 +1,22 @@
+#[macro_use]
+mod trace;
+
 mod config;
 mod connection;
+#[cfg(feature = "dylib-plugins")]
+pub mod dylib;
+mod error;
 mod irc_plugin;
 pub mod message;
+mod middleware;
+pub mod plugins;
 mod server;
 
 pub use config::Config as IrcClient;
-pub use irc_plugin::IrcPlugin;
+pub use error::{ConfigError, ConnectionError, Error, MessageError, Result, ServerError};
+pub use irc_plugin::{Flow, IrcPlugin, PluginFilter};
 pub use message::IrcMessage;
+pub use middleware::OutgoingMiddleware;
 pub use server::*;
 
 pub(crate) use config::Config;
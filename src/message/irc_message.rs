@@ -16,6 +16,9 @@ use std::{fmt, str::FromStr};
 /// ```
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct IrcMessage {
+    /// IRCv3 message tags such as `account` or `time`, unescaped, with an
+    /// empty value for tags that have none.
+    pub tags: Vec<(String, String)>,
     pub prefix: Option<Prefix>,
     pub command: Command,
     pub params: Vec<Param>,
@@ -94,6 +97,7 @@ impl FromStr for IrcMessage {
 impl IrcMessage {
     pub fn new(prefix: Option<Prefix>, command: Command, params: Vec<Param>) -> Self {
         IrcMessage {
+            tags: Vec::new(),
             prefix,
             command,
             params,
@@ -101,6 +105,13 @@ impl IrcMessage {
     }
 
     fn from_str(input: &str) -> Result<Self> {
+        let (tags, input) = match input.strip_prefix('@') {
+            Some(rest) => {
+                let (tags, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                (parse_tags(tags), rest.trim_start())
+            }
+            None => (Vec::new(), input),
+        };
         let mut parts = input.split_whitespace();
         let prefix = if input.starts_with(':') {
             parts.next().map(|s| s[1..].to_string()).and_then(|s| {
@@ -178,6 +189,7 @@ impl IrcMessage {
         let params = IrcMessage::parse_params(&command, &params_str);
 
         Ok(IrcMessage {
+            tags,
             prefix,
             command,
             params,
@@ -193,6 +205,21 @@ impl IrcMessage {
                 if let Some(channel) = parts.next() {
                     params.push(Param::Channel(channel.to_string()));
                 }
+                // extended-join's account and realname, or why someone left
+                let rest = parts.collect::<Vec<&str>>().join(" ");
+                let (middle, trailing) = match rest.strip_prefix(':') {
+                    Some(trailing) => ("", Some(trailing)),
+                    None => match rest.split_once(" :") {
+                        Some((middle, trailing)) => (middle, Some(trailing)),
+                        None => (rest.as_str(), None),
+                    },
+                };
+                params.extend(
+                    middle
+                        .split_whitespace()
+                        .map(|param| Param::Unknown(param.to_string())),
+                );
+                params.extend(trailing.map(|trailing| Param::Message(trailing.to_string())));
             }
             Command::PrivMsg | Command::Notice => {
                 if let Some(channel) = parts.next() {
@@ -251,6 +278,13 @@ impl IrcMessage {
         IrcMessageBuilder::new()
     }
 
+    /// The value of the tag `key`, if the message has it.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find_map(|(tag, value)| (tag == key).then_some(value.as_str()))
+    }
+
     pub fn get_message(&self) -> Option<&String> {
         self.params.iter().find_map(|param| {
             if let Param::Message(msg) = param {
@@ -261,6 +295,35 @@ impl IrcMessage {
         })
     }
 
+    /// The parameters as sent, with a trailing parameter that was split on
+    /// spaces joined back together.
+    ///
+    /// ```rust
+    /// use irc_lib::IrcMessage;
+    ///
+    /// let msg: IrcMessage = ":server 311 me nick user host * :Real Name".parse()?;
+    /// assert_eq!(msg.args(), vec!["me", "nick", "user", "host", "*", "Real Name"]);
+    /// # Ok::<(), irc_lib::message::Error>(())
+    /// ```
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut trailing: Option<String> = None;
+
+        for param in &self.params {
+            match (&mut trailing, param) {
+                (Some(trailing), _) => {
+                    trailing.push(' ');
+                    trailing.push_str(&param.to_string());
+                }
+                (None, Param::Message(message)) => trailing = Some(message.clone()),
+                (None, _) => args.push(param.to_string()),
+            }
+        }
+
+        args.extend(trailing);
+        args
+    }
+
     pub fn get_channel(&self) -> Option<&String> {
         self.params.iter().find_map(|param| {
             if let Param::Channel(ch) = param {
@@ -363,6 +426,7 @@ impl fmt::Display for Param {
 /// ```
 #[derive(Debug, Default)]
 pub struct IrcMessageBuilder {
+    tags: Vec<(String, String)>,
     prefix: Option<Prefix>,
     command: Option<Command>,
     params: Vec<Param>,
@@ -371,6 +435,7 @@ pub struct IrcMessageBuilder {
 impl IrcMessageBuilder {
     pub fn new() -> Self {
         IrcMessageBuilder {
+            tags: Vec::new(),
             prefix: None,
             command: None,
             params: Vec::new(),
@@ -402,6 +467,11 @@ impl IrcMessageBuilder {
         self
     }
 
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.push((key.to_string(), value.to_string()));
+        self
+    }
+
     pub fn command(mut self, command: Command) -> Self {
         self.command = Some(command);
         self
@@ -415,6 +485,7 @@ impl IrcMessageBuilder {
     pub fn build(self) -> Result<IrcMessage> {
         match self.command {
             Some(command) => Ok(IrcMessage {
+                tags: self.tags,
                 prefix: self.prefix,
                 command,
                 params: self.params,
@@ -427,6 +498,19 @@ impl IrcMessageBuilder {
 impl fmt::Display for IrcMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut result = String::new();
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self
+                .tags
+                .iter()
+                .map(|(key, value)| match value.as_str() {
+                    "" => key.clone(),
+                    value => format!("{key}={}", escape_tag(value)),
+                })
+                .collect();
+            result.push('@');
+            result.push_str(&tags.join(";"));
+            result.push(' ');
+        }
         if let Some(ref prefix) = self.prefix {
             result.push(':');
             result.push_str(&prefix.to_string());
@@ -448,10 +532,85 @@ impl fmt::Display for IrcMessage {
     }
 }
 
+// `key=value;key2` with the values unescaped
+fn parse_tags(tags: &str) -> Vec<(String, String)> {
+    tags.split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+            (key.to_string(), unescape_tag(value))
+        })
+        .collect()
+}
+
+fn unescape_tag(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            // Unknown escapes drop the backslash, a trailing one is dropped too
+            Some(other) => unescaped.push(other),
+            None => (),
+        }
+    }
+    unescaped
+}
+
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_tags() {
+        let input =
+            "@account=rusty;msgid=a\\sb\\:c;+draft/flag :nick!user@host PRIVMSG #channel :hi";
+        let msg: IrcMessage = input.parse().unwrap();
+        assert_eq!(msg.tag("account"), Some("rusty"));
+        assert_eq!(msg.tag("msgid"), Some("a b;c"));
+        assert_eq!(msg.tag("+draft/flag"), Some(""));
+        assert_eq!(msg.tag("time"), None);
+        assert_eq!(msg.command, Command::PrivMsg);
+        assert_eq!(msg.to_string(), input);
+    }
+
+    #[test]
+    fn test_join_and_part_params() {
+        let msg: IrcMessage = ":nick!user@host JOIN #channel rusty :Real Name"
+            .parse()
+            .unwrap();
+        assert_eq!(msg.args(), vec!["#channel", "rusty", "Real Name"]);
+        assert_eq!(
+            msg.to_string(),
+            ":nick!user@host JOIN #channel rusty :Real Name"
+        );
+
+        let msg: IrcMessage = ":nick!user@host PART #channel :Gone home".parse().unwrap();
+        assert_eq!(msg.get_message().map(String::as_str), Some("Gone home"));
+    }
+
     #[test]
     fn test_from_str() {
         let input = ":prefix JOIN #channel";
@@ -3,3 +3,6 @@ mod irc_message;
 
 pub use error::Error;
 pub use irc_message::*;
+
+/// Longest line servers accept, leaving room for the trailing CRLF.
+pub(crate) const MAX_LINE: usize = 510;
@@ -0,0 +1,117 @@
+use crate::message::IrcMessage;
+
+use std::fmt::Debug;
+
+/// A step in the chain every outgoing message goes through before hitting the wire.
+///
+/// Returning no messages drops the input, returning several sends them all
+/// (each going through the rest of the chain).
+///
+/// ```rust
+/// use irc_lib::{IrcMessage, OutgoingMiddleware};
+///
+/// // Keeps the bot out of #quiet
+/// #[derive(Debug)]
+/// struct Muzzle;
+/// impl OutgoingMiddleware for Muzzle {
+///     fn process(&mut self, message: IrcMessage) -> Vec<IrcMessage> {
+///         match message.get_channel() {
+///             Some(channel) if channel == "#quiet" => vec![],
+///             _ => vec![message],
+///         }
+///     }
+/// }
+/// ```
+pub trait OutgoingMiddleware: Debug + Send {
+    fn process(&mut self, message: IrcMessage) -> Vec<IrcMessage>;
+}
+
+/// Feeds `message` through every middleware in order.
+pub(crate) fn run_chain(
+    chain: &mut [Box<dyn OutgoingMiddleware>],
+    message: IrcMessage,
+) -> Vec<IrcMessage> {
+    chain
+        .iter_mut()
+        .fold(vec![message], |messages, middleware| {
+            messages
+                .into_iter()
+                .flat_map(|message| middleware.process(message))
+                .collect()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Command, Param};
+
+    #[derive(Debug)]
+    struct Redirect;
+    impl OutgoingMiddleware for Redirect {
+        fn process(&mut self, mut message: IrcMessage) -> Vec<IrcMessage> {
+            for param in message.params.iter_mut() {
+                if *param == Param::Channel("#old".to_string()) {
+                    *param = Param::Channel("#new".to_string());
+                }
+            }
+            vec![message]
+        }
+    }
+
+    #[derive(Debug)]
+    struct SplitWords;
+    impl OutgoingMiddleware for SplitWords {
+        fn process(&mut self, message: IrcMessage) -> Vec<IrcMessage> {
+            let (Some(channel), Some(text)) = (message.get_channel(), message.get_message()) else {
+                return vec![message];
+            };
+            text.split(' ')
+                .map(|word| {
+                    IrcMessage::new(
+                        None,
+                        message.command.clone(),
+                        vec![
+                            Param::Channel(channel.clone()),
+                            Param::Message(word.to_string()),
+                        ],
+                    )
+                })
+                .collect()
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct DropPings(usize);
+    impl OutgoingMiddleware for DropPings {
+        fn process(&mut self, message: IrcMessage) -> Vec<IrcMessage> {
+            if message.command == Command::Ping {
+                self.0 += 1;
+                vec![]
+            } else {
+                vec![message]
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_chain() {
+        let mut chain: Vec<Box<dyn OutgoingMiddleware>> = vec![
+            Box::new(DropPings::default()),
+            Box::new(Redirect),
+            Box::new(SplitWords),
+        ];
+
+        let sent: Vec<String> = run_chain(&mut chain, "PRIVMSG #old :hello there".parse().unwrap())
+            .iter()
+            .map(IrcMessage::to_string)
+            .collect();
+        assert_eq!(sent, vec!["PRIVMSG #new :hello", "PRIVMSG #new :there"]);
+
+        assert!(run_chain(&mut chain, "PING :1".parse().unwrap()).is_empty());
+        assert_eq!(
+            run_chain(&mut [], "PING :1".parse().unwrap()),
+            vec!["PING :1".parse().unwrap()]
+        );
+    }
+}
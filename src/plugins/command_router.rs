@@ -0,0 +1,370 @@
+use std::fmt;
+
+use crate::message::{Command, IrcMessage, Prefix};
+use crate::server::error::{Error, Result};
+use crate::{Flow, IrcPlugin, Server};
+
+type Handler = Box<dyn FnMut(&CommandContext) + Send>;
+
+/// Routes `!command arg "quoted arg"` style messages to registered handlers.
+///
+/// Commands are recognised when prefixed (`!ping`), when addressed to us
+/// (`botnick: ping`) or, in a private query, bare (`ping`). A `help` command
+/// listing everything registered is always available.
+///
+/// Handled commands are consumed, hiding them from lower priority plugins.
+///
+/// ```rust
+/// use irc_lib::plugins::CommandRouter;
+///
+/// let router = CommandRouter::new("!")?
+///     .command("ping", "Replies with pong", |ctx| {
+///         let _ = ctx.reply("pong");
+///     })
+///     .alias("ping", "p");
+/// # Ok::<(), irc_lib::ServerError>(())
+/// ```
+pub struct CommandRouter {
+    prefix: String,
+    commands: Vec<RegisteredCommand>,
+}
+
+struct RegisteredCommand {
+    name: String,
+    help: String,
+    aliases: Vec<String>,
+    handler: Handler,
+}
+
+/// Everything a command handler needs to know about the invocation.
+pub struct CommandContext<'a> {
+    pub server: &'a Server,
+    /// Nick of whoever issued the command.
+    pub sender: &'a str,
+    /// Where replies go: the channel, or the sender for private queries.
+    pub reply_to: &'a str,
+    /// The command name as typed, which might be an alias.
+    pub command: &'a str,
+    pub args: Vec<String>,
+}
+
+impl CommandContext<'_> {
+    pub fn reply(&self, text: &str) -> Result<()> {
+        self.server.privmsg(self.reply_to, text)
+    }
+}
+
+impl CommandRouter {
+    pub fn new(prefix: &str) -> Result<Self> {
+        if prefix.is_empty() {
+            return Err(Error::EmptyPrefix);
+        }
+        Ok(CommandRouter {
+            prefix: prefix.to_owned(),
+            commands: Vec::new(),
+        })
+    }
+
+    pub fn command(
+        mut self,
+        name: &str,
+        help: &str,
+        handler: impl FnMut(&CommandContext) + Send + 'static,
+    ) -> Self {
+        self.commands.push(RegisteredCommand {
+            name: name.to_lowercase(),
+            help: help.to_owned(),
+            aliases: Vec::new(),
+            handler: Box::new(handler),
+        });
+
+        self
+    }
+
+    /// Makes `alias` invoke the already registered `command`.
+    pub fn alias(mut self, command: &str, alias: &str) -> Self {
+        let command = command.to_lowercase();
+        if let Some(registered) = self.commands.iter_mut().find(|c| c.name == command) {
+            registered.aliases.push(alias.to_lowercase());
+        }
+
+        self
+    }
+
+    // Strips whichever form of addressing was used, if any
+    fn command_line<'a>(&self, own_nick: &str, private: bool, text: &'a str) -> Option<&'a str> {
+        if let Some(line) = text.strip_prefix(self.prefix.as_str()) {
+            return Some(line);
+        }
+
+        let addressed = text
+            .get(..own_nick.len())
+            .filter(|nick| nick.eq_ignore_ascii_case(own_nick))
+            .and_then(|_| text[own_nick.len()..].strip_prefix([':', ',']));
+        if let Some(line) = addressed {
+            return Some(line.trim_start());
+        }
+
+        private.then_some(text)
+    }
+
+    fn help(&self, args: &[String]) -> String {
+        match args.first().map(|arg| arg.to_lowercase()) {
+            Some(name) => match self.find(&name) {
+                Some(command) if command.aliases.is_empty() => {
+                    format!("{}{}: {}", self.prefix, command.name, command.help)
+                }
+                Some(command) => format!(
+                    "{}{}: {} (aliases: {})",
+                    self.prefix,
+                    command.name,
+                    command.help,
+                    command.aliases.join(", ")
+                ),
+                None => format!("Unknown command: {}", name),
+            },
+            None => {
+                let names: Vec<String> = self
+                    .commands
+                    .iter()
+                    .map(|command| format!("{}{}", self.prefix, command.name))
+                    .collect();
+                format!("Commands: {}", names.join(", "))
+            }
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&RegisteredCommand> {
+        self.commands
+            .iter()
+            .find(|c| c.name == name || c.aliases.iter().any(|a| a == name))
+    }
+
+    fn find_mut(&mut self, name: &str) -> Option<&mut RegisteredCommand> {
+        self.commands
+            .iter_mut()
+            .find(|c| c.name == name || c.aliases.iter().any(|a| a == name))
+    }
+}
+
+impl IrcPlugin for CommandRouter {
+    fn message(&mut self, server: &Server, message: &IrcMessage) -> Flow {
+        let (
+            IrcMessage {
+                command: Command::PrivMsg,
+                prefix: Some(Prefix::User { nick: sender, .. }),
+                ..
+            },
+            Some(target),
+            Some(text),
+        ) = (message, message.get_channel(), message.get_message())
+        else {
+            return Flow::Continue;
+        };
+
+        let private = target.eq_ignore_ascii_case(&server.nick);
+        let Some(line) = self.command_line(&server.nick, private, text) else {
+            return Flow::Continue;
+        };
+
+        let mut args = parse_args(line);
+        if args.is_empty() {
+            return Flow::Continue;
+        }
+        let command = args.remove(0);
+        let name = command.to_lowercase();
+
+        let ctx = CommandContext {
+            server,
+            sender,
+            reply_to: if private { sender } else { target },
+            command: &command,
+            args,
+        };
+
+        if let Some(registered) = self.find_mut(&name) {
+            (registered.handler)(&ctx);
+        } else if name == "help" {
+            let _ = ctx.reply(&self.help(&ctx.args));
+        } else {
+            return Flow::Continue;
+        }
+
+        Flow::Consume
+    }
+}
+
+impl fmt::Debug for CommandRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandRouter")
+            .field("prefix", &self.prefix)
+            .field(
+                "commands",
+                &self.commands.iter().map(|c| &c.name).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// Splits a command line on whitespace, keeping `"quoted"` and `'quoted'`
+/// arguments together. A backslash escapes the next character.
+pub fn parse_args(input: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+                in_arg = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => current.push(c),
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (c, None) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (c, None) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(current);
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use crate::connection::MockIrcConnection;
+    use std::sync::{Arc, Mutex};
+
+    fn server() -> (Server, std::sync::mpsc::Receiver<IrcMessage>) {
+        let config = Config::new("localhost").nick("bot");
+        let mut server = Server::new(config, Box::new(MockIrcConnection::new()));
+        let outgoing = server.capture_outgoing();
+        (server, outgoing)
+    }
+
+    fn replies(outgoing: &std::sync::mpsc::Receiver<IrcMessage>) -> Vec<String> {
+        outgoing.try_iter().map(|msg| msg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args("a b  c"), vec!["a", "b", "c"]);
+        assert_eq!(
+            parse_args(r#"say "hello world" 'it''s' x\ y"#),
+            vec!["say", "hello world", "its", "x y"]
+        );
+        assert_eq!(parse_args(r#"empty "" arg"#), vec!["empty", "", "arg"]);
+        assert_eq!(
+            parse_args(r#"open "quote never closes"#),
+            vec!["open", "quote never closes"]
+        );
+        assert!(parse_args("   ").is_empty());
+    }
+
+    #[test]
+    fn test_routing() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let mut router = CommandRouter::new("!")
+            .unwrap()
+            .command("echo", "Echoes its arguments", move |ctx| {
+                recorded.lock().unwrap().push(ctx.args.clone());
+                ctx.reply(&ctx.args.join(" ")).unwrap();
+            })
+            .alias("echo", "e");
+        let (server, outgoing) = server();
+
+        for (line, flow) in [
+            (
+                ":nick!u@h PRIVMSG #chan :!echo one \"two three\"",
+                Flow::Consume,
+            ),
+            (":nick!u@h PRIVMSG #chan :!E alias", Flow::Consume),
+            (
+                ":nick!u@h PRIVMSG #chan :bot: echo addressed",
+                Flow::Consume,
+            ),
+            (":nick!u@h PRIVMSG #chan :echo not for us", Flow::Continue),
+            (":nick!u@h PRIVMSG bot :echo private", Flow::Consume),
+            (":nick!u@h PRIVMSG #chan :!unknown", Flow::Continue),
+        ] {
+            assert_eq!(
+                router.message(&server, &line.parse().unwrap()),
+                flow,
+                "{line}"
+            );
+        }
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                vec!["one".to_string(), "two three".to_string()],
+                vec!["alias".to_string()],
+                vec!["addressed".to_string()],
+                vec!["private".to_string()],
+            ]
+        );
+        assert_eq!(
+            replies(&outgoing),
+            vec![
+                "PRIVMSG #chan :one two three",
+                "PRIVMSG #chan :alias",
+                "PRIVMSG #chan :addressed",
+                "PRIVMSG nick :private",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_prefix() {
+        assert!(matches!(CommandRouter::new(""), Err(Error::EmptyPrefix)));
+    }
+
+    #[test]
+    fn test_help() {
+        let mut router = CommandRouter::new("!")
+            .unwrap()
+            .command("ping", "Replies with pong", |_| {})
+            .command("roll", "Rolls a die", |_| {})
+            .alias("roll", "dice");
+        let (server, outgoing) = server();
+
+        for line in [
+            ":nick!u@h PRIVMSG #chan :!help",
+            ":nick!u@h PRIVMSG #chan :!help dice",
+            ":nick!u@h PRIVMSG #chan :!help nope",
+        ] {
+            router.message(&server, &line.parse().unwrap());
+        }
+
+        assert_eq!(
+            replies(&outgoing),
+            vec![
+                "PRIVMSG #chan :Commands: !ping, !roll",
+                "PRIVMSG #chan :!roll: Rolls a die (aliases: dice)",
+                "PRIVMSG #chan :Unknown command: nope",
+            ]
+        );
+    }
+}
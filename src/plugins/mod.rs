@@ -0,0 +1,7 @@
+mod command_router;
+#[cfg(feature = "scripting")]
+mod script_host;
+
+pub use command_router::{CommandContext, CommandRouter, parse_args};
+#[cfg(feature = "scripting")]
+pub use script_host::ScriptHost;
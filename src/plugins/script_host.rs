@@ -0,0 +1,638 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime},
+};
+
+use rhai::{AST, Array, Dynamic, Engine, EvalAltResult, FnPtr, INT, Map, Scope};
+
+use crate::message::{IrcMessage, Prefix};
+use crate::server::{commands, error::Result as ServerResult};
+use crate::{Flow, IrcPlugin, Server, TimerHandle};
+
+type ErrorHandler = Arc<dyn Fn(&Path, &str) + Send + Sync>;
+
+/// Runs [Rhai](https://rhai.rs) scripts as plugins, behind the `scripting` feature.
+///
+/// Scripts can define any of these functions:
+///
+/// - `on_load()`, called whenever the script is (re)loaded
+/// - `on_message(msg)`, called with a map holding `command`, `nick`, `target`,
+///   `text`, `reply_to` and `params`. Returning `true` consumes the message.
+///
+/// And call these:
+///
+/// - `privmsg(target, text)`, `notice(target, text)`, `join(channel)`, `part(channel)`,
+///   which raise an error instead of sending a line with a line break or a
+///   target that isn't one
+/// - `after(seconds, "function")` and `every(seconds, "function")` timers
+/// - `store_get(key)`, `store_set(key, value)` and `store_remove(key)`, a
+///   key-value store kept for as long as the host lives, across reloads
+///
+/// ```rhai
+/// fn on_message(msg) {
+///     if msg.command == "PRIVMSG" && msg.text == "!hello" {
+///         let count = store_get("greeted") ?? 0;
+///         store_set("greeted", count + 1);
+///         privmsg(msg.reply_to, `Hello ${msg.nick}, that's ${count + 1} so far`);
+///     }
+/// }
+/// ```
+///
+/// Scripts are sandboxed: they have no file or network access, can't `eval` or
+/// `import`, and are cut off when they run for too long or build huge values.
+/// Script files are checked for changes every `poll_interval` and reloaded,
+/// dropping their timers. Scripts that fail to load or run are reported to the
+/// `on_error` handler, and a script that fails to reload keeps its old version.
+///
+/// ```rust
+/// use irc_lib::plugins::ScriptHost;
+///
+/// let host = ScriptHost::new()
+///     .script("scripts/greeter.rhai")
+///     .on_error(|path, error| eprintln!("{}: {}", path.display(), error));
+/// ```
+pub struct ScriptHost {
+    scripts: Vec<Arc<Mutex<Script>>>,
+    poll_interval: Duration,
+    on_error: ErrorHandler,
+    watcher: Option<TimerHandle>,
+}
+
+struct Script {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    engine: Engine,
+    compiled: Option<(AST, Scope<'static>)>,
+    context: Arc<Mutex<Context>>,
+    timers: Vec<TimerHandle>,
+}
+
+// Shared with the functions registered on a script's engine
+#[derive(Default)]
+struct Context {
+    actions: Vec<Action>,
+    store: HashMap<String, Dynamic>,
+}
+
+enum Action {
+    Send(IrcMessage),
+    Schedule {
+        delay: Duration,
+        repeat: bool,
+        function: String,
+    },
+}
+
+impl ScriptHost {
+    pub fn new() -> Self {
+        ScriptHost {
+            scripts: Vec::new(),
+            poll_interval: Duration::from_secs(2),
+            on_error: Arc::new(log_error),
+            watcher: None,
+        }
+    }
+
+    pub fn script(mut self, path: impl AsRef<Path>) -> Self {
+        let script = Script::new(path.as_ref().to_owned());
+        self.scripts.push(Arc::new(Mutex::new(script)));
+
+        self
+    }
+
+    /// How often script files are checked for changes.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+
+        self
+    }
+
+    pub fn on_error(mut self, handler: impl Fn(&Path, &str) + Send + Sync + 'static) -> Self {
+        self.on_error = Arc::new(handler);
+
+        self
+    }
+}
+
+impl Default for ScriptHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IrcPlugin for ScriptHost {
+    fn message(&mut self, server: &Server, message: &IrcMessage) -> Flow {
+        let msg = message_map(server, message);
+
+        for script in &self.scripts {
+            let consumed = run(
+                script,
+                server,
+                &self.on_error,
+                "on_message",
+                vec![msg.clone().into()],
+            );
+            if consumed.as_bool() == Ok(true) {
+                return Flow::Consume;
+            }
+        }
+
+        Flow::Continue
+    }
+
+    fn on_load(&mut self, server: &Server) {
+        for script in &self.scripts {
+            load(script, server, &self.on_error);
+        }
+
+        let scripts: Vec<_> = self.scripts.iter().map(Arc::downgrade).collect();
+        let on_error = self.on_error.clone();
+        self.watcher = Some(server.schedule_every(self.poll_interval, move |server| {
+            for script in scripts.iter().filter_map(Weak::upgrade) {
+                let changed = script.lock().is_ok_and(|script| script.changed());
+                if changed {
+                    load(&script, server, &on_error);
+                }
+            }
+        }));
+    }
+
+    fn on_unload(&mut self, _server: &Server) {
+        if let Some(watcher) = self.watcher.take() {
+            watcher.cancel();
+        }
+        for script in &self.scripts {
+            if let Ok(mut script) = script.lock() {
+                script.cancel_timers();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ScriptHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paths: Vec<_> = self
+            .scripts
+            .iter()
+            .filter_map(|script| script.lock().ok().map(|script| script.path.clone()))
+            .collect();
+
+        f.debug_struct("ScriptHost")
+            .field("scripts", &paths)
+            .field("poll_interval", &self.poll_interval)
+            .finish()
+    }
+}
+
+impl Script {
+    fn new(path: PathBuf) -> Self {
+        let context = Arc::new(Mutex::new(Context::default()));
+
+        Script {
+            engine: sandboxed_engine(&context),
+            path,
+            modified: None,
+            compiled: None,
+            context,
+            timers: Vec::new(),
+        }
+    }
+
+    fn modified_on_disk(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    fn changed(&self) -> bool {
+        self.modified_on_disk() != self.modified
+    }
+
+    /// Compiles the file and runs its top level statements, keeping the
+    /// previous version if anything fails.
+    fn load(&mut self) -> Result<(), String> {
+        // Only retried once the file changes again
+        self.modified = self.modified_on_disk();
+
+        let source = fs::read_to_string(&self.path).map_err(|e| e.to_string())?;
+        let ast = self.engine.compile(source).map_err(|e| e.to_string())?;
+        let mut scope = Scope::new();
+        if let Err(error) = self.engine.run_ast_with_scope(&mut scope, &ast) {
+            // Nothing the failed version asked for is carried out
+            self.take_actions();
+            return Err(error.to_string());
+        }
+
+        self.cancel_timers();
+        self.compiled = Some((ast, scope));
+        Ok(())
+    }
+
+    /// Calls `name` if the script defines it with a matching number of parameters.
+    fn call(&mut self, name: &str, args: Vec<Dynamic>) -> Result<Dynamic, String> {
+        let Some((ast, scope)) = &mut self.compiled else {
+            return Ok(Dynamic::UNIT);
+        };
+
+        let defined = ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == args.len());
+        if !defined {
+            return Ok(Dynamic::UNIT);
+        }
+
+        let options = rhai::CallFnOptions::new().eval_ast(false);
+        self.engine
+            .call_fn_with_options(options, scope, ast, name, args)
+            .map_err(|e| e.to_string())
+    }
+
+    fn take_actions(&self) -> Vec<Action> {
+        self.context
+            .lock()
+            .map(|mut context| std::mem::take(&mut context.actions))
+            .unwrap_or_default()
+    }
+
+    fn cancel_timers(&mut self) {
+        for timer in self.timers.drain(..) {
+            timer.cancel();
+        }
+    }
+}
+
+fn load(script: &Arc<Mutex<Script>>, server: &Server, on_error: &ErrorHandler) {
+    let loaded = match script.lock() {
+        Ok(mut script) => script.load().map_err(|e| (script.path.clone(), e)),
+        Err(_) => return,
+    };
+
+    match loaded {
+        Ok(()) => {
+            let _ = run(script, server, on_error, "on_load", Vec::new());
+        }
+        Err((path, error)) => on_error(&path, &error),
+    }
+}
+
+// Calls `function` in the script, then carries out whatever it asked for
+fn run(
+    script: &Arc<Mutex<Script>>,
+    server: &Server,
+    on_error: &ErrorHandler,
+    function: &str,
+    args: Vec<Dynamic>,
+) -> Dynamic {
+    let Ok(mut locked) = script.lock() else {
+        return Dynamic::UNIT;
+    };
+
+    let result = locked.call(function, args).unwrap_or_else(|error| {
+        on_error(&locked.path, &error);
+        Dynamic::UNIT
+    });
+
+    for action in locked.take_actions() {
+        match action {
+            Action::Send(message) => {
+                let _ = server.send_message(message);
+            }
+            Action::Schedule {
+                delay,
+                repeat,
+                function,
+            } => {
+                let weak = Arc::downgrade(script);
+                let on_error = on_error.clone();
+                let task = move |server: &Server| {
+                    if let Some(script) = weak.upgrade() {
+                        let _ = run(&script, server, &on_error, &function, Vec::new());
+                    }
+                };
+                let handle = if repeat {
+                    server.schedule_every(delay, task)
+                } else {
+                    server.schedule_once(delay, task)
+                };
+                locked.timers.push(handle);
+            }
+        }
+    }
+
+    result
+}
+
+fn sandboxed_engine(context: &Arc<Mutex<Context>>) -> Engine {
+    let mut engine = Engine::new();
+
+    engine
+        .set_max_operations(100_000)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(64 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new())
+        .disable_symbol("eval");
+
+    #[cfg(feature = "tracing")]
+    engine.on_print(|text| tracing::debug!(target: "irc_lib::script", "{text}"));
+    #[cfg(not(feature = "tracing"))]
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+
+    let push = |context: &Arc<Mutex<Context>>, action: Action| {
+        if let Ok(mut context) = context.lock() {
+            context.actions.push(action);
+        }
+    };
+
+    // A line that fails validation stops the script with an error instead
+    let send = move |context: &Arc<Mutex<Context>>, message: ServerResult<IrcMessage>| {
+        let message = message.map_err(|e| format!("refused to send: {e}"))?;
+        push(context, Action::Send(message));
+        Ok::<_, Box<EvalAltResult>>(())
+    };
+
+    let ctx = context.clone();
+    engine.register_fn("privmsg", move |target: &str, text: &str| {
+        send(&ctx, commands::privmsg(target, text))
+    });
+    let ctx = context.clone();
+    engine.register_fn("notice", move |target: &str, text: &str| {
+        send(&ctx, commands::notice(target, text))
+    });
+    let ctx = context.clone();
+    engine.register_fn("join", move |channel: &str| {
+        send(&ctx, commands::join(channel, None))
+    });
+    let ctx = context.clone();
+    engine.register_fn("part", move |channel: &str| {
+        send(&ctx, commands::part(channel, None))
+    });
+
+    for (name, repeat) in [("after", false), ("every", true)] {
+        let schedule = move |seconds: INT, function: String| {
+            let seconds = u64::try_from(seconds)
+                .map_err(|_| format!("{name} takes seconds from now, not {seconds}"))?;
+            Ok::<_, Box<EvalAltResult>>(Action::Schedule {
+                // Repeating timers can't spin the loop
+                delay: Duration::from_secs(seconds.max(u64::from(repeat))),
+                repeat,
+                function,
+            })
+        };
+        let ctx = context.clone();
+        engine.register_fn(name, move |seconds: INT, function: &str| {
+            push(&ctx, schedule(seconds, function.to_string())?);
+            Ok::<_, Box<EvalAltResult>>(())
+        });
+        let ctx = context.clone();
+        engine.register_fn(name, move |seconds: INT, function: FnPtr| {
+            push(&ctx, schedule(seconds, function.fn_name().to_string())?);
+            Ok::<_, Box<EvalAltResult>>(())
+        });
+    }
+
+    let ctx = context.clone();
+    engine.register_fn("store_get", move |key: &str| {
+        ctx.lock()
+            .ok()
+            .and_then(|context| context.store.get(key).cloned())
+            .unwrap_or(Dynamic::UNIT)
+    });
+    let ctx = context.clone();
+    engine.register_fn("store_set", move |key: &str, value: Dynamic| {
+        if let Ok(mut context) = ctx.lock() {
+            context.store.insert(key.to_string(), value);
+        }
+    });
+    let ctx = context.clone();
+    engine.register_fn("store_remove", move |key: &str| {
+        ctx.lock()
+            .ok()
+            .and_then(|mut context| context.store.remove(key))
+            .unwrap_or(Dynamic::UNIT)
+    });
+
+    engine
+}
+
+fn message_map(server: &Server, message: &IrcMessage) -> Map {
+    let nick = match &message.prefix {
+        Some(Prefix::User { nick, .. }) => nick.clone(),
+        _ => String::new(),
+    };
+    let target = message.get_channel().cloned().unwrap_or_default();
+    // Replies to private messages go back to the sender
+    let reply_to = if target.eq_ignore_ascii_case(&server.nick) {
+        nick.clone()
+    } else {
+        target.clone()
+    };
+    let params: Array = message
+        .params
+        .iter()
+        .map(|param| param.to_string().into())
+        .collect();
+
+    let mut map = Map::new();
+    map.insert("command".into(), message.command.to_string().into());
+    map.insert("nick".into(), nick.into());
+    map.insert("target".into(), target.into());
+    map.insert(
+        "text".into(),
+        message.get_message().cloned().unwrap_or_default().into(),
+    );
+    map.insert("reply_to".into(), reply_to.into());
+    map.insert("params".into(), params.into());
+    map
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn log_error(path: &Path, error: &str) {
+    warn!(script = %path.display(), "script error: {error}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use crate::connection::MockIrcConnection;
+    use std::sync::mpsc::Receiver;
+
+    fn server() -> (Server, Receiver<IrcMessage>) {
+        let config = Config::new("localhost").nick("bot");
+        let mut server = Server::new(config, Box::new(MockIrcConnection::new()));
+        let outgoing = server.capture_outgoing();
+        (server, outgoing)
+    }
+
+    fn sent(outgoing: &Receiver<IrcMessage>) -> Vec<String> {
+        outgoing.try_iter().map(|msg| msg.to_string()).collect()
+    }
+
+    fn script_file(name: &str, source: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("irc_lib_{}_{name}.rhai", std::process::id()));
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    type Errors = Arc<Mutex<Vec<String>>>;
+
+    fn errors() -> (Errors, impl Fn(&Path, &str) + Send + Sync) {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let recorded = errors.clone();
+        (errors, move |_: &Path, error: &str| {
+            recorded.lock().unwrap().push(error.to_string())
+        })
+    }
+
+    #[test]
+    fn test_script_api() {
+        let path = script_file(
+            "api",
+            r##"
+            fn on_load() { join("#scripts"); }
+
+            fn on_message(msg) {
+                if msg.text == "!count" {
+                    let count = (store_get("count") ?? 0) + 1;
+                    store_set("count", count);
+                    privmsg(msg.reply_to, `${msg.nick} ${count}`);
+                    return true;
+                }
+                if msg.text == "!later" { after(0, "later"); }
+            }
+
+            fn later() { notice("#scripts", "later"); }
+            "##,
+        );
+        let (mut server, outgoing) = server();
+        let mut host = ScriptHost::new().script(&path);
+
+        host.on_load(&server);
+        for (line, flow) in [
+            (":nick!u@h PRIVMSG #chan :!count", Flow::Consume),
+            (":nick!u@h PRIVMSG bot :!count", Flow::Consume),
+            (":nick!u@h PRIVMSG #chan :hi", Flow::Continue),
+            (":nick!u@h PRIVMSG #chan :!later", Flow::Continue),
+        ] {
+            assert_eq!(host.message(&server, &line.parse().unwrap()), flow);
+        }
+        server.run_timers();
+
+        assert_eq!(
+            sent(&outgoing),
+            vec![
+                "JOIN #scripts",
+                "PRIVMSG #chan :nick 1",
+                "PRIVMSG nick :nick 2",
+                "NOTICE #scripts :later",
+            ]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_and_errors() {
+        let runaway = script_file("runaway", "fn on_message(msg) { loop {} }");
+        let evil = script_file("evil", r##"eval("join('#c')");"##);
+        let negative = script_file("negative", r#"after(-5, "later");"#);
+        let (server, outgoing) = server();
+        let (errors, handler) = errors();
+        let mut host = ScriptHost::new()
+            .script(&runaway)
+            .script(&evil)
+            .script(&negative)
+            .on_error(handler);
+
+        host.on_load(&server);
+        host.message(&server, &"PING :1".parse().unwrap());
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert!(errors[0].contains("eval"), "{}", errors[0]);
+        assert!(errors[1].contains("not -5"), "{}", errors[1]);
+        assert!(errors[2].contains("operations"), "{}", errors[2]);
+        assert!(sent(&outgoing).is_empty());
+        fs::remove_file(runaway).unwrap();
+        fs::remove_file(evil).unwrap();
+        fs::remove_file(negative).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_lines_that_would_break() {
+        let injected = script_file(
+            "injected",
+            r##"
+            fn on_message(msg) {
+                if msg.text == "privmsg" { privmsg("#c", "hi\r\nQUIT :owned"); }
+                if msg.text == "notice" { notice("#c\nQUIT", "hi"); }
+                if msg.text == "join" { join("#c\r\nQUIT"); }
+                if msg.text == "part" { part("#c\r"); }
+                privmsg("#c", "not reached");
+            }
+            "##,
+        );
+        let (server, outgoing) = server();
+        let (errors, handler) = errors();
+        let mut host = ScriptHost::new().script(&injected).on_error(handler);
+
+        host.on_load(&server);
+        for text in ["privmsg", "notice", "join", "part"] {
+            let line = format!(":nick!u@h PRIVMSG #c :{text}");
+            host.message(&server, &line.parse().unwrap());
+        }
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 4, "{errors:?}");
+        assert!(errors.iter().all(|error| error.contains("refused")));
+        assert!(sent(&outgoing).is_empty());
+        fs::remove_file(injected).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_store_and_old_version_on_error() {
+        let path = script_file(
+            "reload",
+            r##"fn on_message(msg) { store_set("v", 1); privmsg("#c", "v1"); }"##,
+        );
+        let (server, outgoing) = server();
+        let (errors, handler) = errors();
+        let mut host = ScriptHost::new().script(&path).on_error(handler);
+        let ping: IrcMessage = "PING :1".parse().unwrap();
+
+        host.on_load(&server);
+        host.message(&server, &ping);
+
+        let script = host.scripts[0].clone();
+        let on_error = host.on_error.clone();
+        let reload = |source: &str| {
+            fs::write(&path, source).unwrap();
+            script.lock().unwrap().modified = None;
+            assert!(script.lock().unwrap().changed());
+            load(&script, &server, &on_error);
+        };
+
+        reload(r##"fn on_message(msg) { privmsg("#c", `v2 ${store_get("v")}`); }"##);
+        host.message(&server, &ping);
+        reload("fn on_message(msg) {");
+        host.message(&server, &ping);
+        reload(r##"privmsg("#c", "half loaded"); throw "broken";"##);
+        host.message(&server, &ping);
+
+        assert_eq!(
+            sent(&outgoing),
+            vec![
+                "PRIVMSG #c :v1",
+                "PRIVMSG #c :v2 1",
+                "PRIVMSG #c :v2 1",
+                "PRIVMSG #c :v2 1"
+            ]
+        );
+        assert_eq!(errors.lock().unwrap().len(), 2);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,39 +1,228 @@
 use std::{collections::HashMap, fmt::Display, str::FromStr};
 
-use super::user::User;
+use super::state::casefold;
+use super::user::Statuses;
+
+/// What kind of channel a name is, told by its first character.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelType {
+    /// `#`, shared across the network
+    #[default]
+    Network,
+    /// `&`, only on the server we are connected to
+    Local,
+    /// `+`, without channel modes
+    Modeless,
+    /// `!`, protected from name collisions
+    Safe,
+    /// Another prefix the server lists in `CHANTYPES`
+    Other(char),
+}
+
+impl ChannelType {
+    pub fn from_prefix(prefix: char) -> Self {
+        match prefix {
+            '#' => ChannelType::Network,
+            '&' => ChannelType::Local,
+            '+' => ChannelType::Modeless,
+            '!' => ChannelType::Safe,
+            other => ChannelType::Other(other),
+        }
+    }
+
+    pub fn prefix(self) -> char {
+        match self {
+            ChannelType::Network => '#',
+            ChannelType::Local => '&',
+            ChannelType::Modeless => '+',
+            ChannelType::Safe => '!',
+            ChannelType::Other(prefix) => prefix,
+        }
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct Channel {
+    /// The full name, prefix included, e.g. `#rust` or `&local`.
     pub name: String,
-    pub users: HashMap<String, User>,
+    pub kind: ChannelType,
+    /// The nicks in the channel, lowercased, with their statuses there. Who
+    /// they are is in the server's user table, under the same key.
+    pub users: HashMap<String, Statuses>,
+    pub topic: Option<String>,
+    /// Who set the topic, a nick or a full mask depending on the server.
+    pub topic_set_by: Option<String>,
+    /// Seconds since the epoch.
+    pub topic_set_at: Option<u64>,
+    /// Seconds since the epoch.
+    pub created: Option<u64>,
+    /// The channel's modes, with their argument for those that take one such
+    /// as the key for `k`. Ban lists and member statuses aren't included.
+    pub modes: HashMap<char, Option<String>>,
+    pub bans: Vec<MaskEntry>,
+    pub excepts: Vec<MaskEntry>,
+    pub invite_excepts: Vec<MaskEntry>,
+}
+
+/// An entry of a ban, ban exception or invite exception list.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaskEntry {
+    pub mask: String,
+    pub set_by: Option<String>,
+    /// Seconds since the epoch.
+    pub set_at: Option<u64>,
+}
+
+/// Which channel modes take an argument, from the ISUPPORT `CHANMODES` and
+/// `PREFIX` tokens.
+#[derive(Debug)]
+pub(crate) struct ModeTypes {
+    lists: String,
+    always: String,
+    when_set: String,
+    // Mode letters with the status prefix they give, such as `o` and `@`
+    statuses: Vec<(char, char)>,
+}
+
+impl ModeTypes {
+    pub(crate) fn new(isupport: &HashMap<String, String>) -> Self {
+        let chanmodes = isupport
+            .get("CHANMODES")
+            .map_or("beI,k,l,imnpst", String::as_str);
+        let mut groups = chanmodes.split(',').map(str::to_string);
+        let statuses = isupport
+            .get("PREFIX")
+            .map_or("(ov)@+", String::as_str)
+            .strip_prefix('(')
+            .and_then(|prefix| prefix.split_once(')'))
+            .map(|(modes, prefixes)| modes.chars().zip(prefixes.chars()).collect())
+            .unwrap_or_default();
+
+        ModeTypes {
+            lists: groups.next().unwrap_or_default(),
+            always: groups.next().unwrap_or_default(),
+            when_set: groups.next().unwrap_or_default(),
+            statuses,
+        }
+    }
+
+    /// The status prefixes, highest first.
+    pub(crate) fn prefixes(&self) -> impl Iterator<Item = char> + '_ {
+        self.statuses.iter().map(|(_, prefix)| *prefix)
+    }
+
+    fn status(&self, mode: char) -> Option<char> {
+        self.statuses
+            .iter()
+            .find_map(|(status, prefix)| (*status == mode).then_some(*prefix))
+    }
 }
 
 impl Channel {
+    // For names the server sent, already checked against its `CHANTYPES`
     pub(crate) fn new(name: &str) -> Self {
-        Self::from_str(name).unwrap()
+        Channel {
+            name: name.to_owned(),
+            kind: name
+                .chars()
+                .next()
+                .map(ChannelType::from_prefix)
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// Applies a mode change such as `+kb-l key *!*@spam`, from `MODE` or a
+    /// `324` reply, along with who made it and when if known.
+    pub(crate) fn apply_modes(
+        &mut self,
+        modes: &str,
+        args: &[String],
+        types: &ModeTypes,
+        by: Option<&str>,
+        at: Option<u64>,
+    ) {
+        let mut args = args.iter();
+        let mut adding = true;
+
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ if types.status(mode).is_some() => {
+                    if let (Some(prefix), Some(nick)) = (types.status(mode), args.next())
+                        && let Some(statuses) = self.users.get_mut(&casefold(nick))
+                    {
+                        statuses.set(prefix, adding, types);
+                    }
+                }
+                _ if types.lists.contains(mode) => {
+                    let Some(mask) = args.next() else {
+                        continue;
+                    };
+                    let Some(list) = self.list_mut(mode) else {
+                        continue;
+                    };
+                    list.retain(|entry| entry.mask != *mask);
+                    if adding {
+                        list.push(MaskEntry {
+                            mask: mask.clone(),
+                            set_by: by.map(str::to_string),
+                            set_at: at,
+                        });
+                    }
+                }
+                _ if types.always.contains(mode) || (adding && types.when_set.contains(mode)) => {
+                    let arg = args.next().cloned();
+                    if adding {
+                        self.modes.insert(mode, arg);
+                    } else {
+                        self.modes.remove(&mode);
+                    }
+                }
+                _ if adding => {
+                    self.modes.insert(mode, None);
+                }
+                _ => {
+                    self.modes.remove(&mode);
+                }
+            }
+        }
+    }
+
+    /// The ban (`b`), ban exception (`e`) or invite exception (`I`) list.
+    pub(crate) fn list_mut(&mut self, mode: char) -> Option<&mut Vec<MaskEntry>> {
+        match mode {
+            'b' => Some(&mut self.bans),
+            'e' => Some(&mut self.excepts),
+            'I' => Some(&mut self.invite_excepts),
+            _ => None,
+        }
     }
 }
 
 impl Display for Channel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "#{}", self.name)
+        f.write_str(&self.name)
     }
 }
 
 impl FromStr for Channel {
     type Err = String;
 
+    /// Parses a full name such as `#rust`, `##rust` or `&local`. A name
+    /// without one of the `#&+!` prefixes is taken as a `#` channel.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let name = if let Some(str) = s.strip_prefix('#') {
-            str
+        let name = if s.starts_with(['#', '&', '+', '!']) {
+            s.to_owned()
         } else {
-            s
+            format!("#{s}")
         };
+        if name.len() < 2 || name.contains([' ', ',', '\u{7}', '\0', '\r', '\n']) {
+            return Err(format!("{s:?} is not a valid channel name"));
+        }
 
-        Ok(Channel {
-            name: name.to_owned(),
-            ..Default::default()
-        })
+        Ok(Channel::new(&name))
     }
 }
 
@@ -44,10 +233,90 @@ mod tests {
     #[test]
     fn test_from_str() {
         let channel: Channel = "#channel".parse().unwrap();
-        assert_eq!(channel.name, "channel");
+        assert_eq!(channel.name, "#channel");
 
         let channel: Channel = "channel".parse().unwrap();
-        assert_eq!(channel.name, "channel");
+        assert_eq!(channel.name, "#channel");
+
+        for invalid in ["", "#", "&", "#two words", "#a,b", "#bell\u{7}"] {
+            assert!(invalid.parse::<Channel>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_channel_types() {
+        for (name, kind) in [
+            ("#rust", ChannelType::Network),
+            ("##rust", ChannelType::Network),
+            ("&local", ChannelType::Local),
+            ("+modeless", ChannelType::Modeless),
+            ("!12345safe", ChannelType::Safe),
+        ] {
+            let channel: Channel = name.parse().unwrap();
+            assert_eq!(channel.name, name);
+            assert_eq!(channel.kind, kind, "{name}");
+            assert_eq!(channel.kind.prefix(), name.chars().next().unwrap());
+            assert_eq!(channel.to_string(), name);
+        }
+
+        assert_eq!(Channel::new("~odd").kind, ChannelType::Other('~'));
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_apply_modes() {
+        let types = ModeTypes::new(&HashMap::from([
+            ("CHANMODES".to_string(), "beIq,k,fl,imnpst".to_string()),
+            ("PREFIX".to_string(), "(ohv)@%+".to_string()),
+        ]));
+        let mut channel = Channel::new("#rust");
+        channel
+            .users
+            .insert("alice".to_string(), Statuses::default());
+
+        channel.apply_modes("+ntk", &args(&["secret"]), &types, None, None);
+        channel.apply_modes(
+            "+lbo-t",
+            &args(&["20", "*!*@spam", "alice"]),
+            &types,
+            Some("op"),
+            Some(1_700_000_000),
+        );
+        channel.apply_modes("+vq", &args(&["alice", "*!*@quiet"]), &types, None, None);
+
+        assert_eq!(
+            channel.modes,
+            HashMap::from([
+                ('n', None),
+                ('k', Some("secret".to_string())),
+                ('l', Some("20".to_string())),
+            ])
+        );
+        assert_eq!(
+            channel.bans,
+            vec![MaskEntry {
+                mask: "*!*@spam".to_string(),
+                set_by: Some("op".to_string()),
+                set_at: Some(1_700_000_000),
+            }]
+        );
+        assert_eq!(channel.users["alice"].prefixes(), "@+");
+
+        // Unsetting `l` takes no argument, but `k` and list modes do
+        channel.apply_modes("+h", &args(&["alice"]), &types, None, None);
+        channel.apply_modes(
+            "-lkbo",
+            &args(&["secret", "*!*@spam", "alice"]),
+            &types,
+            None,
+            None,
+        );
+        assert_eq!(channel.modes, HashMap::from([('n', None)]));
+        assert!(channel.bans.is_empty());
+        assert_eq!(channel.users["alice"].prefixes(), "%+");
     }
 
     #[test]
@@ -6,14 +6,34 @@ use std::{
     thread::JoinHandle,
 };
 
+use crate::Result;
+use crate::error::Error;
 use crate::message::{Command, IrcMessage, Param};
 
+use super::channel::ModeTypes;
+use super::commands;
+use super::event::Event;
+use super::presence::Watch;
+use super::requests::{self, ChannelModes, ListEntry, Pending, Query, WhoEntry, WhoisInfo};
+use super::state::{SharedState, StateSnapshot};
+use super::supervisor::{PluginCommand, PluginFailure, PluginHandle, PluginIds, PluginRef};
+use super::user::{Statuses, User};
+use crate::IrcPlugin;
+use crate::server::error::{Error as ServerError, Result as ServerResult};
+
 #[derive(Debug)]
 pub struct Client {
-    pub(in crate::server) thread: Option<JoinHandle<()>>,
+    pub(in crate::server) thread: Option<JoinHandle<Result<()>>>,
     pub(in crate::server) snd_channel: Option<Sender<IrcMessage>>,
     pub(in crate::server) rcv_channel: Option<Receiver<IrcMessage>>,
     pub(in crate::server) ready: Arc<(Mutex<bool>, Condvar)>,
+    pub(in crate::server) plugin_failures: Receiver<PluginFailure>,
+    pub(in crate::server) events: Receiver<Event>,
+    pub(in crate::server) plugin_commands: Sender<PluginCommand>,
+    pub(in crate::server) plugin_ids: PluginIds,
+    pub(in crate::server) requests: Sender<Box<dyn Query>>,
+    pub(in crate::server) watches: Sender<Watch>,
+    pub(in crate::server) state: SharedState,
 }
 
 impl Drop for Client {
@@ -22,12 +42,9 @@ impl Drop for Client {
         drop(self.snd_channel.take());
         drop(self.rcv_channel.take());
 
-        // Join the thread so the server keeps running
-        if let Some(thread) = self.thread.take() {
-            thread
-                .join()
-                .expect("Critical error with IRC Client. Aborting");
-        }
+        // Join the thread so the server keeps running. Anyone interested in
+        // the outcome should have called `join` instead
+        let _ = self.wait();
     }
 }
 
@@ -41,6 +58,177 @@ impl Client {
         )
     }
 
+    /// Typed events for everything that reaches the client, alongside the raw
+    /// messages from `channels`.
+    ///
+    /// Up to 10,000 unread events are buffered; after that new ones are dropped.
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+
+    /// What the worker currently knows about our nick, channels, users and the server.
+    ///
+    /// The snapshot doesn't change once taken, call this again for fresh state.
+    pub fn state(&self) -> Arc<StateSnapshot> {
+        match self.state.read() {
+            Ok(state) => state.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Panics caught in plugins, so the application can alert on them.
+    pub fn plugin_failures(&self) -> &Receiver<PluginFailure> {
+        &self.plugin_failures
+    }
+
+    /// Adds a plugin to the running worker, see `Server::register_plugin`.
+    pub fn register_plugin(&self, plugin: impl IrcPlugin + 'static) -> Result<PluginHandle> {
+        let id = self.plugin_ids.next();
+        self.send_plugin_command(PluginCommand::Register {
+            plugin: Box::new(plugin),
+            id,
+            owner: None,
+        })?;
+        Ok(PluginHandle::new(id, self.plugin_commands.clone()))
+    }
+
+    /// See `Server::remove_plugin`.
+    pub fn remove_plugin(&self, name: &str) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Remove(PluginRef::Named(name.to_string())))
+    }
+
+    /// See `Server::enable_plugin`.
+    pub fn enable_plugin(&self, name: &str) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Enable(PluginRef::Named(name.to_string())))
+    }
+
+    /// See `Server::disable_plugin`.
+    pub fn disable_plugin(&self, name: &str) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Disable(PluginRef::Named(name.to_string())))
+    }
+
+    /// See `Server::replace_plugin`.
+    pub fn replace_plugin(
+        &self,
+        name: &str,
+        factory: impl FnOnce() -> Result<Box<dyn IrcPlugin>> + Send + 'static,
+    ) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Replace(name.to_string(), Box::new(factory)))
+    }
+
+    pub fn privmsg(&self, target: &str, text: &str) -> ServerResult<()> {
+        self.send(commands::privmsg(target, text)?)
+    }
+
+    pub fn notice(&self, target: &str, text: &str) -> ServerResult<()> {
+        self.send(commands::notice(target, text)?)
+    }
+
+    /// Sends `text` as a CTCP ACTION, like `/me` does.
+    pub fn action(&self, target: &str, text: &str) -> ServerResult<()> {
+        self.send(commands::action(target, text)?)
+    }
+
+    /// Named so it doesn't clash with `Client::join`, which waits on the connection.
+    pub fn join_channel(&self, channel: &str, key: Option<&str>) -> ServerResult<()> {
+        self.send(commands::join(channel, key)?)
+    }
+
+    pub fn part(&self, channel: &str, reason: Option<&str>) -> ServerResult<()> {
+        self.send(commands::part(channel, reason)?)
+    }
+
+    pub fn kick(&self, channel: &str, nick: &str, reason: Option<&str>) -> ServerResult<()> {
+        self.send(commands::kick(channel, nick, reason)?)
+    }
+
+    /// Changes the modes of a channel or nick, e.g. `mode("#chan", "+o", &["nick"])`.
+    pub fn mode(&self, target: &str, modes: &str, args: &[&str]) -> ServerResult<()> {
+        self.send(commands::mode(target, modes, args)?)
+    }
+
+    /// Sets the topic, or asks the server for it when `topic` is `None`.
+    pub fn topic(&self, channel: &str, topic: Option<&str>) -> ServerResult<()> {
+        self.send(commands::topic(channel, topic)?)
+    }
+
+    pub fn invite(&self, nick: &str, channel: &str) -> ServerResult<()> {
+        self.send(commands::invite(nick, channel)?)
+    }
+
+    pub fn quit(&self, reason: Option<&str>) -> ServerResult<()> {
+        self.send(commands::quit(reason)?)
+    }
+
+    pub fn nick(&self, nick: &str) -> ServerResult<()> {
+        self.send(commands::nick(nick)?)
+    }
+
+    /// Marks us away with `reason`, or back when it is `None`.
+    pub fn away(&self, reason: Option<&str>) -> ServerResult<()> {
+        self.send(commands::away(reason)?)
+    }
+
+    /// See `Server::watch`.
+    pub fn watch(&self, nick: &str) -> ServerResult<()> {
+        let nick = commands::valid_nick(nick)?;
+        self.watches
+            .send(Watch::Add(nick))
+            .map_err(|_| ServerError::Send)
+    }
+
+    pub fn unwatch(&self, nick: &str) -> ServerResult<()> {
+        self.watches
+            .send(Watch::Remove(nick.to_string()))
+            .map_err(|_| ServerError::Send)
+    }
+
+    fn send(&self, message: IrcMessage) -> ServerResult<()> {
+        self.snd_channel
+            .as_ref()
+            .ok_or(ServerError::Send)?
+            .send(message)
+            .map_err(|_| ServerError::Send)
+    }
+
+    /// Asks the server about `nick`, see `Pending` for getting the answer.
+    pub fn whois(&self, nick: &str) -> Pending<WhoisInfo> {
+        self.request(requests::whois(nick))
+    }
+
+    /// Lists the users matching `mask`, which may be a channel.
+    pub fn who(&self, mask: &str) -> Pending<Vec<WhoEntry>> {
+        self.request(requests::who(mask))
+    }
+
+    /// Lists the users in `channel` with their statuses there, straight from the server.
+    pub fn names(&self, channel: &str) -> Pending<Vec<(User, Statuses)>> {
+        let types = ModeTypes::new(&self.state().isupport);
+        self.request(requests::names(channel, types))
+    }
+
+    /// Lists the channels on the network. This can be slow on large networks.
+    pub fn list(&self) -> Pending<Vec<ListEntry>> {
+        self.request(requests::list())
+    }
+
+    pub fn channel_modes(&self, channel: &str) -> Pending<ChannelModes> {
+        self.request(requests::channel_modes(channel))
+    }
+
+    fn request<T>(&self, (query, pending): (Box<dyn Query>, Pending<T>)) -> Pending<T> {
+        // If the worker is gone the query is dropped, which fails `pending`
+        let _ = self.requests.send(query);
+        pending
+    }
+
+    fn send_plugin_command(&self, command: PluginCommand) -> Result<()> {
+        Ok(self
+            .plugin_commands
+            .send(command)
+            .map_err(|_| ServerError::Send)?)
+    }
+
     // Blocks until the connection is considered ready
     fn wait_ready(&self) {
         let (lock, cvar) = &*self.ready;
@@ -50,18 +238,31 @@ impl Client {
         }
     }
 
-    pub fn shutdown(self) {
+    /// Blocks until the connection ends, returning why it ended.
+    ///
+    /// A connection closed after a QUIT is a clean exit, anything else is an error.
+    pub fn join(mut self) -> Result<()> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| Error::WorkerPanicked)?,
+            None => Ok(()),
+        }
+    }
+
+    pub fn shutdown(self) -> Result<()> {
         // Time to close our connection!
-        if let Some(send) = &self.snd_channel {
-            if let Ok(msg) = IrcMessage::builder()
+        if let Some(send) = &self.snd_channel
+            && let Ok(msg) = IrcMessage::builder()
                 .command(Command::Quit)
                 .param(Param::Message("Client shutting down".to_string()))
                 .build()
-            {
-                let _ = send.send(msg);
-            }
+        {
+            let _ = send.send(msg);
         }
 
-        drop(self);
+        self.join()
     }
 }
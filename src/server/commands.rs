@@ -0,0 +1,202 @@
+//! Builds the messages behind the `Server` and `Client` convenience methods,
+//! refusing anything that would break the line or address the wrong target.
+
+use super::error::{Error, Result};
+use crate::message::{Command, IrcMessage, Param};
+
+const CHANNEL_PREFIXES: [char; 4] = ['#', '&', '+', '!'];
+
+pub(crate) fn privmsg(target: &str, text: &str) -> Result<IrcMessage> {
+    Ok(message(
+        Command::PrivMsg,
+        vec![Param::Channel(valid_target(target)?), trailing(text)?],
+    ))
+}
+
+pub(crate) fn notice(target: &str, text: &str) -> Result<IrcMessage> {
+    Ok(message(
+        Command::Notice,
+        vec![Param::Channel(valid_target(target)?), trailing(text)?],
+    ))
+}
+
+/// A CTCP ACTION, what `/me` sends.
+pub(crate) fn action(target: &str, text: &str) -> Result<IrcMessage> {
+    privmsg(target, &format!("\u{1}ACTION {}\u{1}", valid_text(text)?))
+}
+
+pub(crate) fn join(channel: &str, key: Option<&str>) -> Result<IrcMessage> {
+    let mut params = vec![Param::Channel(valid_channel(channel)?)];
+    if let Some(key) = key {
+        params.push(Param::Unknown(valid_word(key)?));
+    }
+    Ok(message(Command::Join, params))
+}
+
+pub(crate) fn part(channel: &str, reason: Option<&str>) -> Result<IrcMessage> {
+    let mut params = vec![Param::Channel(valid_channel(channel)?)];
+    params.extend(reason.map(trailing).transpose()?);
+    Ok(message(Command::Part, params))
+}
+
+pub(crate) fn kick(channel: &str, nick: &str, reason: Option<&str>) -> Result<IrcMessage> {
+    let mut params = vec![
+        Param::Channel(valid_channel(channel)?),
+        Param::Unknown(valid_nick(nick)?),
+    ];
+    params.extend(reason.map(trailing).transpose()?);
+    Ok(message(Command::Kick, params))
+}
+
+/// Sets `modes` such as `+o` on a channel or nick, with their arguments.
+pub(crate) fn mode(target: &str, modes: &str, args: &[&str]) -> Result<IrcMessage> {
+    let mut params = vec![Param::Channel(valid_target(target)?)];
+    if !modes.is_empty() {
+        params.push(Param::Unknown(valid_word(modes)?));
+    }
+    for arg in args {
+        params.push(Param::Unknown(valid_word(arg)?));
+    }
+    Ok(message(Command::Mode, params))
+}
+
+/// Sets the topic, or asks for it when `topic` is `None`.
+pub(crate) fn topic(channel: &str, topic: Option<&str>) -> Result<IrcMessage> {
+    let mut params = vec![Param::Channel(valid_channel(channel)?)];
+    params.extend(topic.map(trailing).transpose()?);
+    Ok(message(Command::Topic, params))
+}
+
+pub(crate) fn invite(nick: &str, channel: &str) -> Result<IrcMessage> {
+    Ok(message(
+        Command::Invite,
+        vec![
+            Param::Unknown(valid_nick(nick)?),
+            Param::Channel(valid_channel(channel)?),
+        ],
+    ))
+}
+
+pub(crate) fn quit(reason: Option<&str>) -> Result<IrcMessage> {
+    let params = reason.map(trailing).transpose()?.into_iter().collect();
+    Ok(message(Command::Quit, params))
+}
+
+pub(crate) fn nick(nick: &str) -> Result<IrcMessage> {
+    Ok(message(Command::Nick, vec![Param::Nick(valid_nick(nick)?)]))
+}
+
+/// Marks us away with `reason`, or back when it is `None`.
+pub(crate) fn away(reason: Option<&str>) -> Result<IrcMessage> {
+    let params = reason.map(trailing).transpose()?.into_iter().collect();
+    Ok(message(Command::Away, params))
+}
+
+fn message(command: Command, params: Vec<Param>) -> IrcMessage {
+    IrcMessage::new(None, command, params)
+}
+
+fn trailing(text: &str) -> Result<Param> {
+    Ok(Param::Message(valid_text(text)?.to_string()))
+}
+
+// Anything that would end the line early lets the rest be read as another command
+fn valid_text(text: &str) -> Result<&str> {
+    if text.contains(['\r', '\n', '\0']) {
+        return Err(Error::InvalidText(text.to_string()));
+    }
+    Ok(text)
+}
+
+// A single middle parameter: no spaces, and not mistakable for a trailing one
+pub(crate) fn valid_word(word: &str) -> Result<String> {
+    if word.is_empty()
+        || word.starts_with(':')
+        || word.contains([' ', ',', '\r', '\n', '\0', '\u{7}'])
+    {
+        return Err(Error::InvalidTarget(word.to_string()));
+    }
+    Ok(word.to_string())
+}
+
+fn valid_target(target: &str) -> Result<String> {
+    if target.starts_with(CHANNEL_PREFIXES) {
+        valid_channel(target)
+    } else {
+        valid_nick(target)
+    }
+}
+
+pub(crate) fn valid_channel(channel: &str) -> Result<String> {
+    if channel.len() < 2 || !channel.starts_with(CHANNEL_PREFIXES) {
+        return Err(Error::InvalidTarget(channel.to_string()));
+    }
+    valid_word(channel)
+}
+
+pub(crate) fn valid_nick(nick: &str) -> Result<String> {
+    let first = nick.chars().next();
+    if first.is_none_or(|c| c.is_ascii_digit() || c == '-' || CHANNEL_PREFIXES.contains(&c))
+        || nick.contains(['!', '@'])
+    {
+        return Err(Error::InvalidTarget(nick.to_string()));
+    }
+    valid_word(nick)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(message: Result<IrcMessage>) -> String {
+        message.unwrap().to_string()
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            line(privmsg("#chan", "hi there")),
+            "PRIVMSG #chan :hi there"
+        );
+        assert_eq!(line(notice("nick", "psst")), "NOTICE nick :psst");
+        assert_eq!(
+            line(action("#chan", "waves")),
+            "PRIVMSG #chan :\u{1}ACTION waves\u{1}"
+        );
+        assert_eq!(line(join("#chan", None)), "JOIN #chan");
+        assert_eq!(line(join("&local", Some("key"))), "JOIN &local key");
+        assert_eq!(line(part("#chan", Some("bye all"))), "PART #chan :bye all");
+        assert_eq!(line(kick("#chan", "troll", None)), "KICK #chan troll");
+        assert_eq!(
+            line(mode("#chan", "+ov", &["alice", "bob"])),
+            "MODE #chan +ov alice bob"
+        );
+        assert_eq!(line(topic("#chan", None)), "TOPIC #chan");
+        assert_eq!(line(topic("#chan", Some(""))), "TOPIC #chan :");
+        assert_eq!(line(invite("alice", "#chan")), "INVITE alice #chan");
+        assert_eq!(line(quit(Some("later"))), "QUIT :later");
+        assert_eq!(line(nick("rusty")), "NICK rusty");
+        assert_eq!(line(away(Some("lunch"))), "AWAY :lunch");
+        assert_eq!(line(away(None)), "AWAY");
+    }
+
+    #[test]
+    fn test_validation() {
+        for target in ["", "#", "two words", "a,b", ":sneaky", "1nick", "n!u@h"] {
+            assert!(
+                matches!(privmsg(target, "hi"), Err(Error::InvalidTarget(_))),
+                "{target:?} should be rejected"
+            );
+        }
+        assert!(matches!(join("chan", None), Err(Error::InvalidTarget(_))));
+        assert!(matches!(
+            join("#chan", Some("two words")),
+            Err(Error::InvalidTarget(_))
+        ));
+        assert!(matches!(
+            privmsg("#chan", "hi\r\nQUIT"),
+            Err(Error::InvalidText(_))
+        ));
+        assert!(matches!(quit(Some("bye\n")), Err(Error::InvalidText(_))));
+    }
+}
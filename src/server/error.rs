@@ -6,6 +6,23 @@ pub enum Error {
     Read(String),
     Write(String, String),
     Send,
+    LockPoisoned,
+    InvalidSchedule(String),
+    /// The server didn't answer a request in time.
+    Timeout(String),
+    /// The server answered a request with an error numeric.
+    RequestFailed(String),
+    /// The connection went away before a request was answered.
+    Disconnected,
+    /// Not a valid channel, nick or parameter to send.
+    InvalidTarget(String),
+    /// Message text with line breaks or NULs, which could smuggle in another command.
+    InvalidText(String),
+    /// A `NetworkManager` already has a network by this name.
+    DuplicateNetwork(String),
+    UnknownNetwork(String),
+    /// An empty `CommandRouter` prefix, which would make every message a command.
+    EmptyPrefix,
 }
 
 // region:    --- Error Boilerplate
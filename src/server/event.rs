@@ -0,0 +1,245 @@
+use crate::message::{Command, IrcMessage, Prefix};
+
+/// How many events wait for the `Client` before new ones are dropped, so a
+/// client that never reads them doesn't grow without bound.
+pub(crate) const EVENT_BUFFER: usize = 10_000;
+
+/// What happened on the connection, for clients that would rather not match
+/// on raw `IrcMessage`s. Messages without a typed event arrive as `Raw`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Connected,
+    Registered {
+        nick: String,
+    },
+    Message {
+        from: String,
+        target: String,
+        text: String,
+        /// A CTCP ACTION, `/me`, with the CTCP markers stripped from `text`.
+        is_action: bool,
+    },
+    Notice {
+        from: String,
+        target: String,
+        text: String,
+    },
+    Joined {
+        channel: String,
+        nick: String,
+    },
+    Parted {
+        channel: String,
+        nick: String,
+        reason: Option<String>,
+    },
+    Kicked {
+        channel: String,
+        nick: String,
+        by: String,
+        reason: Option<String>,
+    },
+    NickChanged {
+        old: String,
+        new: String,
+    },
+    /// From someone changing it, or the server telling us on join. `by` is
+    /// only known for changes.
+    TopicChanged {
+        channel: String,
+        topic: Option<String>,
+        by: Option<String>,
+    },
+    ModeChanged {
+        target: String,
+        modes: String,
+        args: Vec<String>,
+        by: String,
+    },
+    /// A watched nick came online or went offline, see `Server::watch`.
+    PresenceChanged {
+        nick: String,
+        online: bool,
+    },
+    /// A plugin panicked, as also reported through `Client::plugin_failures`.
+    PluginFailed {
+        plugin: String,
+        message: String,
+        /// How many times it has failed so far.
+        failures: usize,
+        /// Whether it has been disabled because of this failure.
+        disabled: bool,
+    },
+    /// A plugin given to `Server::replace_plugin` couldn't be created.
+    PluginLoadFailed {
+        plugin: String,
+        error: String,
+    },
+    Disconnected,
+    /// Why the connection worker stopped.
+    Error(String),
+    Raw(IrcMessage),
+}
+
+impl From<&IrcMessage> for Event {
+    fn from(message: &IrcMessage) -> Self {
+        let from = match &message.prefix {
+            Some(Prefix::User { nick, .. }) => nick.clone(),
+            Some(Prefix::Server(server)) => server.clone(),
+            None => String::new(),
+        };
+        let mut args = message.args().into_iter();
+        let raw = || Event::Raw(message.clone());
+
+        match &message.command {
+            Command::PrivMsg => {
+                let (Some(target), Some(text)) = (args.next(), args.next()) else {
+                    return raw();
+                };
+                match text
+                    .strip_prefix("\u{1}ACTION ")
+                    .map(|action| action.trim_end_matches('\u{1}'))
+                {
+                    Some(action) => Event::Message {
+                        from,
+                        target,
+                        text: action.to_string(),
+                        is_action: true,
+                    },
+                    None => Event::Message {
+                        from,
+                        target,
+                        text,
+                        is_action: false,
+                    },
+                }
+            }
+            Command::Notice => match (args.next(), args.next()) {
+                (Some(target), Some(text)) => Event::Notice { from, target, text },
+                _ => raw(),
+            },
+            Command::Join => match args.next() {
+                Some(channel) => Event::Joined {
+                    channel,
+                    nick: from,
+                },
+                None => raw(),
+            },
+            Command::Part => match args.next() {
+                Some(channel) => Event::Parted {
+                    channel,
+                    nick: from,
+                    reason: message.get_message().cloned(),
+                },
+                None => raw(),
+            },
+            Command::Kick => match (args.next(), args.next()) {
+                (Some(channel), Some(nick)) => Event::Kicked {
+                    channel,
+                    nick,
+                    by: from,
+                    reason: args.next(),
+                },
+                _ => raw(),
+            },
+            Command::Nick => match args.next() {
+                Some(new) => Event::NickChanged { old: from, new },
+                None => raw(),
+            },
+            Command::Topic => match (args.next(), args.next()) {
+                (Some(channel), topic) => Event::TopicChanged {
+                    channel,
+                    topic: topic.filter(|topic| !topic.is_empty()),
+                    by: Some(from),
+                },
+                _ => raw(),
+            },
+            Command::Numeric(332) => match (args.next(), args.next(), args.next()) {
+                (Some(_), Some(channel), Some(topic)) => Event::TopicChanged {
+                    channel,
+                    topic: Some(topic),
+                    by: None,
+                },
+                _ => raw(),
+            },
+            Command::Mode => match (args.next(), args.next()) {
+                (Some(target), Some(modes)) => Event::ModeChanged {
+                    target,
+                    modes,
+                    args: args.collect(),
+                    by: from,
+                },
+                _ => raw(),
+            },
+            _ => raw(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(line: &str) -> Event {
+        Event::from(&line.parse::<IrcMessage>().unwrap())
+    }
+
+    #[test]
+    fn test_from_message() {
+        assert_eq!(
+            event(":nick!u@h PRIVMSG #chan :hello there"),
+            Event::Message {
+                from: "nick".to_string(),
+                target: "#chan".to_string(),
+                text: "hello there".to_string(),
+                is_action: false,
+            }
+        );
+        assert_eq!(
+            event(":nick!u@h PRIVMSG #chan :\u{1}ACTION waves\u{1}"),
+            Event::Message {
+                from: "nick".to_string(),
+                target: "#chan".to_string(),
+                text: "waves".to_string(),
+                is_action: true,
+            }
+        );
+        assert_eq!(
+            event(":op!u@h KICK #chan troll :be nice"),
+            Event::Kicked {
+                channel: "#chan".to_string(),
+                nick: "troll".to_string(),
+                by: "op".to_string(),
+                reason: Some("be nice".to_string()),
+            }
+        );
+        assert_eq!(
+            event(":old!u@h NICK new"),
+            Event::NickChanged {
+                old: "old".to_string(),
+                new: "new".to_string(),
+            }
+        );
+        assert_eq!(
+            event(":srv.example 332 me #chan :The topic"),
+            Event::TopicChanged {
+                channel: "#chan".to_string(),
+                topic: Some("The topic".to_string()),
+                by: None,
+            }
+        );
+        assert_eq!(
+            event(":op!u@h MODE #chan +ov alice bob"),
+            Event::ModeChanged {
+                target: "#chan".to_string(),
+                modes: "+ov".to_string(),
+                args: vec!["alice".to_string(), "bob".to_string()],
+                by: "op".to_string(),
+            }
+        );
+        assert!(matches!(
+            event(":srv.example 372 me :- MOTD line"),
+            Event::Raw(_)
+        ));
+    }
+}
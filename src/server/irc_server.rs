@@ -1,41 +1,134 @@
-use crate::connection::IrcConnection;
-use crate::message::{Command, IrcMessage, Param};
-use crate::{Config, connection::ConnectionNegotiator};
+use crate::connection::error::{Error as ConnectionError, Result as ConnectionResult};
+use crate::connection::{IrcConnection, sasl};
+use crate::message::{Command, IrcMessage, Param, Prefix};
+use crate::{Config, Flow, IrcPlugin, connection::ConnectionNegotiator, middleware};
 
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
-    collections::HashMap,
+    cell::Cell,
+    collections::{HashMap, HashSet, VecDeque},
     sync::{
         Arc, Condvar, Mutex,
-        mpsc::{self, Sender},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender},
     },
     thread,
 };
 
-use super::channel::Channel;
+use super::channel::{Channel, ChannelType, MaskEntry, ModeTypes};
 use super::client::Client;
+use super::commands;
 use super::error::{Error, Result};
+use super::event::{EVENT_BUFFER, Event};
+use super::network_manager::{Ended, NetworkEvent};
+use super::presence::{Presence, Watch};
+use super::requests::{Offer, Query};
+use super::scheduler::{self, CronSchedule, Schedule, Scheduler, Timer, TimerHandle};
+use super::state::{self, SharedState, StateSnapshot};
+use super::supervisor::{
+    self, PluginCommand, PluginFailure, PluginHandle, PluginIds, PluginRef, PluginSlot,
+};
+use super::throttle::Throttle;
 use super::user::User;
 
 #[derive(Debug)]
 pub struct Server {
     pub address: String,
+    pub nick: String,
+    /// Our channels, by lowercased name.
     pub channels: HashMap<String, Channel>,
+    /// Everyone in our channels, by lowercased nick.
+    pub users: HashMap<String, User>,
     config: Config,
     connection: Arc<Mutex<Box<dyn IrcConnection>>>,
     sender: Option<Sender<IrcMessage>>,
+    events: Option<SyncSender<Event>>,
+    network: String,
+    network_events: Option<SyncSender<NetworkEvent>>,
+    // Where a `NetworkManager` learns that the worker stopped for good
+    network_ended: Option<Ended>,
     ready: Arc<(Mutex<bool>, Condvar)>,
+    scheduler: Scheduler,
+    plugins: Vec<PluginSlot>,
+    plugin_ids: PluginIds,
+    // The plugin being called, which owns the timers and plugins it adds
+    calling: Cell<Option<u64>>,
+    plugin_failures: Option<Sender<PluginFailure>>,
+    plugin_commands: Sender<PluginCommand>,
+    plugin_command_rcv: Receiver<PluginCommand>,
+    requests: Sender<Box<dyn Query>>,
+    request_rcv: Receiver<Box<dyn Query>>,
+    in_flight: Vec<Box<dyn Query>>,
+    next_label: u64,
+    // Open `labeled-response` batches, by reference, with their label
+    batches: HashMap<String, String>,
+    throttle: Throttle,
+    registered: bool,
+    logged_in: bool,
+    login_deadline: Option<Instant>,
+    available_caps: HashMap<String, String>,
+    capabilities: HashSet<String>,
+    isupport: HashMap<String, String>,
+    // Channels and list modes whose listing is coming in
+    listing: HashSet<(String, char)>,
+    // Channels waiting for their `WHO`, and the one being answered
+    who_queue: VecDeque<String>,
+    who_pending: Option<(String, Instant)>,
+    presence: Presence,
+    watches: Sender<Watch>,
+    watch_rcv: Receiver<Watch>,
+    state: SharedState,
 }
 
 impl Server {
-    pub fn new(config: Config, connection: Box<dyn IrcConnection>) -> Self {
+    pub fn new(mut config: Config, connection: Box<dyn IrcConnection>) -> Self {
         let ready = Arc::new((Mutex::new(false), Condvar::new()));
+        let plugin_ids = PluginIds::default();
+        let mut plugins: Vec<PluginSlot> = std::mem::take(&mut config.plugins)
+            .into_iter()
+            .map(|plugin| PluginSlot::new(plugin, plugin_ids.next()))
+            .collect();
+        supervisor::sort_by_priority(&mut plugins);
+        let (plugin_commands, plugin_command_rcv) = mpsc::channel();
+        let (requests, request_rcv) = mpsc::channel();
+        let (watches, watch_rcv) = mpsc::channel();
         Self {
             address: config.server.clone(),
+            nick: config.nick.clone(),
             channels: config.channels.clone(),
+            users: HashMap::new(),
             connection: Arc::new(Mutex::new(connection)),
             sender: None,
+            events: None,
+            network: config.server.clone(),
+            network_events: None,
+            network_ended: None,
             ready,
+            scheduler: Scheduler::new(),
+            plugins,
+            plugin_ids,
+            calling: Cell::new(None),
+            plugin_failures: None,
+            plugin_commands,
+            plugin_command_rcv,
+            requests,
+            request_rcv,
+            in_flight: Vec::new(),
+            next_label: 0,
+            batches: HashMap::new(),
+            throttle: Throttle::new(config.flood_limit),
+            registered: false,
+            logged_in: false,
+            login_deadline: None,
+            available_caps: HashMap::new(),
+            capabilities: HashSet::new(),
+            isupport: HashMap::new(),
+            listing: HashSet::new(),
+            who_queue: VecDeque::new(),
+            who_pending: None,
+            presence: Presence::new(&config.watch),
+            watches,
+            watch_rcv,
+            state: SharedState::default(),
             config,
         }
     }
@@ -57,102 +150,656 @@ impl Server {
         }
     }
 
+    pub fn privmsg(&self, target: &str, text: &str) -> Result<()> {
+        self.send_message(commands::privmsg(target, text)?)
+    }
+
+    pub fn notice(&self, target: &str, text: &str) -> Result<()> {
+        self.send_message(commands::notice(target, text)?)
+    }
+
+    /// Sends `text` as a CTCP ACTION, like `/me` does.
+    pub fn action(&self, target: &str, text: &str) -> Result<()> {
+        self.send_message(commands::action(target, text)?)
+    }
+
+    pub fn join(&self, channel: &str, key: Option<&str>) -> Result<()> {
+        self.send_message(commands::join(channel, key)?)
+    }
+
+    pub fn part(&self, channel: &str, reason: Option<&str>) -> Result<()> {
+        self.send_message(commands::part(channel, reason)?)
+    }
+
+    pub fn kick(&self, channel: &str, nick: &str, reason: Option<&str>) -> Result<()> {
+        self.send_message(commands::kick(channel, nick, reason)?)
+    }
+
+    /// Changes the modes of a channel or nick, e.g. `mode("#chan", "+o", &["nick"])`.
+    pub fn mode(&self, target: &str, modes: &str, args: &[&str]) -> Result<()> {
+        self.send_message(commands::mode(target, modes, args)?)
+    }
+
+    /// Sets the topic, or asks the server for it when `topic` is `None`.
+    pub fn topic(&self, channel: &str, topic: Option<&str>) -> Result<()> {
+        self.send_message(commands::topic(channel, topic)?)
+    }
+
+    pub fn invite(&self, nick: &str, channel: &str) -> Result<()> {
+        self.send_message(commands::invite(nick, channel)?)
+    }
+
+    pub fn quit(&self, reason: Option<&str>) -> Result<()> {
+        self.send_message(commands::quit(reason)?)
+    }
+
+    pub fn nick(&self, nick: &str) -> Result<()> {
+        self.send_message(commands::nick(nick)?)
+    }
+
+    /// Marks us away with `reason`, or back when it is `None`.
+    pub fn away(&self, reason: Option<&str>) -> Result<()> {
+        self.send_message(commands::away(reason)?)
+    }
+
+    /// The name this connection was given in a `NetworkManager`, or its address.
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    // Names this connection and sends its events to a `NetworkManager` as well
+    pub(crate) fn join_manager(
+        &mut self,
+        network: &str,
+        events: SyncSender<NetworkEvent>,
+        ended: Ended,
+    ) {
+        self.network = network.to_string();
+        self.network_events = Some(events);
+        self.network_ended = Some(ended);
+    }
+
+    /// A copy of everything tracked about the connection, as `Client::state` sees it.
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            nick: self.nick.clone(),
+            registered: self.registered,
+            channels: self.channels.clone(),
+            users: self.users.clone(),
+            available_caps: self.available_caps.clone(),
+            capabilities: self.capabilities.clone(),
+            isupport: self.isupport.clone(),
+            presence: self.presence.online.clone(),
+        }
+    }
+
+    /// Reports when `nick` comes online or goes offline, as
+    /// `Event::PresenceChanged` and through `IrcPlugin::on_presence`. Uses
+    /// `MONITOR` where the server supports it and polls with `ISON` otherwise.
+    pub fn watch(&self, nick: &str) -> Result<()> {
+        let nick = commands::valid_nick(nick)?;
+        self.watches.send(Watch::Add(nick)).map_err(|_| Error::Send)
+    }
+
+    pub fn unwatch(&self, nick: &str) -> Result<()> {
+        self.watches
+            .send(Watch::Remove(nick.to_string()))
+            .map_err(|_| Error::Send)
+    }
+
+    /// Whether a watched nick is online, `None` until the server has said.
+    pub fn is_online(&self, nick: &str) -> Option<bool> {
+        self.presence.is_online(nick)
+    }
+
+    /// The value of an `RPL_ISUPPORT` token, empty for tokens without one.
+    pub fn isupport(&self, token: &str) -> Option<&str> {
+        self.isupport.get(token).map(String::as_str)
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Runs `task` once, `delay` from now.
+    pub fn schedule_once(
+        &self,
+        delay: Duration,
+        task: impl FnMut(&Server) + Send + 'static,
+    ) -> TimerHandle {
+        self.scheduler.add(
+            Timer::new(
+                scheduler::after(Instant::now(), delay),
+                Schedule::Once,
+                task,
+            )
+            .owned_by(self.calling.get()),
+        )
+    }
+
+    /// Runs `task` every `interval`, starting one `interval` from now.
+    pub fn schedule_every(
+        &self,
+        interval: Duration,
+        task: impl FnMut(&Server) + Send + 'static,
+    ) -> TimerHandle {
+        self.scheduler.add(
+            Timer::new(
+                scheduler::after(Instant::now(), interval),
+                Schedule::Every(interval),
+                task,
+            )
+            .owned_by(self.calling.get()),
+        )
+    }
+
+    /// Runs `task` whenever the five field cron `expression` matches, in UTC.
+    ///
+    /// ```rust
+    /// # fn plugin(server: &irc_lib::Server) -> irc_lib::Result<()> {
+    /// // Weekdays at 09:00
+    /// server.schedule_cron("0 9 * * 1-5", |server| {
+    ///     // ...
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn schedule_cron(
+        &self,
+        expression: &str,
+        task: impl FnMut(&Server) + Send + 'static,
+    ) -> Result<TimerHandle> {
+        let cron: CronSchedule = expression.parse()?;
+        let first = cron
+            .until_next(SystemTime::now())
+            .map(|wait| scheduler::after(Instant::now(), wait))
+            .ok_or_else(|| Error::InvalidSchedule(expression.to_string()))?;
+
+        Ok(self
+            .scheduler
+            .add(Timer::new(first, Schedule::Cron(cron), task).owned_by(self.calling.get())))
+    }
+
+    /// Adds a plugin to the running worker. It is loaded on the next loop tick.
+    /// The handle removes, enables or disables this plugin alone.
+    pub fn register_plugin(&self, plugin: impl IrcPlugin + 'static) -> Result<PluginHandle> {
+        let id = self.plugin_ids.next();
+        self.send_plugin_command(PluginCommand::Register {
+            plugin: Box::new(plugin),
+            id,
+            owner: self.calling.get(),
+        })?;
+        Ok(PluginHandle::new(id, self.plugin_commands.clone()))
+    }
+
+    /// Removes every plugin called `name`, either its full or short type name
+    /// unless the plugin overrides `IrcPlugin::name`, along with the timers
+    /// they scheduled and the plugins they registered.
+    pub fn remove_plugin(&self, name: &str) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Remove(PluginRef::Named(name.to_string())))
+    }
+
+    /// Re-enables a disabled plugin, also clearing its failure count. It gets
+    /// `on_load` again, and `on_register` if we are registered.
+    pub fn enable_plugin(&self, name: &str) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Enable(PluginRef::Named(name.to_string())))
+    }
+
+    /// Stops calling a plugin without removing it.
+    pub fn disable_plugin(&self, name: &str) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Disable(PluginRef::Named(name.to_string())))
+    }
+
+    /// Removes every plugin called `name`, then registers whatever `factory`
+    /// builds. Used to reload plugins whose old instance must be dropped before
+    /// the new one can be created. A `factory` that fails is reported as
+    /// `Event::PluginLoadFailed`.
+    pub fn replace_plugin(
+        &self,
+        name: &str,
+        factory: impl FnOnce() -> crate::Result<Box<dyn IrcPlugin>> + Send + 'static,
+    ) -> Result<()> {
+        self.send_plugin_command(PluginCommand::Replace(name.to_string(), Box::new(factory)))
+    }
+
+    fn send_plugin_command(&self, command: PluginCommand) -> Result<()> {
+        self.plugin_commands.send(command).map_err(|_| Error::Send)
+    }
+
     fn connect(mut self) -> Client {
-        let connection = self.connection.clone();
         let (thread_snd, rcv_channel) = mpsc::channel::<IrcMessage>();
         let (snd_channel, thread_rcv) = mpsc::channel::<IrcMessage>();
+        let (failure_snd, plugin_failures) = mpsc::channel::<PluginFailure>();
+        let (event_snd, events) = mpsc::sync_channel::<Event>(EVENT_BUFFER);
         let ready = Arc::clone(&self.ready);
         self.sender = Some(snd_channel.clone());
+        self.plugin_failures = Some(failure_snd);
+        self.events = Some(event_snd);
+        let plugin_commands = self.plugin_commands.clone();
+        let plugin_ids = self.plugin_ids.clone();
+        let requests = self.requests.clone();
+        let watches = self.watches.clone();
+        self.publish_state();
+        let state = self.state.clone();
 
         let thread = thread::spawn(move || {
-            if let Ok(mut conn) = connection.lock() {
-                if conn.connect(self.address.clone()).is_err() {
-                    panic!("Could not connect to {}", self.address);
+            #[cfg(feature = "tracing")]
+            let _span = tracing::info_span!("irc_server", address = %self.address).entered();
+
+            self.notify_plugins(|plugin, server| plugin.on_load(server));
+
+            let mut attempt = 0;
+            let result = loop {
+                let result = self.work(&thread_snd, &thread_rcv);
+                let Err(e) = &result else {
+                    break result;
+                };
+                // A connection that got through registration starts the count over
+                if self.registered {
+                    attempt = 0;
+                }
+                let Some(delay) = self.config.reconnect.delay(attempt) else {
+                    break result;
+                };
+                attempt += 1;
+
+                warn!(error = %e, ?delay, attempt, "reconnecting");
+                self.emit(Event::Error(e.to_string()));
+                self.emit(Event::Disconnected);
+                if !self.back_off(&thread_rcv, delay) {
+                    break Ok(());
                 }
+                self.reset_session();
+            };
+            if let Err(e) = &result {
+                error!(error = %e, "connection worker stopped");
+                self.emit(Event::Error(e.to_string()));
+            }
+            // Before the event, so the manager has let go of the name by then
+            if let Some(ended) = &self.network_ended
+                && let Ok(mut ended) = ended.lock()
+            {
+                ended.insert(self.network.clone());
+            }
+            self.emit(Event::Disconnected);
+            self.notify_plugins(|plugin, server| plugin.on_shutdown(server));
+
+            // Never leave `Client::channels` waiting on a worker that is gone
+            let (lock, cvar) = &*self.ready;
+            if let Ok(mut conn_ready) = lock.lock() {
+                *conn_ready = true;
+                cvar.notify_all();
             }
 
-            let mut negotiator = ConnectionNegotiator::new(&self.config);
+            result
+        });
 
-            loop {
-                let mut conn = match connection.lock() {
-                    Ok(conn) => conn,
-                    Err(_) => {
-                        thread::sleep(Duration::from_millis(10)); // Avoid busy-waiting
-                        continue;
-                    }
-                };
+        Client {
+            thread: Some(thread),
+            rcv_channel: Some(rcv_channel),
+            snd_channel: Some(snd_channel),
+            ready,
+            plugin_failures,
+            events,
+            plugin_commands,
+            plugin_ids,
+            requests,
+            watches,
+            state,
+        }
+    }
+
+    // Waits out `delay` before reconnecting, returning false if the client
+    // quit meanwhile. Anything else it sent goes out on the new connection.
+    fn back_off(&mut self, thread_rcv: &Receiver<IrcMessage>, delay: Duration) -> bool {
+        let deadline = Instant::now() + delay;
+        let mut held = Vec::new();
+        let reconnect = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match thread_rcv.recv_timeout(timeout) {
+                Ok(message) if message.command == Command::Quit => break false,
+                Ok(message) => held.push(message),
+                Err(RecvTimeoutError::Timeout) => break true,
+                Err(RecvTimeoutError::Disconnected) => break false,
+            }
+        };
+        if reconnect && let Some(sender) = &self.sender {
+            for message in held {
+                let _ = sender.send(message);
+            }
+        }
+        reconnect
+    }
+
+    fn work(
+        &mut self,
+        thread_snd: &Sender<IrcMessage>,
+        thread_rcv: &Receiver<IrcMessage>,
+    ) -> crate::Result<()> {
+        let connection = self.connection.clone();
+        connection
+            .lock()
+            .map_err(|_| Error::LockPoisoned)?
+            .connect(self.address.clone())?;
+        self.emit(Event::Connected);
+
+        let mut negotiator = ConnectionNegotiator::new(&self.config);
+        let mut quitting = false;
+
+        loop {
+            self.apply_plugin_commands();
+            self.accept_requests();
+
+            let mut conn = match connection.lock() {
+                Ok(conn) => conn,
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(10)); // Avoid busy-waiting
+                    continue;
+                }
+            };
 
-                let (lock, cvar) = &*Arc::clone(&self.ready);
-                let mut conn_ready = lock.lock().unwrap();
+            let ready = Arc::clone(&self.ready);
+            let (lock, cvar) = &*ready;
+            let mut conn_ready = lock.lock().map_err(|_| Error::LockPoisoned)?;
 
-                if *conn_ready {
-                    for outgoing in thread_rcv.try_iter() {
-                        let _ = conn.send_message(&outgoing.to_string());
+            if *conn_ready {
+                while self.throttle.permits(Instant::now())
+                    && let Ok(queued) = thread_rcv.try_recv()
+                {
+                    self.throttle.record(Instant::now());
+                    for outgoing in middleware::run_chain(&mut self.config.middleware, queued) {
+                        self.notify_plugins(|plugin, server| plugin.on_outgoing(server, &outgoing));
+                        quitting |= outgoing.command == Command::Quit;
+                        if outgoing.command == Command::Ison {
+                            self.presence.sent_ison(&outgoing.args());
+                        }
+                        conn.send_message(&outgoing.to_string())?;
                     }
                 }
+            }
 
-                match conn.read() {
-                    Ok(Some(message)) => {
-                        match &message {
-                            IrcMessage {
-                                command: Command::Numeric(1..6),
-                                ..
-                            } => {
-                                self.negotiate(&mut negotiator, &mut **conn, &mut conn_ready, cvar)
+            match conn.read() {
+                Ok(Some(message)) => {
+                    self.track_users(&message);
+                    self.track_batches(&message);
+                    match &message {
+                        IrcMessage {
+                            command: Command::Numeric(1..6),
+                            ..
+                        } => {
+                            self.track_server_state(&message);
+                            // Servers without CAP register us without answering `CAP LS`
+                            negotiator.skip_caps();
+                            self.negotiate(&mut negotiator, &mut **conn, &mut conn_ready, cvar)?
+                        }
+                        IrcMessage {
+                            command: Command::Unknown(command),
+                            ..
+                        } if command == "CAP" => {
+                            self.track_server_state(&message);
+                            if state::cap_list_ends(&message.args()) {
+                                negotiator.listed(&self.available_caps);
+                                self.negotiate(&mut negotiator, &mut **conn, &mut conn_ready, cvar)?
                             }
-                            IrcMessage {
-                                command: Command::Numeric(353),
-                                params,
-                                ..
-                            } => self.parse_users(params),
-                            IrcMessage {
-                                command: Command::Ping,
-                                ..
-                            } => Self::ping_response(&mut **conn, &message),
-                            IrcMessage {
-                                command: Command::PrivMsg,
-                                params,
-                                ..
-                            } => {
-                                for param in params {
-                                    if let Param::Message(message) = param {
-                                        if message.contains('\u{1}') {
-                                            // CTCP message
-                                            Self::version_response(&mut **conn, message)
-                                        }
-                                    }
+                        }
+                        IrcMessage {
+                            command:
+                                Command::Join
+                                | Command::Part
+                                | Command::Kick
+                                | Command::Nick
+                                | Command::Quit,
+                            ..
+                        } => self.track_membership(&message),
+                        IrcMessage {
+                            command: Command::Numeric(353),
+                            params,
+                            ..
+                        } => self.parse_users(params),
+                        IrcMessage {
+                            command: Command::Numeric(315 | 352 | 354),
+                            ..
+                        } => self.parse_who(&message),
+                        IrcMessage {
+                            command: Command::Numeric(303 | 730 | 731 | 734),
+                            ..
+                        } => self.track_presence(&message),
+                        IrcMessage {
+                            command:
+                                Command::Numeric(324 | 329 | 331..=333 | 346..=349 | 367 | 368)
+                                | Command::Topic
+                                | Command::Mode
+                                | Command::Unknown(_),
+                            ..
+                        } => self.track_server_state(&message),
+                        IrcMessage {
+                            command: Command::Numeric(432 | 433),
+                            ..
+                        } if !self.registered => self.next_nick(&mut **conn)?,
+                        IrcMessage {
+                            command: Command::Numeric(code @ (900 | 901)),
+                            ..
+                        } => self.logged_in = *code == 900,
+                        IrcMessage {
+                            command: Command::Ping,
+                            ..
+                        } => Self::ping_response(&mut **conn, &message)?,
+                        IrcMessage {
+                            command: Command::PrivMsg,
+                            params,
+                            ..
+                        } => {
+                            for param in params {
+                                if let Param::Message(message) = param
+                                    && message.contains('\u{1}')
+                                {
+                                    // CTCP message
+                                    Self::version_response(&mut **conn, message)?
                                 }
                             }
-                            IrcMessage {
-                                command: Command::Version,
-                                ..
-                            } => conn.send_message("VERSION 123").unwrap(),
-                            _ => (),
                         }
+                        IrcMessage {
+                            command: Command::Version,
+                            ..
+                        } => conn.send_message("VERSION 123")?,
+                        _ => (),
+                    }
 
-                        thread_snd.send(message.clone()).ok();
-                        for plugin in self.config.plugins.iter() {
-                            plugin.message(&self, &message)
-                        }
+                    if let Command::Numeric(code) = message.command {
+                        self.route_reply(code, &message);
+                    }
+
+                    if self.config.sasl.is_some() && !self.registered {
+                        self.authenticate(&mut **conn, &message)?;
                     }
-                    Ok(None) => self.negotiate(&mut negotiator, &mut **conn, &mut conn_ready, cvar),
-                    Err(e) => {
-                        println!("Error reading from connection: {:?}", e);
-                        if !*conn_ready {
-                            *conn_ready = true;
-                            cvar.notify_all();
+
+                    if message.command == Command::Numeric(1) {
+                        // The nick we ended up with, whichever was free
+                        if let Some(nick) = message.args().into_iter().next() {
+                            self.nick = nick;
                         }
-                        break;
+                        self.registered = true;
+                        if let Some(password) = &self.config.nickserv
+                            && !self.logged_in
+                        {
+                            conn.send_message(&format!(
+                                "PRIVMSG NickServ :IDENTIFY {} {}",
+                                self.config.nick, password.0
+                            ))?;
+                        }
+                        self.login_deadline = self
+                            .config
+                            .join_after_login
+                            .map(|timeout| Instant::now() + timeout);
+                        self.emit(Event::Registered {
+                            nick: self.nick.clone(),
+                        });
+                        self.notify_plugins(|plugin, server| plugin.on_register(server));
+                    }
+
+                    if Self::changes_state(&message) {
+                        self.publish_state();
+                    }
+
+                    if self.dispatch_message(&message) == Flow::Continue {
+                        self.emit(Event::from(&message));
+                        thread_snd.send(message).ok();
                     }
                 }
+                Ok(None) => self.negotiate(&mut negotiator, &mut **conn, &mut conn_ready, cvar)?,
+                // The server closing the link is how a QUIT is acknowledged
+                Err(ConnectionError::ConnectionClosed) if quitting => {
+                    debug!("connection closed after QUIT");
+                    self.notify_plugins(|plugin, server| plugin.on_disconnect(server));
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(error = %e, "connection lost");
+                    self.notify_plugins(|plugin, server| plugin.on_disconnect(server));
+                    return Err(e.into());
+                }
+            }
+
+            self.run_timers();
+            self.expire_requests();
+            self.request_who();
+            self.update_presence(*conn_ready);
+        }
+    }
+
+    // Sends queued requests and starts waiting on their answers
+    fn accept_requests(&mut self) {
+        for mut query in self.request_rcv.try_iter().collect::<Vec<_>>() {
+            let mut message = query.message(state::chantypes(&self.isupport));
+            if self.capabilities.contains("labeled-response") {
+                self.next_label += 1;
+                let label = self.next_label.to_string();
+                message.tags.push(("label".to_string(), label.clone()));
+                query.set_label(label);
+            }
+            if self.send_message(message).is_ok() {
+                self.in_flight.push(query);
+            }
+        }
+    }
+
+    // Labeled replies go to the request with their label. Servers answer in
+    // order, so the others go to the oldest unlabeled request that wants them
+    fn route_reply(&mut self, code: u16, message: &IrcMessage) {
+        if self.in_flight.is_empty() {
+            return;
+        }
+
+        let label = self.label_of(message);
+        let args = message.args();
+        // The replies to our own `WHO` for a channel aren't anyone's request
+        if label.is_none()
+            && matches!(code, 315 | 352)
+            && self.who_pending.as_ref().is_some_and(|(channel, _)| {
+                args.get(1)
+                    .is_some_and(|name| name.eq_ignore_ascii_case(channel))
+            })
+        {
+            return;
+        }
+
+        for index in 0..self.in_flight.len() {
+            if self.in_flight[index].label() != label.as_deref() {
+                continue;
+            }
+            let chantypes = state::chantypes(&self.isupport);
+            match self.in_flight[index].offer(code, &args, chantypes) {
+                Offer::Ignored => continue,
+                // A response outside a batch is that one message
+                Offer::Taken if message.tag("label").is_some() => {
+                    self.in_flight.remove(index).finish();
+                    return;
+                }
+                Offer::Taken => return,
+                Offer::Done => {
+                    self.in_flight.remove(index);
+                    return;
+                }
+            }
+        }
+    }
+
+    // A labeled reply carries the label itself, or comes in a batch that does
+    fn label_of(&self, message: &IrcMessage) -> Option<String> {
+        message
+            .tag("label")
+            .or_else(|| {
+                message
+                    .tag("batch")
+                    .and_then(|batch| self.batches.get(batch))
+                    .map(String::as_str)
+            })
+            .map(str::to_string)
+    }
+
+    // Opens and closes `labeled-response` batches. A response ends with its
+    // batch, or is an `ACK` when there was nothing to answer
+    fn track_batches(&mut self, message: &IrcMessage) {
+        let Command::Unknown(command) = &message.command else {
+            return;
+        };
+        let args = message.args();
+        let ended = match (command.as_str(), args.first()) {
+            ("BATCH", Some(reference)) if reference.starts_with('+') => {
+                if args.get(1).is_some_and(|kind| kind == "labeled-response")
+                    && let Some(label) = message.tag("label")
+                {
+                    self.batches
+                        .insert(reference[1..].to_string(), label.to_string());
+                }
+                return;
+            }
+            ("BATCH", Some(reference)) => reference
+                .strip_prefix('-')
+                .and_then(|reference| self.batches.remove(reference)),
+            ("ACK", _) => message.tag("label").map(str::to_string),
+            _ => return,
+        };
+
+        if let Some(label) = ended
+            && let Some(index) = self
+                .in_flight
+                .iter()
+                .position(|query| query.label() == Some(label.as_str()))
+        {
+            self.in_flight.remove(index).finish();
+        }
+    }
+
+    fn expire_requests(&mut self) {
+        let timeout = self.config.request_timeout;
+        let chantypes = state::chantypes(&self.isupport);
+        self.in_flight.retain_mut(|query| {
+            if query.created().elapsed() < timeout {
+                return true;
             }
+            query.fail(Error::Timeout(query.message(chantypes).to_string()));
+            false
         });
+    }
 
-        Client {
-            thread: Some(thread),
-            rcv_channel: Some(rcv_channel),
-            snd_channel: Some(snd_channel),
-            ready,
+    pub(crate) fn run_timers(&mut self) {
+        let now = Instant::now();
+        for mut timer in self.scheduler.due(now) {
+            // Timers scheduled by a timer belong to the same plugin
+            let calling = self.calling.replace(timer.owner());
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| timer.run(self)));
+            self.calling.set(calling);
+            match result {
+                Ok(()) => self.scheduler.fired(timer, now),
+                // A panicking timer is dropped for good
+                Err(payload) => self.report_failure(PluginFailure {
+                    plugin: "timer".to_string(),
+                    message: supervisor::panic_message(payload.as_ref()),
+                    failures: 1,
+                    disabled: true,
+                }),
+            }
         }
     }
 
@@ -162,135 +809,997 @@ impl Server {
         conn: &mut dyn IrcConnection,
         ready: &mut bool,
         signal: &Condvar,
-    ) {
-        match negotiator.next() {
-            Some(message) => {
-                let _ = conn.send_message(&message);
+    ) -> ConnectionResult<()> {
+        if negotiator.awaits_caps() {
+            return Ok(());
+        }
+        if negotiator.joins_next() {
+            if !self.may_join() {
+                return Ok(());
             }
+            negotiator.limit_joins(state::max_targets(&self.isupport, "JOIN"));
+        }
+
+        match negotiator.next() {
+            Some(message) => conn.send_message(&message)?,
             None => {
                 if !*ready {
+                    debug!("connection ready");
                     *ready = true;
                     signal.notify_all();
                 }
             }
         }
+
+        Ok(())
     }
 
-    // This is a 353 message we need to parse
-    fn parse_users(&mut self, params: &[Param]) {
-        // 2nd param is the channel name, 3rd and onwards are the users
-        let channel_name = params[2].to_string();
-        let channel = self
-            .channels
-            .entry(channel_name.to_string())
-            .or_insert(Channel::new(&channel_name));
-        for param in params[3..].iter() {
-            if let Param::Unknown(user) = param {
-                let user = User::new(user);
-                channel.users.insert(user.nick.clone(), user);
-            }
-        }
+    // Channels can't be joined before the server welcomes us, nor before
+    // services log us in when `join_after_login` asks to wait for that
+    fn may_join(&self) -> bool {
+        self.registered
+            && (self.logged_in
+                || self
+                    .login_deadline
+                    .is_none_or(|deadline| Instant::now() >= deadline))
     }
 
-    fn ping_response(connection: &mut dyn IrcConnection, message: &IrcMessage) {
-        let msg = message.params.iter().find_map(|param| {
-            if let Param::Message(msg) = param {
-                Some(msg)
-            } else {
-                None
-            }
+    // Tries the alternative nicks in order, then appends underscores for as
+    // long as the nick fits in `NICKLEN`
+    fn next_nick(&mut self, conn: &mut dyn IrcConnection) -> ConnectionResult<()> {
+        let mut nicks = std::iter::once(&self.config.nick).chain(&self.config.alt_nicks);
+        let next = match nicks.position(|nick| *nick == self.nick) {
+            Some(index) => self.config.alt_nicks.get(index).cloned(),
+            None => None,
+        }
+        .or_else(|| {
+            let next = format!("{}_", self.nick);
+            (next.len() <= state::nick_len(&self.isupport)).then_some(next)
         });
+        let Some(next) = next else {
+            warn!(nick = %self.nick, "no nick left to try");
+            return Err(ConnectionError::NicksExhausted);
+        };
+
+        debug!(taken = %self.nick, next = %next, "nick in use");
+        self.nick = next;
+        conn.send_message(&format!("NICK {}", self.nick))
+    }
+
+    // Walks SASL PLAIN through to the `CAP END` the negotiator left out
+    fn authenticate(
+        &mut self,
+        conn: &mut dyn IrcConnection,
+        message: &IrcMessage,
+    ) -> ConnectionResult<()> {
+        let Some(credentials) = &self.config.sasl else {
+            return Ok(());
+        };
+        let args = message.args();
 
-        if let Some(msg) = msg {
-            connection.send_message(&format!("PONG :{}", msg)).unwrap()
+        match &message.command {
+            Command::Unknown(command) if command == "CAP" => {
+                let subcommand = args.get(1).map(|subcommand| subcommand.to_uppercase());
+                let acked_sasl = args
+                    .last()
+                    .is_some_and(|caps| caps.split_whitespace().any(|cap| cap == "sasl"));
+                match subcommand.as_deref() {
+                    Some("ACK") if acked_sasl => conn.send_message("AUTHENTICATE PLAIN"),
+                    Some("NAK") => {
+                        warn!("server refused the requested capabilities, skipping SASL");
+                        conn.send_message("CAP END")
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Command::Unknown(command) if command == "AUTHENTICATE" => {
+                if args.first().is_some_and(|arg| arg == "+") {
+                    for line in sasl::plain(&credentials.account, &credentials.password.0) {
+                        conn.send_message(&line)?;
+                    }
+                }
+                Ok(())
+            }
+            Command::Numeric(903) => {
+                debug!("logged in with SASL");
+                conn.send_message("CAP END")
+            }
+            Command::Numeric(902 | 904..=907) => {
+                warn!(reply = %message, "SASL login failed");
+                conn.send_message("CAP END")
+            }
+            _ => Ok(()),
         }
     }
 
-    fn version_response(connection: &mut dyn IrcConnection, message: &str) {
-        connection
-            .send_message(&format!("NOTICE :{} PRIVMSG :\u{1}VERSION 1\u{1}", message))
-            .unwrap();
+    // Forgets what belonged to a lost connection before reconnecting
+    fn reset_session(&mut self) {
+        self.registered = false;
+        self.logged_in = false;
+        self.login_deadline = None;
+        self.nick = self.config.nick.clone();
+        self.channels = self.config.channels.clone();
+        self.users.clear();
+        self.available_caps.clear();
+        self.capabilities.clear();
+        self.isupport.clear();
+        self.listing.clear();
+        self.batches.clear();
+        self.who_queue.clear();
+        self.who_pending = None;
+        self.presence.reset();
+        self.throttle = Throttle::new(self.config.flood_limit);
+        if let Ok(mut ready) = self.ready.0.lock() {
+            *ready = false;
+        }
+        self.publish_state();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::connection::MockIrcConnection;
-    use crate::message::{Command, IrcMessage, Param};
-    use std::time::Duration;
+    // Plugins are lent out for the duration of the call so they can be
+    // mutated while still seeing the rest of the server
+    fn notify_plugins(&mut self, mut hook: impl FnMut(&mut dyn IrcPlugin, &Server)) {
+        let mut plugins = std::mem::take(&mut self.plugins);
+        for slot in plugins.iter_mut() {
+            self.notify_plugin(slot, &mut hook);
+        }
+        self.plugins = plugins;
+    }
 
-    #[test]
-    fn test_ping_response() {
-        let mut mock_conn = MockIrcConnection::new();
-        mock_conn
-            .expect_send_message()
-            .with(mockall::predicate::eq("PONG :12345"))
-            .times(1)
-            .returning(|_| Ok(()));
+    fn notify_plugin(
+        &self,
+        slot: &mut PluginSlot,
+        hook: &mut impl FnMut(&mut dyn IrcPlugin, &Server),
+    ) {
+        if let Err(failure) = self.call_plugin(slot, |plugin| hook(plugin, self)) {
+            self.plugin_failed(slot, failure);
+        }
+    }
 
-        let message = IrcMessage {
-            prefix: None,
-            command: Command::Ping,
-            params: vec![Param::Message("12345".to_string())],
-        };
+    // Unlike other hooks, a failing `on_unload` isn't followed by another
+    fn notify_unload(&self, slot: &mut PluginSlot) {
+        if let Err(failure) = self.call_plugin(slot, |plugin| plugin.on_unload(self)) {
+            self.report_failure(failure);
+        }
+    }
 
-        Server::ping_response(&mut mock_conn, &message);
+    // A plugin disabled for failing is unloaded like one disabled on request,
+    // so enabling it again and its `on_load` pair up either way
+    fn plugin_failed(&self, slot: &mut PluginSlot, failure: PluginFailure) {
+        let disabled = failure.disabled;
+        self.report_failure(failure);
+        if disabled {
+            let calling = self.calling.replace(Some(slot.id));
+            let plugin = slot.plugin.as_mut();
+            // Already disabled, there's nothing left to do about another panic
+            let _ =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| plugin.on_unload(self)));
+            self.calling.set(calling);
+        }
     }
 
-    #[test]
-    fn test_version_response() {
-        let mut mock_conn = MockIrcConnection::new();
-        mock_conn
-            .expect_send_message()
-            .with(mockall::predicate::eq(
-                "NOTICE :test_user PRIVMSG :\u{1}VERSION 1\u{1}",
-            ))
-            .times(1)
-            .returning(|_| Ok(()));
+    // Remembers whose call it is, for whatever the plugin schedules or registers
+    fn call_plugin<R: Default>(
+        &self,
+        slot: &mut PluginSlot,
+        hook: impl FnOnce(&mut dyn IrcPlugin) -> R,
+    ) -> std::result::Result<R, PluginFailure> {
+        let calling = self.calling.replace(Some(slot.id));
+        let result = slot.call(self.config.max_plugin_failures, hook);
+        self.calling.set(calling);
+        result
+    }
 
-        let message = "test_user";
+    fn apply_plugin_commands(&mut self) {
+        let commands: Vec<PluginCommand> = self.plugin_command_rcv.try_iter().collect();
+        if commands.is_empty() {
+            return;
+        }
 
-        Server::version_response(&mut mock_conn, message);
+        let mut plugins = std::mem::take(&mut self.plugins);
+        for command in commands {
+            match command {
+                PluginCommand::Register { plugin, id, owner } => {
+                    self.load_plugin(&mut plugins, plugin, id, owner)
+                }
+                PluginCommand::Remove(plugin) => self.unload_plugins(&mut plugins, &plugin),
+                PluginCommand::Replace(name, factory) => {
+                    // The old plugins must be gone first, a shared library can't
+                    // be opened again while a previous copy is still loaded
+                    self.unload_plugins(&mut plugins, &PluginRef::Named(name.clone()));
+                    match factory() {
+                        Ok(plugin) => {
+                            self.load_plugin(&mut plugins, plugin, self.plugin_ids.next(), None)
+                        }
+                        Err(e) => {
+                            warn!(plugin = %name, error = %e, "couldn't load plugin");
+                            self.emit(Event::PluginLoadFailed {
+                                plugin: name,
+                                error: e.to_string(),
+                            });
+                        }
+                    }
+                }
+                PluginCommand::Enable(plugin) => {
+                    for slot in plugins
+                        .iter_mut()
+                        .filter(|slot| slot.is(&plugin) && !slot.enabled)
+                    {
+                        slot.enabled = true;
+                        slot.failures = 0;
+                        self.notify_plugin(slot, &mut |plugin, server| plugin.on_load(server));
+                        if self.registered {
+                            self.notify_plugin(slot, &mut |plugin, server| {
+                                plugin.on_register(server)
+                            });
+                        }
+                    }
+                }
+                PluginCommand::Disable(plugin) => {
+                    for slot in plugins
+                        .iter_mut()
+                        .filter(|slot| slot.is(&plugin) && slot.enabled)
+                    {
+                        self.notify_unload(slot);
+                        slot.enabled = false;
+                    }
+                }
+            }
+        }
+        self.plugins = plugins;
     }
 
-    #[test]
-    fn test_parse_users() {
-        let config = Config {
-            nick: "test".to_string(),
-            user: "test".to_string(),
-            server: "localhost".to_string(),
-            channels: HashMap::new(),
-            plugins: vec![],
-        };
-        let mock_conn = MockIrcConnection::new();
-        let mut server = Server::new(config, Box::new(mock_conn));
+    fn load_plugin(
+        &mut self,
+        plugins: &mut Vec<PluginSlot>,
+        plugin: Box<dyn IrcPlugin>,
+        id: u64,
+        owner: Option<u64>,
+    ) {
+        let mut slot = PluginSlot::new(plugin, id);
+        slot.owner = owner;
+        debug!(plugin = slot.plugin.name(), "loading plugin");
+        self.notify_plugin(&mut slot, &mut |plugin, server| plugin.on_load(server));
+        if self.registered {
+            self.notify_plugin(&mut slot, &mut |plugin, server| plugin.on_register(server));
+        }
+        plugins.push(slot);
+        supervisor::sort_by_priority(plugins);
+    }
 
-        let params = vec![
-            Param::Unknown("".to_string()),
-            Param::Unknown("".to_string()),
-            Param::Channel("#test".to_string()),
-            Param::Unknown("user1".to_string()),
-            Param::Unknown("user2".to_string()),
-        ];
+    fn unload_plugins(&mut self, plugins: &mut Vec<PluginSlot>, plugin: &PluginRef) {
+        let (removed, kept) = std::mem::take(plugins)
+            .into_iter()
+            .partition::<Vec<_>, _>(|slot| slot.is(plugin));
+        *plugins = kept;
+        for slot in removed {
+            self.unload_plugin(plugins, slot);
+        }
+    }
 
-        server.parse_users(&params);
+    // Whatever a plugin left behind may be code from its shared library, so
+    // the plugins and timers it added go before it does
+    fn unload_plugin(&mut self, plugins: &mut Vec<PluginSlot>, mut slot: PluginSlot) {
+        debug!(plugin = slot.plugin.name(), "unloading plugin");
+        self.notify_unload(&mut slot);
 
-        let channel = server.channels.get("#test").unwrap();
-        assert!(channel.users.contains_key("user1"));
-        assert!(channel.users.contains_key("user2"));
+        let (owned, kept) = std::mem::take(plugins)
+            .into_iter()
+            .partition::<Vec<_>, _>(|other| other.owner == Some(slot.id));
+        *plugins = kept;
+        for other in owned {
+            self.unload_plugin(plugins, other);
+        }
+        self.scheduler.remove_owned(slot.id);
     }
 
-    #[test]
-    fn test_connect_loop() {
-        let config = Config {
-            nick: "test".to_string(),
-            user: "test".to_string(),
-            server: "localhost".to_string(),
-            channels: HashMap::new(),
-            plugins: vec![],
+    // Hands an incoming message to interested plugins by priority until one consumes it
+    fn dispatch_message(&mut self, message: &IrcMessage) -> Flow {
+        let mut plugins = std::mem::take(&mut self.plugins);
+        let mut flow = Flow::Continue;
+        for slot in plugins.iter_mut().filter(|slot| slot.wants(message)) {
+            match self.call_plugin(slot, |plugin| plugin.message(self, message)) {
+                Ok(Flow::Continue) => (),
+                Ok(Flow::Consume) => {
+                    flow = Flow::Consume;
+                    break;
+                }
+                Err(failure) => self.plugin_failed(slot, failure),
+            }
+        }
+        self.plugins = plugins;
+        flow
+    }
+
+    fn report_failure(&self, failure: PluginFailure) {
+        warn!(
+            plugin = %failure.plugin,
+            message = %failure.message,
+            disabled = failure.disabled,
+            "plugin panicked"
+        );
+        self.emit(Event::PluginFailed {
+            plugin: failure.plugin.clone(),
+            message: failure.message.clone(),
+            failures: failure.failures,
+            disabled: failure.disabled,
+        });
+        if let Some(sender) = &self.plugin_failures {
+            let _ = sender.send(failure);
+        }
+    }
+
+    // Keeps our nick and who is in our channels current, firing the plugin hooks for our own moves
+    fn track_membership(&mut self, message: &IrcMessage) {
+        let Some(Prefix::User { nick, user, host }) = &message.prefix else {
+            return;
+        };
+        let own = self.is_me(nick);
+        let key = state::casefold(nick);
+        let args = message.args();
+        let channel = args.first().filter(|name| self.is_channel(name));
+        let channel_key = channel.map(|name| state::casefold(name));
+        let channel_key = channel_key.as_deref().unwrap_or_default();
+
+        match (&message.command, channel.map(String::as_str)) {
+            (Command::Join, Some(channel)) => {
+                if own {
+                    let kind = self
+                        .channels
+                        .entry(channel_key.to_string())
+                        .or_insert(Channel::new(channel))
+                        .kind;
+                    // Servers only send the modes and creation time when asked
+                    if kind != ChannelType::Modeless {
+                        let params = vec![Param::Channel(channel.to_string())];
+                        let _ = self.send_message(IrcMessage::new(None, Command::Mode, params));
+                    }
+                    // and NAMES leaves out hostmasks and accounts
+                    if !self
+                        .who_queue
+                        .iter()
+                        .any(|queued| queued.eq_ignore_ascii_case(channel))
+                    {
+                        self.who_queue.push_back(channel.to_string());
+                    }
+                }
+                let Some(joined) = self.channels.get_mut(channel_key) else {
+                    return;
+                };
+                joined.users.entry(key.clone()).or_default();
+
+                let record = self.users.entry(key).or_insert_with(|| User::new(nick));
+                record.update(user.as_ref(), host.as_ref(), None);
+                // extended-join adds the account, `*` for none, and realname
+                if let (Some(account), Some(realname)) = (args.get(1), args.get(2)) {
+                    record.account = (account != "*").then(|| account.clone());
+                    record.realname = Some(realname.clone());
+                }
+
+                if own {
+                    self.notify_plugins(|plugin, server| plugin.on_join(server, channel));
+                }
+            }
+            (Command::Part, Some(channel)) if own => {
+                self.channels.remove(channel_key);
+                self.forget_strangers();
+                self.notify_plugins(|plugin, server| plugin.on_part(server, channel));
+            }
+            (Command::Part, Some(_)) => {
+                if let Some(channel) = self.channels.get_mut(channel_key) {
+                    channel.users.remove(&key);
+                }
+                self.forget_strangers();
+            }
+            (Command::Kick, Some(channel)) => {
+                let Some(kicked) = args.get(1) else {
+                    return;
+                };
+                if self.is_me(kicked) {
+                    self.channels.remove(channel_key);
+                    self.forget_strangers();
+                    self.notify_plugins(|plugin, server| plugin.on_part(server, channel));
+                    self.rejoin_later(channel);
+                } else {
+                    if let Some(channel) = self.channels.get_mut(channel_key) {
+                        channel.users.remove(&state::casefold(kicked));
+                    }
+                    self.forget_strangers();
+                }
+            }
+            (Command::Nick, _) => {
+                let Some(Param::Nick(new_nick)) = message.params.first() else {
+                    return;
+                };
+                if own {
+                    self.nick = new_nick.to_string();
+                }
+                let new_key = state::casefold(new_nick);
+                if let Some(mut record) = self.users.remove(&key) {
+                    record.nick = new_nick.to_string();
+                    self.users.insert(new_key.clone(), record);
+                }
+                for channel in self.channels.values_mut() {
+                    if let Some(status) = channel.users.remove(&key) {
+                        channel.users.insert(new_key.clone(), status);
+                    }
+                }
+            }
+            (Command::Quit, _) => {
+                for channel in self.channels.values_mut() {
+                    channel.users.remove(&key);
+                }
+                self.users.remove(&key);
+            }
+            _ => (),
+        }
+    }
+
+    // Drops the records of users we no longer share a channel with
+    fn forget_strangers(&mut self) {
+        let channels = &self.channels;
+        self.users.retain(|nick, _| {
+            channels
+                .values()
+                .any(|channel| channel.users.contains_key(nick))
+        });
+    }
+
+    // Hostmasks, accounts, away status and realnames of the users we know
+    fn track_users(&mut self, message: &IrcMessage) {
+        let args = message.args();
+        if message.command == Command::Numeric(301) {
+            if let (Some(nick), Some(reason)) = (args.get(1), args.get(2))
+                && let Some(record) = self.users.get_mut(&state::casefold(nick))
+            {
+                record.away = Some(reason.clone());
+            }
+            return;
+        }
+
+        let Some(Prefix::User { nick, user, host }) = &message.prefix else {
+            return;
+        };
+        let Some(record) = self.users.get_mut(&state::casefold(nick)) else {
+            return;
+        };
+        record.update(user.as_ref(), host.as_ref(), message.tag("account"));
+
+        match &message.command {
+            Command::Away => record.away = args.first().cloned(),
+            Command::Unknown(command) => match command.as_str() {
+                "ACCOUNT" => record.account = args.first().filter(|a| *a != "*").cloned(),
+                "CHGHOST" => {
+                    if let (Some(user), Some(host)) = (args.first(), args.get(1)) {
+                        record.user = Some(user.clone());
+                        record.host = Some(host.clone());
+                    }
+                }
+                "SETNAME" => record.realname = args.first().cloned(),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    // Against the nick the server registered us with, which `001` tells
+    fn is_me(&self, nick: &str) -> bool {
+        nick.eq_ignore_ascii_case(&self.nick)
+    }
+
+    // Going by the server's `CHANTYPES`, so `&local` or `!safe` channels count too
+    fn is_channel(&self, name: &str) -> bool {
+        name.starts_with(|prefix| state::chantypes(&self.isupport).contains(prefix))
+    }
+
+    fn rejoin_later(&self, channel: &str) {
+        let Some(delay) = self.config.rejoin_on_kick else {
+            return;
+        };
+        let key = self
+            .config
+            .keys
+            .get(&state::casefold(channel))
+            .map(|key| key.0.clone());
+        let channel = channel.to_string();
+
+        debug!(%channel, ?delay, "kicked, rejoining");
+        self.schedule_once(delay, move |server| {
+            if server.join(&channel, key.as_deref()).is_err() {
+                warn!(%channel, "couldn't rejoin, the connection is gone");
+            }
+        });
+    }
+
+    // Channel topics and modes, capabilities and ISUPPORT tokens
+    fn track_server_state(&mut self, message: &IrcMessage) {
+        let args = message.args();
+        let by = match &message.prefix {
+            Some(Prefix::User { nick, .. }) => Some(nick.as_str()),
+            Some(Prefix::Server(server)) => Some(server.as_str()),
+            None => None,
+        };
+        // Numerics name the channel after our nick, commands first
+        let channel = match &message.command {
+            Command::Numeric(_) => args.get(1),
+            _ => args.first(),
+        };
+        let channel = channel.and_then(|name| self.channels.get_mut(&state::casefold(name)));
+
+        match (&message.command, channel) {
+            (Command::Numeric(5), _) => state::apply_isupport(&mut self.isupport, &args),
+            (Command::Numeric(331), Some(channel)) => {
+                channel.topic = None;
+                channel.topic_set_by = None;
+                channel.topic_set_at = None;
+            }
+            (Command::Numeric(332), Some(channel)) => channel.topic = args.get(2).cloned(),
+            (Command::Numeric(333), Some(channel)) => {
+                channel.topic_set_by = args.get(2).cloned();
+                channel.topic_set_at = args.get(3).and_then(|at| at.parse().ok());
+            }
+            (Command::Topic, Some(channel)) => {
+                channel.topic = args.get(1).filter(|topic| !topic.is_empty()).cloned();
+                channel.topic_set_by = by.map(str::to_string);
+                channel.topic_set_at = seconds_since_epoch();
+            }
+            (Command::Numeric(324), Some(channel)) => {
+                // The full set, apart from lists and statuses
+                channel.modes.clear();
+                if let Some(modes) = args.get(2) {
+                    let types = ModeTypes::new(&self.isupport);
+                    channel.apply_modes(modes, &args[3..], &types, None, None);
+                }
+            }
+            (Command::Numeric(329), Some(channel)) => {
+                channel.created = args.get(2).and_then(|at| at.parse().ok());
+            }
+            (Command::Mode, Some(channel)) => {
+                if let Some(modes) = args.get(1) {
+                    let types = ModeTypes::new(&self.isupport);
+                    channel.apply_modes(modes, &args[2..], &types, by, seconds_since_epoch());
+                }
+            }
+            (Command::Numeric(code @ (346 | 348 | 367)), Some(channel)) => {
+                let mode = list_mode(*code);
+                let fresh = self.listing.insert((channel.name.clone(), mode));
+                let Some(list) = channel.list_mut(mode) else {
+                    return;
+                };
+                // A fresh listing replaces what we had
+                if fresh {
+                    list.clear();
+                }
+                if let Some(mask) = args.get(2) {
+                    list.retain(|entry| entry.mask != *mask);
+                    list.push(MaskEntry {
+                        mask: mask.clone(),
+                        set_by: args.get(3).cloned(),
+                        set_at: args.get(4).and_then(|at| at.parse().ok()),
+                    });
+                }
+            }
+            (Command::Numeric(code @ (347 | 349 | 368)), channel) => {
+                let Some(name) = args.get(1) else {
+                    return;
+                };
+                let mode = list_mode(*code);
+                // An empty listing has no entries to clear the list with
+                if !self.listing.remove(&(name.clone(), mode))
+                    && let Some(list) = channel.and_then(|channel| channel.list_mut(mode))
+                {
+                    list.clear();
+                }
+            }
+            (Command::Unknown(command), _) if command == "CAP" => {
+                state::apply_cap(&mut self.available_caps, &mut self.capabilities, &args)
+            }
+            _ => (),
+        }
+    }
+
+    fn changes_state(message: &IrcMessage) -> bool {
+        match &message.command {
+            Command::Join
+            | Command::Part
+            | Command::Kick
+            | Command::Nick
+            | Command::Quit
+            | Command::Topic
+            | Command::Mode
+            | Command::Away
+            | Command::Numeric(
+                1 | 5 | 301 | 324 | 329 | 331..=333 | 346..=349 | 352 | 353 | 354 | 367 | 368,
+            ) => true,
+            Command::Unknown(command) => {
+                matches!(command.as_str(), "CAP" | "ACCOUNT" | "CHGHOST" | "SETNAME")
+            }
+            _ => message.tag("account").is_some(),
+        }
+    }
+
+    // Events nobody is reading are dropped rather than piling up
+    fn emit(&self, event: Event) {
+        if let Some(network_events) = &self.network_events {
+            let _ = network_events.try_send(NetworkEvent {
+                network: self.network.clone(),
+                event: event.clone(),
+            });
+        }
+        if let Some(events) = &self.events {
+            let _ = events.try_send(event);
+        }
+    }
+
+    // Replaces the snapshot `Client::state` hands out
+    fn publish_state(&self) {
+        let snapshot = Arc::new(self.snapshot());
+        match self.state.write() {
+            Ok(mut state) => *state = snapshot,
+            Err(poisoned) => *poisoned.into_inner() = snapshot,
+        }
+    }
+
+    // This is a 353 message we need to parse
+    fn parse_users(&mut self, params: &[Param]) {
+        // 2nd param is the channel name, 3rd and onwards are the users
+        let Some(channel_name) = params.get(2) else {
+            return;
+        };
+        let types = ModeTypes::new(&self.isupport);
+        // `NAMES` for channels we aren't in is for whoever asked for it
+        let Some(channel) = self
+            .channels
+            .get_mut(&state::casefold(&channel_name.to_string()))
+        else {
+            return;
+        };
+        for param in &params[3..] {
+            // The first name arrives as the start of the trailing parameter
+            if let Param::Unknown(entry) | Param::Message(entry) = param
+                && !entry.is_empty()
+            {
+                let (user, statuses) = User::from_names(entry, &types);
+                let key = state::casefold(&user.nick);
+                channel.users.insert(key.clone(), statuses);
+                let record = self
+                    .users
+                    .entry(key)
+                    .or_insert_with(|| User::new(&user.nick));
+                record.update(user.user.as_ref(), user.host.as_ref(), None);
+            }
+        }
+    }
+
+    // One channel at a time, so joining many big channels doesn't bury us
+    // in replies; an unanswered `WHO` gives up after the request timeout
+    fn request_who(&mut self) {
+        if !self.registered
+            || self
+                .who_pending
+                .as_ref()
+                .is_some_and(|(_, sent)| sent.elapsed() < self.config.request_timeout)
+        {
+            return;
+        }
+        self.who_pending = None;
+
+        while let Some(channel) = self.who_queue.pop_front() {
+            // Left again before its turn came
+            if !self.channels.contains_key(&state::casefold(&channel)) {
+                continue;
+            }
+            let mut params = vec![Param::Channel(channel.clone())];
+            if self.isupport.contains_key("WHOX") {
+                params.push(Param::Unknown(format!("%tcuhnfar,{WHOX_TOKEN}")));
+            }
+            let message = IrcMessage::new(None, Command::Unknown("WHO".to_string()), params);
+            if self.send_message(message).is_ok() {
+                self.who_pending = Some((channel, Instant::now()));
+            }
+            return;
+        }
+    }
+
+    // Watch changes, then the MONITOR list once the connection is ready and
+    // the ISUPPORT tokens are in, then ISON for whatever it doesn't cover
+    fn update_presence(&mut self, ready: bool) {
+        let monitor = self
+            .isupport
+            .get("MONITOR")
+            .map(|limit| limit.parse::<usize>().ok());
+        let mut lines = Vec::new();
+
+        for watch in self.watch_rcv.try_iter().collect::<Vec<_>>() {
+            match watch {
+                Watch::Add(nick) => {
+                    if self.presence.watch(&nick)
+                        && self.presence.synced()
+                        && let Some(limit) = monitor
+                    {
+                        lines.extend(self.presence.sync(limit));
+                    }
+                }
+                Watch::Remove(nick) => {
+                    if self.presence.unwatch(&nick) {
+                        lines.push(format!("MONITOR - {nick}"));
+                    }
+                    self.publish_state();
+                }
+            }
+        }
+
+        if ready && self.registered {
+            if !self.presence.synced() {
+                // Without MONITOR there is no room on its list, so all are polled
+                lines.extend(self.presence.sync(monitor.unwrap_or(Some(0))));
+            }
+            lines.extend(
+                self.presence
+                    .poll(Instant::now(), self.config.ison_interval),
+            );
+        }
+
+        // The nicks were checked when they were watched, so these parse
+        for message in lines.iter().filter_map(|line| line.parse().ok()) {
+            let _ = self.send_message(message);
+        }
+    }
+
+    fn track_presence(&mut self, message: &IrcMessage) {
+        let args = message.args();
+        let changes = match (&message.command, args.get(1)) {
+            (Command::Numeric(303), reply) => {
+                self.presence.apply_ison(reply.map_or("", String::as_str))
+            }
+            (Command::Numeric(730), Some(targets)) => self.presence.apply_monitor(targets, true),
+            (Command::Numeric(731), Some(targets)) => self.presence.apply_monitor(targets, false),
+            (Command::Numeric(734), _) => {
+                if let Some(refused) = args.get(2) {
+                    debug!(%refused, "MONITOR list is full, polling instead");
+                    self.presence.refused(refused);
+                }
+                return;
+            }
+            _ => return,
+        };
+        if changes.is_empty() {
+            return;
+        }
+
+        self.publish_state();
+        for (nick, online) in changes {
+            self.emit(Event::PresenceChanged {
+                nick: nick.clone(),
+                online,
+            });
+            self.notify_plugins(|plugin, server| plugin.on_presence(server, &nick, online));
+        }
+    }
+
+    // Fills the user table from `352` replies, or the `354` ones answering
+    // our WHOX query, whose fields come in the order `%tcuhnfar` asks for
+    fn parse_who(&mut self, message: &IrcMessage) {
+        let args = message.args();
+        let arg = |index: usize| args.get(index).cloned();
+        let (channel, user, host, nick, flags, account, realname) = match message.command {
+            Command::Numeric(315) => {
+                if self.who_pending.as_ref().is_some_and(|(channel, _)| {
+                    args.get(1)
+                        .is_some_and(|name| name.eq_ignore_ascii_case(channel))
+                }) {
+                    self.who_pending = None;
+                }
+                return;
+            }
+            Command::Numeric(352) => {
+                // The last argument is "<hops> <realname>"
+                let realname = arg(7).map(|last| match last.split_once(' ') {
+                    Some((_, realname)) => realname.to_string(),
+                    None => String::new(),
+                });
+                (arg(1), arg(2), arg(3), arg(5), arg(6), None, realname)
+            }
+            _ if arg(1).as_deref() == Some(WHOX_TOKEN) => {
+                // An account of `0` means none
+                let account = arg(7).map(|account| (account != "0").then_some(account));
+                (arg(2), arg(3), arg(4), arg(5), arg(6), account, arg(8))
+            }
+            _ => return,
+        };
+        let Some(nick) = nick else {
+            return;
+        };
+
+        // Replies for channels we aren't in, or for a nick mask, only update
+        // users we already know
+        let types = ModeTypes::new(&self.isupport);
+        let key = state::casefold(&nick);
+        let member = channel
+            .and_then(|channel| self.channels.get_mut(&state::casefold(&channel)))
+            .map(|channel| channel.users.entry(key.clone()).or_default());
+        match member {
+            // After `H` or `G` come their statuses in the channel, among other flags
+            Some(statuses) => {
+                let flags = flags.as_deref().unwrap_or_default();
+                for prefix in flags.chars().skip(1) {
+                    if types.prefixes().any(|status| status == prefix) {
+                        statuses.set(prefix, true, &types);
+                    }
+                }
+            }
+            None if !self.users.contains_key(&key) => return,
+            None => (),
+        }
+
+        let record = self.users.entry(key).or_insert_with(|| User::new(&nick));
+        record.update(user.as_ref(), host.as_ref(), None);
+        if let Some(account) = account {
+            record.account = account;
+        }
+        if realname.is_some() {
+            record.realname = realname;
+        }
+        // `G`one or `H`ere; the away message itself needs a WHOIS
+        match flags.as_deref().and_then(|flags| flags.chars().next()) {
+            Some('G') if record.away.is_none() => record.away = Some(String::new()),
+            Some('H') => record.away = None,
+            _ => (),
+        }
+    }
+
+    fn ping_response(
+        connection: &mut dyn IrcConnection,
+        message: &IrcMessage,
+    ) -> ConnectionResult<()> {
+        let msg = message.params.iter().find_map(|param| {
+            if let Param::Message(msg) = param {
+                Some(msg)
+            } else {
+                None
+            }
+        });
+
+        match msg {
+            Some(msg) => connection.send_message(&format!("PONG :{}", msg)),
+            None => Ok(()),
+        }
+    }
+
+    fn version_response(connection: &mut dyn IrcConnection, message: &str) -> ConnectionResult<()> {
+        connection.send_message(&format!("NOTICE :{} PRIVMSG :\u{1}VERSION 1\u{1}", message))
+    }
+}
+
+#[cfg(test)]
+impl Server {
+    // Lets tests see what would have been queued for the connection
+    pub(crate) fn capture_outgoing(&mut self) -> Receiver<IrcMessage> {
+        let (sender, receiver) = mpsc::channel();
+        self.sender = Some(sender);
+        receiver
+    }
+}
+
+// Tells our WHOX replies apart from those to `Client::who`
+const WHOX_TOKEN: &str = "77";
+
+fn seconds_since_epoch() -> Option<u64> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    Some(since_epoch.as_secs())
+}
+
+// The list mode a ban, exception or invite exception listing numeric is about
+fn list_mode(code: u16) -> char {
+    match code {
+        346 | 347 => 'I',
+        348 | 349 => 'e',
+        _ => 'b',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MockIrcConnection;
+    use crate::message::{Command, IrcMessage, Param};
+    use std::time::Duration;
+
+    // A connection that reads `incoming` in turn, `None` being a read
+    // timeout, then closes. The log has what was sent, and what was read
+    // behind `<- `.
+    fn scripted<L: Into<Option<&'static str>>>(
+        incoming: impl IntoIterator<Item = L>,
+    ) -> (MockIrcConnection, Arc<Mutex<Vec<String>>>) {
+        let mut incoming: VecDeque<Option<&str>> = incoming.into_iter().map(Into::into).collect();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sent = log.clone();
+        let read = log.clone();
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().returning(|_| Ok(()));
+        mock_conn.expect_send_message().returning(move |message| {
+            sent.lock().unwrap().push(message.to_string());
+            Ok(())
+        });
+        mock_conn.expect_read().returning(move || {
+            let line = incoming
+                .pop_front()
+                .ok_or(crate::connection::error::Error::ConnectionClosed)?;
+            read.lock()
+                .unwrap()
+                .extend(line.map(|line| format!("<- {line}")));
+            Ok(line.map(|line| line.parse().unwrap()))
+        });
+        (mock_conn, log)
+    }
+
+    #[test]
+    fn test_ping_response() {
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn
+            .expect_send_message()
+            .with(mockall::predicate::eq("PONG :12345"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let message = IrcMessage::new(
+            None,
+            Command::Ping,
+            vec![Param::Message("12345".to_string())],
+        );
+
+        Server::ping_response(&mut mock_conn, &message).unwrap();
+    }
+
+    #[test]
+    fn test_version_response() {
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn
+            .expect_send_message()
+            .with(mockall::predicate::eq(
+                "NOTICE :test_user PRIVMSG :\u{1}VERSION 1\u{1}",
+            ))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let message = "test_user";
+
+        Server::version_response(&mut mock_conn, message).unwrap();
+    }
+
+    #[test]
+    fn test_parse_users() {
+        let config = Config::new("localhost").nick("test").user("test");
+        let mock_conn = MockIrcConnection::new();
+        let mut server = Server::new(config, Box::new(mock_conn));
+        server
+            .channels
+            .insert("#test".to_string(), Channel::new("#test"));
+
+        let names = |channel: &str| {
+            vec![
+                Param::Unknown("".to_string()),
+                Param::Unknown("".to_string()),
+                Param::Channel(channel.to_string()),
+                Param::Unknown("user1".to_string()),
+                Param::Unknown("user2".to_string()),
+            ]
         };
 
+        server.parse_users(&names("#test"));
+        // Not ours to track, nor a reason to panic
+        server.parse_users(&names("#elsewhere"));
+        server.parse_users(&names("#test")[..2]);
+
+        let channel = server.channels.get("#test").unwrap();
+        assert!(channel.users.contains_key("user1"));
+        assert!(channel.users.contains_key("user2"));
+        assert!(!server.channels.contains_key("#elsewhere"));
+    }
+
+    #[test]
+    fn test_connect_loop() {
+        let config = Config::new("localhost").nick("test").user("test");
+
         let mut mock_conn = MockIrcConnection::new();
         mock_conn
             .expect_connect()
@@ -299,11 +1808,11 @@ mod tests {
             .returning(|_| Ok(()));
 
         mock_conn.expect_read().times(1).returning(|| {
-            Ok(Some(IrcMessage {
-                prefix: None,
-                command: Command::Ping,
-                params: vec![Param::Message("12345".to_string())],
-            }))
+            Ok(Some(IrcMessage::new(
+                None,
+                Command::Ping,
+                vec![Param::Message("12345".to_string())],
+            )))
         });
 
         mock_conn
@@ -326,4 +1835,1158 @@ mod tests {
         // Ensure the client thread is still running
         assert!(client.thread.is_some());
     }
+
+    #[test]
+    fn test_connect_failure_is_returned() {
+        let config = Config::new("localhost");
+
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().times(1).returning(|_| {
+            Err(crate::connection::error::Error::Io(std::io::Error::from(
+                std::io::ErrorKind::ConnectionRefused,
+            )))
+        });
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+
+        assert!(matches!(
+            client.join(),
+            Err(crate::Error::Connection(
+                crate::connection::error::Error::Io(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_closed_after_quit_is_clean() {
+        let config = Config::new("localhost");
+
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().times(1).returning(|_| Ok(()));
+        // The peer only hangs up once it has seen our QUIT
+        let quit_sent = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let quit_flag = quit_sent.clone();
+        mock_conn.expect_send_message().returning(move |message| {
+            if message.starts_with("QUIT") {
+                quit_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+            Ok(())
+        });
+        let mut listed = false;
+        mock_conn.expect_read().returning(move || {
+            if quit_sent.load(std::sync::atomic::Ordering::SeqCst) {
+                Err(crate::connection::error::Error::ConnectionClosed)
+            } else if !std::mem::replace(&mut listed, true) {
+                Ok(Some(":irc.example.com CAP * LS :".parse().unwrap()))
+            } else {
+                Ok(None)
+            }
+        });
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        client.channels();
+
+        assert!(client.shutdown().is_ok());
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingPlugin(Arc<Mutex<Vec<String>>>);
+
+    impl IrcPlugin for RecordingPlugin {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            Flow::Continue
+        }
+
+        fn on_register(&mut self, server: &Server) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("register {}", server.nick));
+        }
+
+        fn on_join(&mut self, _server: &Server, channel: &str) {
+            self.0.lock().unwrap().push(format!("join {}", channel));
+        }
+
+        fn on_part(&mut self, _server: &Server, channel: &str) {
+            self.0.lock().unwrap().push(format!("part {}", channel));
+        }
+
+        fn on_disconnect(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("disconnect".to_string());
+        }
+
+        fn on_shutdown(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("shutdown".to_string());
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_hooks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config = Config::new("localhost")
+            .nick("rusty")
+            .register_plugin(RecordingPlugin(events.clone()));
+
+        let incoming = [
+            ":irc.example.com 001 rusty :Welcome",
+            ":rusty!rusty@host JOIN #test",
+            ":someone!user@host JOIN #test",
+            ":rusty!rusty@host JOIN #other",
+            ":rusty!rusty@host PART #test",
+            ":op!op@host KICK #other rusty :bye",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        assert!(client.join().is_err());
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "register rusty",
+                "join #test",
+                "join #other",
+                "part #test",
+                "part #other",
+                "disconnect",
+                "shutdown",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_own_nick_from_welcome() {
+        let config = Config::new("localhost").nick("rustacean_bot");
+        // The server cut our nick down to its NICKLEN
+        let incoming = [
+            ":irc.example.com 001 rustacean :Welcome",
+            ":Rustacean!r@host JOIN #test",
+            ":rustacean!r@host JOIN #other",
+            ":op!op@host KICK #other RUSTACEAN :bye",
+        ];
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        assert!(client.join().is_err());
+        let state = shared.read().unwrap().clone();
+
+        assert_eq!(state.nick, "rustacean");
+        assert!(state.channel("#test").is_some());
+        assert!(state.channel("#other").is_none());
+    }
+
+    #[derive(Debug)]
+    struct TimerPlugin;
+
+    impl IrcPlugin for TimerPlugin {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            Flow::Continue
+        }
+
+        fn on_register(&mut self, server: &Server) {
+            server.schedule_once(Duration::ZERO, |server| {
+                let msg = IrcMessage::builder()
+                    .command(Command::Quit)
+                    .param(Param::Message("timer fired".to_string()))
+                    .build()
+                    .unwrap();
+                server.send_message(msg).unwrap();
+            });
+            let never = server.schedule_once(Duration::ZERO, |_| panic!("cancelled"));
+            never.cancel();
+        }
+    }
+
+    #[test]
+    fn test_timers_run_from_loop() {
+        let config = Config::new("localhost").register_plugin(TimerPlugin);
+
+        let mut welcomed = false;
+        let quit_sent = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let quit_flag = quit_sent.clone();
+
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().returning(|_| Ok(()));
+        mock_conn.expect_send_message().returning(move |message| {
+            if message == "QUIT :timer fired" {
+                quit_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+            Ok(())
+        });
+        mock_conn.expect_read().returning(move || {
+            if quit_sent.load(std::sync::atomic::Ordering::SeqCst) {
+                Err(crate::connection::error::Error::ConnectionClosed)
+            } else if !welcomed {
+                welcomed = true;
+                Ok(Some(":irc.example.com 001 User :Welcome".parse().unwrap()))
+            } else {
+                Ok(None)
+            }
+        });
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        assert!(client.join().is_ok());
+    }
+
+    #[derive(Debug)]
+    struct PanickingPlugin(Arc<Mutex<usize>>);
+
+    impl IrcPlugin for PanickingPlugin {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            *self.0.lock().unwrap() += 1;
+            panic!("plugin bug");
+        }
+    }
+
+    #[test]
+    fn test_plugin_panics_are_contained() {
+        let calls = Arc::new(Mutex::new(0));
+        let config = Config::new("localhost")
+            .max_plugin_failures(2)
+            .register_plugin(PanickingPlugin(calls.clone()));
+
+        let incoming = ["PING :1", "PING :2", "PING :3"];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let failures: Vec<PluginFailure> = client.plugin_failures().iter().collect();
+        let events: Vec<Event> = client.events().try_iter().collect();
+
+        assert_eq!(*calls.lock().unwrap(), 2);
+        assert!(events.contains(&Event::PluginFailed {
+            plugin: failures[1].plugin.clone(),
+            message: "plugin bug".to_string(),
+            failures: 2,
+            disabled: true,
+        }));
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].message, "plugin bug");
+        assert!(!failures[0].disabled);
+        assert!(failures[1].disabled);
+        // The worker outlived the panics and stopped for its own reasons
+        assert!(matches!(
+            client.join(),
+            Err(crate::Error::Connection(
+                crate::connection::error::Error::ConnectionClosed
+            ))
+        ));
+    }
+
+    #[derive(Debug)]
+    struct Fragile(Arc<Mutex<Vec<&'static str>>>);
+
+    impl IrcPlugin for Fragile {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            self.0.lock().unwrap().push("message");
+            panic!("plugin bug");
+        }
+
+        fn on_load(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("load");
+        }
+
+        fn on_unload(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("unload");
+        }
+    }
+
+    #[test]
+    fn test_disabled_plugins_are_unloaded() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let config = Config::new("localhost").max_plugin_failures(1);
+        let mut server = Server::new(config, Box::new(MockIrcConnection::new()));
+        server.register_plugin(Fragile(log.clone())).unwrap();
+        server.apply_plugin_commands();
+
+        let ping: IrcMessage = "PING :1".parse().unwrap();
+        server.dispatch_message(&ping);
+        server.dispatch_message(&ping);
+        server.enable_plugin("Fragile").unwrap();
+        server.apply_plugin_commands();
+        server.disable_plugin("Fragile").unwrap();
+        server.apply_plugin_commands();
+        server.remove_plugin("Fragile").unwrap();
+        server.apply_plugin_commands();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["load", "message", "unload", "load", "unload"]
+        );
+    }
+
+    #[derive(Debug)]
+    struct Gatekeeper;
+
+    impl IrcPlugin for Gatekeeper {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            Flow::Consume
+        }
+
+        fn priority(&self) -> i32 {
+            10
+        }
+
+        fn filter(&self) -> Option<crate::PluginFilter> {
+            Some(crate::PluginFilter::new().channel("#secret"))
+        }
+    }
+
+    #[derive(Debug)]
+    struct Witness(Arc<Mutex<Vec<String>>>);
+
+    impl IrcPlugin for Witness {
+        fn message(&mut self, _server: &Server, message: &IrcMessage) -> Flow {
+            self.0.lock().unwrap().push(message.to_string());
+            Flow::Continue
+        }
+    }
+
+    #[test]
+    fn test_priority_filter_and_consumption() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        // Registered first but runs last because of its lower priority
+        let config = Config::new("localhost")
+            .register_plugin(Witness(seen.clone()))
+            .register_plugin(Gatekeeper);
+
+        let incoming = [
+            ":nick!u@h PRIVMSG #secret :hidden",
+            ":nick!u@h PRIVMSG #public :visible",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let (_, receiver) = client.channels();
+        let received: Vec<String> = receiver.iter().map(|msg| msg.to_string()).collect();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![":nick!u@h PRIVMSG #public :visible"]
+        );
+        assert_eq!(received, vec![":nick!u@h PRIVMSG #public :visible"]);
+    }
+
+    #[derive(Debug)]
+    struct Quotes(Arc<Mutex<Vec<String>>>);
+
+    impl IrcPlugin for Quotes {
+        fn message(&mut self, _server: &Server, message: &IrcMessage) -> Flow {
+            if let Some(text) = message.get_message() {
+                self.0.lock().unwrap().push(text.clone());
+            }
+            Flow::Continue
+        }
+
+        fn on_load(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("load".to_string());
+        }
+
+        fn on_unload(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("unload".to_string());
+        }
+
+        fn on_register(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("register".to_string());
+        }
+    }
+
+    #[derive(Debug)]
+    struct Loader(Arc<Mutex<Vec<String>>>, Option<PluginHandle>);
+
+    impl IrcPlugin for Loader {
+        fn message(&mut self, server: &Server, message: &IrcMessage) -> Flow {
+            let text = message.get_message().map(String::as_str);
+            if text == Some("!load") {
+                self.1 = Some(server.register_plugin(Quotes(self.0.clone())).unwrap());
+            }
+            if let Some(quotes) = &self.1 {
+                match text {
+                    Some("!disable") => quotes.disable().unwrap(),
+                    Some("!enable") => quotes.enable().unwrap(),
+                    Some("!unload") => quotes.remove().unwrap(),
+                    _ => (),
+                }
+            }
+            Flow::Continue
+        }
+    }
+
+    #[test]
+    fn test_runtime_plugin_registration() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config = Config::new("localhost").register_plugin(Loader(events.clone(), None));
+
+        let incoming = [
+            ":irc.example.com 001 User :Welcome",
+            ":admin!u@h PRIVMSG #c :!load",
+            ":admin!u@h PRIVMSG #c :hello",
+            ":admin!u@h PRIVMSG #c :!disable",
+            ":admin!u@h PRIVMSG #c :quiet",
+            ":admin!u@h PRIVMSG #c :!enable",
+            ":admin!u@h PRIVMSG #c :!unload",
+            ":admin!u@h PRIVMSG #c :after",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        assert!(client.join().is_err());
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "load", "register", "hello", "!disable", "unload", "load", "register", "!unload",
+                "unload"
+            ]
+        );
+    }
+
+    // Records being dropped, as the code of an unloaded library would be
+    #[derive(Debug)]
+    struct DropFlag(Arc<Mutex<Vec<String>>>, &'static str);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push(format!("dropped {}", self.1));
+        }
+    }
+
+    #[derive(Debug)]
+    struct Scheduling(Arc<Mutex<Vec<String>>>);
+
+    impl IrcPlugin for Scheduling {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            Flow::Continue
+        }
+
+        fn on_load(&mut self, server: &Server) {
+            let timer = DropFlag(self.0.clone(), "timer");
+            server.schedule_once(Duration::from_secs(3600), move |_| {
+                let _ = &timer;
+            });
+            server.register_plugin(Quotes(self.0.clone())).unwrap();
+        }
+
+        fn on_unload(&mut self, _server: &Server) {
+            self.0.lock().unwrap().push("unload scheduling".to_string());
+        }
+    }
+
+    #[test]
+    fn test_removed_plugins_take_their_timers_and_plugins() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut server = Server::new(Config::new("localhost"), Box::new(MockIrcConnection::new()));
+        let unowned = DropFlag(events.clone(), "unowned timer");
+        server.schedule_once(Duration::from_secs(3600), move |_| {
+            let _ = &unowned;
+        });
+
+        server.register_plugin(Scheduling(events.clone())).unwrap();
+        server.apply_plugin_commands();
+        // The plugin it registered arrives on the next tick
+        server.apply_plugin_commands();
+        assert_eq!(server.plugins.len(), 2);
+        events.lock().unwrap().clear();
+
+        server.remove_plugin("Scheduling").unwrap();
+        server.apply_plugin_commands();
+        assert!(server.plugins.is_empty());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["unload scheduling", "unload", "dropped timer"]
+        );
+    }
+
+    #[test]
+    fn test_failed_replacement_is_a_load_error() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config = Config::new("localhost").register_plugin(Quotes(events.clone()));
+        let mut server = Server::new(config, Box::new(MockIrcConnection::new()));
+        let (sender, receiver) = mpsc::sync_channel(10);
+        server.events = Some(sender);
+
+        server
+            .replace_plugin("Quotes", || Err(crate::Error::WorkerPanicked))
+            .unwrap();
+        server.apply_plugin_commands();
+
+        assert!(server.plugins.is_empty());
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(Event::PluginLoadFailed { plugin, .. }) if plugin == "Quotes"
+        ));
+    }
+
+    #[test]
+    fn test_requests_are_routed_and_expire() {
+        let config = Config::new("localhost").request_timeout(Duration::ZERO);
+        let mut server = Server::new(config, Box::new(MockIrcConnection::new()));
+        let outgoing = server.capture_outgoing();
+
+        let (query, whois) = super::super::requests::whois("alice");
+        server.requests.send(query).unwrap();
+        let (query, names) =
+            super::super::requests::names("#chan", ModeTypes::new(&HashMap::new()));
+        server.requests.send(query).unwrap();
+        server.accept_requests();
+
+        for line in [
+            ":srv 311 me alice ali host * :Alice",
+            ":srv 318 me alice :End of /WHOIS list.",
+        ] {
+            let message: IrcMessage = line.parse().unwrap();
+            let Command::Numeric(code) = message.command else {
+                unreachable!()
+            };
+            server.route_reply(code, &message);
+        }
+        server.expire_requests();
+
+        assert_eq!(
+            outgoing
+                .try_iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>(),
+            vec!["WHOIS alice", "NAMES #chan"]
+        );
+        assert_eq!(whois.wait().unwrap().realname, "Alice");
+        assert!(matches!(
+            names.wait(),
+            Err(crate::Error::Server(Error::Timeout(request))) if request == "NAMES #chan"
+        ));
+        assert!(server.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_labeled_requests() {
+        let mut server = Server::new(Config::new("localhost"), Box::new(MockIrcConnection::new()));
+        let outgoing = server.capture_outgoing();
+        server.capabilities.insert("labeled-response".to_string());
+        server
+            .channels
+            .insert("#auto".to_string(), Channel::new("#auto"));
+        server.who_pending = Some(("#auto".to_string(), Instant::now()));
+
+        let (query, alice) = super::super::requests::whois("alice");
+        server.requests.send(query).unwrap();
+        let (query, bob) = super::super::requests::whois("bob");
+        server.requests.send(query).unwrap();
+        let (query, nobody) = super::super::requests::who("nobody");
+        server.requests.send(query).unwrap();
+        server.accept_requests();
+
+        // Answered out of order, the replies only tell by their labels
+        for line in [
+            "@label=2 :srv BATCH +b labeled-response",
+            "@batch=b :srv 311 me bob bob host * :Bob",
+            "@batch=b :srv 318 me bob :End of /WHOIS list.",
+            ":srv BATCH -b",
+            "@label=1 :srv BATCH +a labeled-response",
+            "@batch=a :srv 311 me alice ali host * :Alice",
+            ":srv 352 me #auto x x.host srv mallory H :0 Our own WHO",
+            ":srv BATCH -a",
+            "@label=3 :srv ACK",
+        ] {
+            let message: IrcMessage = line.parse().unwrap();
+            server.track_batches(&message);
+            if let Command::Numeric(code) = message.command {
+                server.route_reply(code, &message);
+            }
+        }
+
+        assert_eq!(
+            outgoing
+                .try_iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "@label=1 WHOIS alice",
+                "@label=2 WHOIS bob",
+                "@label=3 WHO nobody"
+            ]
+        );
+        assert_eq!(bob.wait().unwrap().realname, "Bob");
+        // Ended with its batch, without the end numeric
+        assert_eq!(alice.wait().unwrap().realname, "Alice");
+        assert!(nobody.wait().unwrap().is_empty());
+        assert!(server.in_flight.is_empty());
+        assert!(server.batches.is_empty());
+    }
+
+    #[test]
+    fn test_state_snapshot() {
+        let config = Config::new("localhost").nick("rusty");
+
+        let incoming = [
+            ":irc.example.com CAP * LS :multi-prefix away-notify",
+            ":irc.example.com CAP rusty ACK :multi-prefix",
+            ":irc.example.com 001 rusty :Welcome",
+            ":irc.example.com 005 rusty CHANTYPES=#& NICKLEN=30 :are supported by this server",
+            ":rusty!rusty@host JOIN #test",
+            ":irc.example.com 332 rusty #test :Testing things",
+            ":irc.example.com 353 rusty = #test :@alice bob rusty",
+            ":carol!carol@host JOIN #test",
+            ":bob!bob@host NICK robert",
+            ":alice!alice@host QUIT :gone",
+            ":carol!carol@host TOPIC #test :Still testing",
+            ":rusty!rusty@host NICK rusty_",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        assert!(client.join().is_err());
+        let state = shared.read().unwrap().clone();
+
+        assert_eq!(state.nick, "rusty_");
+        assert!(state.registered);
+        assert!(state.has_capability("multi-prefix"));
+        assert!(!state.has_capability("away-notify"));
+        assert!(state.available_caps.contains_key("away-notify"));
+        assert_eq!(state.isupport("CHANTYPES"), Some("#&"));
+        assert_eq!(state.isupport("NICKLEN"), Some("30"));
+
+        let channel = state.channel("#test").unwrap();
+        assert_eq!(channel.topic.as_deref(), Some("Still testing"));
+        let mut users: Vec<_> = state.nicks().into_iter().collect();
+        users.sort();
+        assert_eq!(users, vec!["carol", "robert", "rusty_"]);
+    }
+
+    #[test]
+    fn test_channel_state() {
+        let config = Config::new("localhost").nick("rusty");
+
+        let incoming = [
+            Some(":irc.example.com 001 rusty :Welcome"),
+            Some(
+                ":irc.example.com 005 rusty CHANMODES=beI,k,l,imnpst PREFIX=(ov)@+ :are supported",
+            ),
+            Some(":rusty!rusty@host JOIN #test"),
+            Some(":irc.example.com 332 rusty #test :Testing things"),
+            Some(":irc.example.com 333 rusty #test alice!alice@host 1700000000"),
+            Some(":irc.example.com 353 rusty = #test :@alice bob rusty"),
+            Some(":irc.example.com 324 rusty #test +ntk secret"),
+            Some(":irc.example.com 329 rusty #test 1600000000"),
+            Some(":irc.example.com 367 rusty #test *!*@old alice 1650000000"),
+            Some(":irc.example.com 368 rusty #test :End of Channel Ban List"),
+            Some(":irc.example.com 367 rusty #test *!*@new alice 1660000000"),
+            Some(":irc.example.com 368 rusty #test :End of Channel Ban List"),
+            Some(":irc.example.com 349 rusty #test :End of Channel Exception List"),
+            Some(":alice!alice@host MODE #test +l-k+o 10 secret bob"),
+            Some(":bob!bob@host MODE #test +I *!*@friend"),
+            Some(":bob!bob@host TOPIC #test :Still testing"),
+            // Read timeouts, for registering to finish and the queued `MODE` to go out
+            None,
+            None,
+            None,
+            None,
+        ];
+
+        let (mock_conn, sent) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        assert!(client.join().is_err());
+        let state = shared.read().unwrap().clone();
+        let channel = state.channel("#test").unwrap();
+
+        assert!(sent.lock().unwrap().contains(&"MODE #test".to_string()));
+        assert_eq!(channel.topic.as_deref(), Some("Still testing"));
+        assert_eq!(channel.topic_set_by.as_deref(), Some("bob"));
+        assert!(channel.topic_set_at.is_some_and(|at| at > 1700000000));
+        assert_eq!(channel.created, Some(1600000000));
+        assert_eq!(
+            channel.modes,
+            HashMap::from([('n', None), ('t', None), ('l', Some("10".to_string()))])
+        );
+        assert!(channel.users["bob"].has('@'));
+        assert_eq!(
+            channel.bans,
+            vec![MaskEntry {
+                mask: "*!*@new".to_string(),
+                set_by: Some("alice".to_string()),
+                set_at: Some(1660000000),
+            }]
+        );
+        assert!(channel.excepts.is_empty());
+        assert_eq!(channel.invite_excepts[0].mask, "*!*@friend");
+        assert_eq!(channel.invite_excepts[0].set_by.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn test_user_table() {
+        let config = Config::new("localhost").nick("rusty");
+
+        let incoming = [
+            ":irc.example.com 001 rusty :Welcome",
+            ":rusty!rusty@host JOIN #a",
+            ":rusty!rusty@host JOIN #b",
+            ":irc.example.com 353 rusty = #a :@alice!al@host.a bob rusty",
+            ":irc.example.com 353 rusty = #b :alice rusty",
+            ":bob!bob@bob.host JOIN #b bob_account :Bob Builder",
+            "@account=alice_account :alice!al@host.a PRIVMSG #a :hi",
+            ":alice!al@host.a AWAY :lunch",
+            ":irc.example.com 301 rusty bob :brb",
+            ":bob!bob@bob.host CHGHOST builder new.host",
+            ":bob!builder@new.host ACCOUNT *",
+            ":alice!al@host.a PART #a",
+            ":bob!builder@new.host PART #a",
+            ":carol!carol@host JOIN #a",
+            ":carol!carol@host PART #a :bye",
+            ":bob!builder@new.host NICK robert",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        assert!(client.join().is_err());
+        let state = shared.read().unwrap().clone();
+
+        let mut nicks: Vec<_> = state.nicks().into_iter().collect();
+        nicks.sort();
+        assert_eq!(nicks, vec!["alice", "robert", "rusty"]);
+
+        let alice = state.user("alice").unwrap();
+        assert_eq!(alice.user.as_deref(), Some("al"));
+        assert_eq!(alice.host.as_deref(), Some("host.a"));
+        assert_eq!(alice.account.as_deref(), Some("alice_account"));
+        assert_eq!(alice.away.as_deref(), Some("lunch"));
+
+        let robert = state.user("robert").unwrap();
+        assert_eq!(robert.nick, "robert");
+        assert_eq!(robert.user.as_deref(), Some("builder"));
+        assert_eq!(robert.host.as_deref(), Some("new.host"));
+        assert_eq!(robert.realname.as_deref(), Some("Bob Builder"));
+        assert_eq!(robert.account, None);
+        assert_eq!(robert.away.as_deref(), Some("brb"));
+
+        let channel = state.channel("#b").unwrap();
+        assert!(channel.users.contains_key("robert"));
+        assert!(!state.channel("#a").unwrap().users.contains_key("robert"));
+    }
+
+    #[test]
+    fn test_names_in_any_case() {
+        let config = Config::new("localhost").nick("rusty");
+
+        let incoming = [
+            ":irc.example.com 001 rusty :Welcome",
+            ":rusty!rusty@host JOIN #Rust",
+            ":irc.example.com 353 rusty = #rust :@Alice Bob rusty",
+            ":irc.example.com 332 rusty #RUST :Crabs",
+            ":Carol!carol@host JOIN #rust",
+            ":alice!al@host MODE #Rust +o bob",
+            ":BOB!bob@host NICK Robert",
+            ":Alice!al@host KICK #rust CAROL :out",
+            ":ALICE!al@host QUIT :gone",
+            ":rusty!rusty@host JOIN #Other",
+            ":RUSTY!rusty@host PART #OTHER",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        assert!(client.join().is_err());
+        let state = shared.read().unwrap().clone();
+
+        assert_eq!(state.channels.keys().collect::<Vec<_>>(), vec!["#rust"]);
+        let channel = state.channel("#RUST").unwrap();
+        assert_eq!(channel.name, "#Rust");
+        assert_eq!(channel.topic.as_deref(), Some("Crabs"));
+        let mut members: Vec<_> = channel.users.keys().map(String::as_str).collect();
+        members.sort();
+        assert_eq!(members, vec!["robert", "rusty"]);
+        assert!(channel.users["robert"].has('@'));
+
+        let mut nicks: Vec<_> = state.nicks().into_iter().collect();
+        nicks.sort();
+        assert_eq!(nicks, vec!["Robert", "rusty"]);
+        assert_eq!(state.user("ROBERT").unwrap().nick, "Robert");
+    }
+
+    #[test]
+    fn test_who_on_join() {
+        let config = Config::new("localhost").nick("rusty");
+
+        let incoming = [
+            Some(":irc.example.com 001 rusty :Welcome"),
+            Some(":irc.example.com 005 rusty WHOX PREFIX=(ohv)@%+ :are supported"),
+            Some(":rusty!rusty@host JOIN #a"),
+            Some(":rusty!rusty@host JOIN #b"),
+            Some(":irc.example.com 353 rusty = #a :@alice rusty"),
+            None,
+            None,
+            None,
+            None,
+            Some(":irc.example.com 354 rusty 77 #a al host.a alice G@ alice_account :Alice"),
+            Some(":irc.example.com 354 rusty 77 #a rusty host rusty H 0 :Rusty"),
+            Some(":irc.example.com 354 rusty 12 #a x x.host mallory H mal :Someone else's"),
+            Some(":irc.example.com 315 rusty #a :End of /WHO list."),
+            None,
+            Some(":irc.example.com 352 rusty #b bob bob.host irc.example.com bob H%+ :0 Bob"),
+            Some(":irc.example.com 315 rusty #b :End of /WHO list."),
+        ];
+
+        let (mock_conn, log) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        assert!(client.join().is_err());
+        let state = shared.read().unwrap().clone();
+
+        let log = log.lock().unwrap();
+        let position = |line: &str| log.iter().position(|logged| logged.starts_with(line));
+        let end_of_a = position("<- :irc.example.com 315 rusty #a").unwrap();
+        assert!(position("WHO #a %tcuhnfar,77").unwrap() < end_of_a);
+        // The second channel waits for the first to be answered
+        assert!(position("WHO #b %tcuhnfar,77").unwrap() > end_of_a);
+
+        let alice = state.user("alice").unwrap();
+        assert_eq!(alice.user.as_deref(), Some("al"));
+        assert_eq!(alice.host.as_deref(), Some("host.a"));
+        assert_eq!(alice.account.as_deref(), Some("alice_account"));
+        assert_eq!(alice.realname.as_deref(), Some("Alice"));
+        assert_eq!(alice.away.as_deref(), Some(""));
+        assert_eq!(state.user("rusty").unwrap().account, None);
+        assert!(state.user("mallory").is_none());
+
+        let bob = state.user("bob").unwrap();
+        assert_eq!(bob.host.as_deref(), Some("bob.host"));
+        assert_eq!(bob.realname.as_deref(), Some("Bob"));
+        assert_eq!(state.channel("#b").unwrap().users["bob"].prefixes(), "%+");
+        assert!(state.channel("#a").unwrap().users["alice"].has('@'));
+    }
+
+    #[test]
+    fn test_channel_types() {
+        let config = Config::new("localhost").nick("rusty").channel("&local");
+
+        let incoming = [
+            ":irc.example.com 001 rusty :Welcome",
+            ":irc.example.com 005 rusty CHANTYPES=#&!+ :are supported by this server",
+            ":rusty!rusty@host JOIN ##rust",
+            ":rusty!rusty@host JOIN &local",
+            ":rusty!rusty@host JOIN +modeless",
+            ":rusty!rusty@host JOIN !ABCDEsafe",
+            ":irc.example.com 353 rusty = &local :@alice rusty",
+            ":bob!bob@host JOIN &local",
+            ":alice!alice@host KICK &local bob :bye",
+            ":op!op@host KICK !ABCDEsafe rusty :bye",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        assert!(client.join().is_err());
+        let state = shared.read().unwrap().clone();
+
+        let mut names: Vec<_> = state.channels.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["##rust", "&local", "+modeless"]);
+        for channel in state.channels.values() {
+            assert_eq!(channel.kind.prefix(), channel.name.chars().next().unwrap());
+        }
+        assert_eq!(state.channel("##rust").unwrap().kind, ChannelType::Network);
+        assert_eq!(
+            state.channel("+modeless").unwrap().kind,
+            ChannelType::Modeless
+        );
+
+        let local = state.channel("&local").unwrap();
+        assert_eq!(local.kind, ChannelType::Local);
+        let mut users: Vec<_> = local.users.keys().map(String::as_str).collect();
+        users.sort();
+        assert_eq!(users, vec!["alice", "rusty"]);
+    }
+
+    #[test]
+    fn test_events() {
+        let config = Config::new("localhost").nick("rusty");
+
+        let incoming = [
+            ":irc.example.com 001 rusty :Welcome",
+            ":rusty!rusty@host JOIN #test",
+            ":someone!user@host PRIVMSG #test :\u{1}ACTION waves\u{1}",
+            ":irc.example.com 372 rusty :- MOTD",
+        ];
+
+        let (mock_conn, _) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let mut events = Vec::new();
+        while let Ok(event) = client.events().recv() {
+            let done = event == Event::Disconnected;
+            events.push(event);
+            if done {
+                break;
+            }
+        }
+        assert!(client.join().is_err());
+
+        assert_eq!(events[0], Event::Connected);
+        assert_eq!(
+            events[1],
+            Event::Registered {
+                nick: "rusty".to_string()
+            }
+        );
+        assert!(
+            matches!(&events[2], Event::Raw(message) if message.command == Command::Numeric(1))
+        );
+        assert_eq!(
+            events[3],
+            Event::Joined {
+                channel: "#test".to_string(),
+                nick: "rusty".to_string()
+            }
+        );
+        assert_eq!(
+            events[4],
+            Event::Message {
+                from: "someone".to_string(),
+                target: "#test".to_string(),
+                text: "waves".to_string(),
+                is_action: true,
+            }
+        );
+        assert!(matches!(&events[5], Event::Raw(_)));
+        assert!(matches!(&events[6], Event::Error(_)));
+        assert_eq!(events[7], Event::Disconnected);
+    }
+
+    #[derive(Debug)]
+    struct PresencePlugin(Arc<Mutex<Vec<(String, bool)>>>);
+
+    impl IrcPlugin for PresencePlugin {
+        fn message(&mut self, _server: &Server, _message: &IrcMessage) -> Flow {
+            Flow::Continue
+        }
+
+        fn on_presence(&mut self, _server: &Server, nick: &str, online: bool) {
+            self.0.lock().unwrap().push((nick.to_string(), online));
+        }
+    }
+
+    #[test]
+    fn test_presence() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        // The MONITOR list only has room for alice, so bob is polled
+        let config = Config::new("localhost")
+            .nick("rusty")
+            .watch("alice")
+            .watch("bob")
+            .register_plugin(PresencePlugin(seen.clone()));
+
+        let incoming = [
+            Some(":irc.example.com 001 rusty :Welcome"),
+            Some(":irc.example.com 005 rusty MONITOR=1 :are supported"),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(":irc.example.com 730 rusty :alice!al@host.a"),
+            Some(":irc.example.com 303 rusty :"),
+            Some(":irc.example.com 731 rusty :alice"),
+            Some(":irc.example.com 730 rusty :carol!carol@host"),
+        ];
+
+        let (mock_conn, sent) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let shared = client.state.clone();
+        let mut events = Vec::new();
+        while let Ok(event) = client.events().recv() {
+            match event {
+                Event::PresenceChanged { nick, online } => events.push((nick, online)),
+                Event::Disconnected => break,
+                _ => (),
+            }
+        }
+        assert!(client.join().is_err());
+
+        let sent = sent.lock().unwrap();
+        assert!(sent.contains(&"MONITOR + alice".to_string()));
+        assert!(sent.contains(&"ISON bob".to_string()));
+
+        let expected = vec![
+            ("alice".to_string(), true),
+            ("bob".to_string(), false),
+            ("alice".to_string(), false),
+        ];
+        assert_eq!(events, expected);
+        assert_eq!(*seen.lock().unwrap(), expected);
+
+        let state = shared.read().unwrap().clone();
+        assert_eq!(state.is_online("Alice"), Some(false));
+        assert_eq!(state.is_online("bob"), Some(false));
+        assert_eq!(state.is_online("carol"), None);
+    }
+
+    #[test]
+    fn test_sasl_and_alt_nicks() {
+        let config = Config::new("localhost")
+            .nick("rusty")
+            .alt_nick("rusty_")
+            .sasl("rusty", "sesame");
+
+        // `None` is a read timeout, letting the negotiator take a step
+        let incoming = [
+            Some(":irc.example.com CAP * LS :sasl multi-prefix"),
+            None,
+            None,
+            None,
+            Some(":irc.example.com CAP * ACK :sasl"),
+            Some("AUTHENTICATE +"),
+            Some(":irc.example.com 433 * rusty :Nickname is already in use"),
+            Some(":irc.example.com 433 * rusty_ :Nickname is already in use"),
+            Some(":irc.example.com 903 rusty__ :SASL authentication successful"),
+            Some(":irc.example.com 001 rusty__ :Welcome"),
+        ];
+
+        let (mock_conn, sent) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let state = client.state.clone();
+        assert!(client.join().is_err());
+
+        let sent = sent.lock().unwrap();
+        let position = |line: &str| sent.iter().position(|sent| sent == line).unwrap();
+        assert!(position("CAP LS 302") < position("CAP REQ :sasl"));
+        assert!(position("AUTHENTICATE PLAIN") < position("AUTHENTICATE cnVzdHkAcnVzdHkAc2VzYW1l"));
+        assert!(position("NICK rusty_") < position("NICK rusty__"));
+        assert!(position("NICK rusty__") < position("CAP END"));
+        assert_eq!(state.read().unwrap().nick, "rusty__");
+    }
+
+    #[test]
+    fn test_nicks_run_out() {
+        let config = Config::new("localhost").nick("rusty").alt_nick("rustbot");
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let log = sent.clone();
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_send_message().returning(move |message| {
+            log.lock().unwrap().push(message.to_string());
+            Ok(())
+        });
+
+        let mut server = Server::new(config, Box::new(MockIrcConnection::new()));
+        server
+            .isupport
+            .insert("NICKLEN".to_string(), "9".to_string());
+        while server.next_nick(&mut mock_conn).is_ok() {}
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec!["NICK rustbot", "NICK rustbot_", "NICK rustbot__"]
+        );
+        assert!(matches!(
+            server.next_nick(&mut mock_conn),
+            Err(ConnectionError::NicksExhausted)
+        ));
+    }
+
+    #[test]
+    fn test_reconnects() {
+        let config = Config::new("localhost").reconnect(
+            2,
+            Duration::from_millis(1),
+            Duration::from_millis(1),
+        );
+
+        let connects = Arc::new(Mutex::new(0));
+        let count = connects.clone();
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().returning(move |_| {
+            *count.lock().unwrap() += 1;
+            Ok(())
+        });
+        mock_conn.expect_send_message().returning(|_| Ok(()));
+        mock_conn
+            .expect_read()
+            .returning(|| Err(crate::connection::error::Error::ConnectionClosed));
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let events: Vec<Event> = client.events().iter().collect();
+        assert!(client.join().is_err());
+
+        assert_eq!(*connects.lock().unwrap(), 3);
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| **event == Event::Connected)
+                .count(),
+            3
+        );
+        assert_eq!(events.last(), Some(&Event::Disconnected));
+    }
+
+    #[test]
+    fn test_shutdown_interrupts_reconnect_delay() {
+        let config =
+            Config::new("localhost").reconnect(5, Duration::from_secs(60), Duration::from_secs(60));
+
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().returning(|_| Ok(()));
+        mock_conn.expect_send_message().returning(|_| Ok(()));
+        mock_conn
+            .expect_read()
+            .returning(|| Err(crate::connection::error::Error::ConnectionClosed));
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        let events = client.events();
+        while events.recv() != Ok(Event::Disconnected) {}
+
+        let started = Instant::now();
+        assert!(client.shutdown().is_ok());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_joins_wait_for_login_and_rejoin_after_kick() {
+        let config = Config::new("localhost")
+            .nick("rusty")
+            .nickserv("sesame")
+            .join_after_login(Duration::from_secs(60))
+            .rejoin_on_kick(Duration::ZERO)
+            .channel("#open")
+            .channel_with_key("#secret", "key");
+
+        // `None` is a read timeout, letting the negotiator take a step
+        let incoming = [
+            Some(":irc.example.com 001 rusty :Welcome"),
+            None,
+            None,
+            None,
+            None,
+            Some(":irc.example.com 900 rusty rusty!rusty@host rusty :You are now logged in"),
+            None,
+            None,
+            Some(":op!op@host KICK #secret rusty :bye"),
+            None,
+            None,
+        ];
+
+        let (mock_conn, sent) = scripted(incoming);
+
+        let client = Server::new(config, Box::new(mock_conn)).run();
+        assert!(client.join().is_err());
+
+        let sent = sent.lock().unwrap();
+        let position = |prefix: &str| sent.iter().position(|line| line.starts_with(prefix));
+        assert!(position("JOIN") > position("<- :irc.example.com 900"));
+        assert!(sent.contains(&"PRIVMSG NickServ :IDENTIFY rusty sesame".to_string()));
+        assert!(sent.contains(&"JOIN #secret,#open key".to_string()));
+        assert_eq!(sent.last().map(String::as_str), Some("JOIN #secret key"));
+    }
 }
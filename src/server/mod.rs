@@ -1,9 +1,27 @@
 mod channel;
 mod client;
-mod error;
+pub(crate) mod commands;
+pub(crate) mod error;
+mod event;
 mod irc_server;
+mod network_manager;
+mod presence;
+pub(crate) mod requests;
+mod scheduler;
+mod state;
+mod supervisor;
+mod throttle;
 mod user;
 
-pub use channel::Channel;
+pub use channel::{Channel, ChannelType, MaskEntry};
 pub use client::Client;
+pub use event::Event;
 pub use irc_server::Server;
+pub use network_manager::{NetworkEvent, NetworkManager};
+pub use requests::{ChannelModes, ListEntry, Pending, WhoEntry, WhoisInfo};
+pub use scheduler::TimerHandle;
+pub use state::StateSnapshot;
+pub use supervisor::{PluginFailure, PluginHandle};
+pub use user::{Statuses, User};
+
+pub(crate) use state::casefold;
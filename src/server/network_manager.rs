@@ -0,0 +1,402 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        mpsc::{self, Receiver, SyncSender},
+    },
+};
+
+use super::error::Error;
+use super::event::{EVENT_BUFFER, Event};
+use super::{Client, Server};
+use crate::connection::{Connection, IrcConnection};
+use crate::{Config, Flow, IrcMessage, IrcPlugin, PluginFilter};
+
+// Networks whose worker stopped for good, by name
+pub(crate) type Ended = Arc<Mutex<HashSet<String>>>;
+
+/// An event from one of a `NetworkManager`'s networks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkEvent {
+    pub network: String,
+    pub event: Event,
+}
+
+/// Runs connections to several networks side by side.
+///
+/// Plugins registered on the manager are shared: every network calls the same
+/// instance, which can tell them apart through `Server::network`. Their hooks
+/// run for each network, so `on_load` and `on_shutdown` are called once per
+/// network too. Events from every network are merged into `events`.
+///
+/// A network whose connection ended for good, after its last
+/// `Event::Disconnected`, is dropped from the manager and its name can be
+/// added again. Dropping the manager quits every network still running.
+///
+/// ```rust,no_run
+/// use irc_lib::{IrcClient, NetworkManager};
+///
+/// # fn main() -> irc_lib::Result<()> {
+/// let mut manager = NetworkManager::new();
+/// manager.add_network("libera", IrcClient::new("irc.libera.chat:6667").nick("rusty"))?;
+/// manager.add_network("oftc", IrcClient::new("irc.oftc.net:6667").nick("rusty"))?;
+///
+/// for NetworkEvent { network, event } in manager.events() {
+///     println!("{network}: {event:?}");
+/// }
+/// # Ok(())
+/// # }
+/// # use irc_lib::NetworkEvent;
+/// ```
+pub struct NetworkManager {
+    plugins: Vec<SharedPlugin>,
+    networks: HashMap<String, Client>,
+    ended: Ended,
+    event_snd: SyncSender<NetworkEvent>,
+    events: Receiver<NetworkEvent>,
+}
+
+impl NetworkManager {
+    pub fn new() -> Self {
+        let (event_snd, events) = mpsc::sync_channel(EVENT_BUFFER);
+        NetworkManager {
+            plugins: Vec::new(),
+            networks: HashMap::new(),
+            ended: Ended::default(),
+            event_snd,
+            events,
+        }
+    }
+
+    /// Shares `plugin` with every network, including those already running.
+    pub fn register_plugin(&mut self, plugin: impl IrcPlugin + 'static) -> crate::Result<()> {
+        self.remove_ended();
+        let plugin = SharedPlugin::new(Box::new(plugin));
+        for client in self.networks.values() {
+            client.register_plugin(plugin.clone())?;
+        }
+        self.plugins.push(plugin);
+
+        Ok(())
+    }
+
+    /// Connects to a new network, named `name` in events and `Server::network`.
+    pub fn add_network(&mut self, name: &str, config: Config) -> crate::Result<()> {
+        let connection = Connection::new(config.tls);
+        self.connect(name, config, Box::new(connection))
+    }
+
+    fn connect(
+        &mut self,
+        name: &str,
+        config: Config,
+        connection: Box<dyn IrcConnection>,
+    ) -> crate::Result<()> {
+        self.remove_ended();
+        if self.networks.contains_key(name) {
+            return Err(Error::DuplicateNetwork(name.to_string()).into());
+        }
+
+        let config = self.plugins.iter().fold(config, |config, plugin| {
+            config.register_plugin(plugin.clone())
+        });
+        let mut server = config.build_with(connection)?;
+        server.join_manager(name, self.event_snd.clone(), self.ended.clone());
+        self.networks.insert(name.to_string(), server.run());
+
+        Ok(())
+    }
+
+    /// Quits `name` and waits for its connection to close.
+    pub fn remove_network(&mut self, name: &str) -> crate::Result<()> {
+        self.remove_ended();
+        let client = self
+            .networks
+            .remove(name)
+            .ok_or_else(|| Error::UnknownNetwork(name.to_string()))?;
+
+        client.shutdown()
+    }
+
+    pub fn network(&self, name: &str) -> Option<&Client> {
+        self.networks.get(name).filter(|_| !self.has_ended(name))
+    }
+
+    pub fn networks(&self) -> impl Iterator<Item = &str> {
+        self.networks
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !self.has_ended(name))
+    }
+
+    /// Events from every network. Like `Client::events`, unread events are
+    /// dropped once 10,000 are waiting.
+    pub fn events(&self) -> &Receiver<NetworkEvent> {
+        &self.events
+    }
+
+    /// Quits every network, returning the first error any of them ended with.
+    pub fn shutdown(mut self) -> crate::Result<()> {
+        let mut result = Ok(());
+        for (_, client) in self.networks.drain() {
+            let shutdown = client.shutdown();
+            if result.is_ok() {
+                result = shutdown;
+            }
+        }
+
+        result
+    }
+
+    fn has_ended(&self, name: &str) -> bool {
+        self.ended
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(name)
+    }
+
+    // Forgets the networks whose worker stopped, or is about to
+    fn remove_ended(&mut self) {
+        let ended = std::mem::take(&mut *self.ended.lock().unwrap_or_else(PoisonError::into_inner));
+        for name in ended {
+            self.networks.remove(&name);
+        }
+    }
+}
+
+impl Drop for NetworkManager {
+    fn drop(&mut self) {
+        // Dropping a client waits for its worker, which only stops once told
+        // to quit. Every network is told first so they close side by side.
+        for client in self.networks.values() {
+            let _ = client.quit(Some("Client shutting down"));
+        }
+    }
+}
+
+impl Default for NetworkManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for NetworkManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NetworkManager")
+            .field("plugins", &self.plugins)
+            .field("networks", &self.networks.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// One plugin instance called by several networks, one at a time.
+#[derive(Clone, Debug)]
+struct SharedPlugin {
+    plugin: Arc<Mutex<Box<dyn IrcPlugin>>>,
+    // Read once, since they can't be borrowed through the lock
+    name: String,
+    priority: i32,
+    filter: Option<PluginFilter>,
+}
+
+impl SharedPlugin {
+    fn new(plugin: Box<dyn IrcPlugin>) -> Self {
+        SharedPlugin {
+            name: plugin.name().to_string(),
+            priority: plugin.priority(),
+            filter: plugin.filter(),
+            plugin: Arc::new(Mutex::new(plugin)),
+        }
+    }
+
+    // A panic in one network's call is contained there, the others carry on
+    fn lock(&self) -> MutexGuard<'_, Box<dyn IrcPlugin>> {
+        self.plugin.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl IrcPlugin for SharedPlugin {
+    fn message(&mut self, server: &Server, message: &IrcMessage) -> Flow {
+        self.lock().message(server, message)
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn filter(&self) -> Option<PluginFilter> {
+        self.filter.clone()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_load(&mut self, server: &Server) {
+        self.lock().on_load(server)
+    }
+
+    fn on_unload(&mut self, server: &Server) {
+        self.lock().on_unload(server)
+    }
+
+    fn on_register(&mut self, server: &Server) {
+        self.lock().on_register(server)
+    }
+
+    fn on_join(&mut self, server: &Server, channel: &str) {
+        self.lock().on_join(server, channel)
+    }
+
+    fn on_part(&mut self, server: &Server, channel: &str) {
+        self.lock().on_part(server, channel)
+    }
+
+    fn on_presence(&mut self, server: &Server, nick: &str, online: bool) {
+        self.lock().on_presence(server, nick, online)
+    }
+
+    fn on_outgoing(&mut self, server: &Server, message: &IrcMessage) {
+        self.lock().on_outgoing(server, message)
+    }
+
+    fn on_disconnect(&mut self, server: &Server) {
+        self.lock().on_disconnect(server)
+    }
+
+    fn on_shutdown(&mut self, server: &Server) {
+        self.lock().on_shutdown(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MockIrcConnection;
+    use crate::message::Command;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Debug)]
+    struct Counter(Arc<Mutex<Vec<String>>>);
+
+    impl IrcPlugin for Counter {
+        fn message(&mut self, server: &Server, message: &IrcMessage) -> Flow {
+            if message.command == Command::PrivMsg
+                && let Some(text) = message.get_message()
+            {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", server.network(), text));
+            }
+            Flow::Continue
+        }
+    }
+
+    fn connection(line: &'static str) -> Box<dyn IrcConnection> {
+        let mut incoming = VecDeque::from([line]);
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().returning(|_| Ok(()));
+        mock_conn.expect_send_message().returning(|_| Ok(()));
+        mock_conn
+            .expect_read()
+            .returning(move || match incoming.pop_front() {
+                Some(line) => Ok(Some(line.parse().unwrap())),
+                None => Err(crate::connection::error::Error::ConnectionClosed),
+            });
+        Box::new(mock_conn)
+    }
+
+    // The peer only hangs up once it has seen our QUIT
+    fn until_quit() -> Box<dyn IrcConnection> {
+        let quit = Arc::new(AtomicBool::new(false));
+        let sent = quit.clone();
+        let mut listed = false;
+        let mut mock_conn = MockIrcConnection::new();
+        mock_conn.expect_connect().returning(|_| Ok(()));
+        mock_conn.expect_send_message().returning(move |message| {
+            sent.fetch_or(message.starts_with("QUIT"), Ordering::SeqCst);
+            Ok(())
+        });
+        mock_conn.expect_read().returning(move || {
+            if quit.load(Ordering::SeqCst) {
+                Err(crate::connection::error::Error::ConnectionClosed)
+            } else if !std::mem::replace(&mut listed, true) {
+                Ok(Some(":irc.example.com CAP * LS :".parse().unwrap()))
+            } else {
+                Ok(None)
+            }
+        });
+        Box::new(mock_conn)
+    }
+
+    #[test]
+    fn test_shared_plugins_and_merged_events() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut manager = NetworkManager::new();
+        manager.register_plugin(Counter(seen.clone())).unwrap();
+
+        for (name, line) in [
+            ("one", ":a!a@h PRIVMSG #c :hello"),
+            ("two", ":b!b@h PRIVMSG #c :world"),
+        ] {
+            let config = Config::new("localhost:6667").nick("rusty");
+            manager.connect(name, config, connection(line)).unwrap();
+        }
+        let config = Config::new("localhost:6667").nick("rusty");
+        manager.connect("idle", config, until_quit()).unwrap();
+        assert!(matches!(
+            manager.add_network("idle", Config::new("localhost:6667")),
+            Err(crate::Error::Server(Error::DuplicateNetwork(_)))
+        ));
+        assert!(matches!(
+            manager.add_network("three", Config::new("localhost")),
+            Err(crate::Error::Config(_))
+        ));
+
+        let mut disconnected = Vec::new();
+        while disconnected.len() < 2 {
+            let NetworkEvent { network, event } = manager.events().recv().unwrap();
+            if event == Event::Disconnected {
+                disconnected.push(network);
+            }
+        }
+        disconnected.sort();
+        assert_eq!(disconnected, vec!["one", "two"]);
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(seen, vec!["one hello", "two world"]);
+
+        assert!(matches!(
+            manager.remove_network("three"),
+            Err(crate::Error::Server(Error::UnknownNetwork(_)))
+        ));
+
+        // Both connections are gone, so their names are free again
+        assert_eq!(manager.networks().collect::<Vec<_>>(), vec!["idle"]);
+        assert!(manager.network("one").is_none());
+        let config = Config::new("localhost:6667").nick("rusty");
+        manager
+            .connect("one", config, connection("PING :again"))
+            .unwrap();
+        assert!(manager.remove_network("one").is_err());
+        assert!(matches!(
+            manager.remove_network("two"),
+            Err(crate::Error::Server(Error::UnknownNetwork(_)))
+        ));
+        assert!(manager.remove_network("idle").is_ok());
+    }
+
+    #[test]
+    fn test_drop_quits_every_network() {
+        let mut manager = NetworkManager::new();
+        for name in ["one", "two"] {
+            let config = Config::new("localhost:6667").nick("rusty");
+            manager.connect(name, config, until_quit()).unwrap();
+        }
+        drop(manager);
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::message::MAX_LINE;
+
+/// A change to the watched nicks, from the `Client` or a plugin.
+#[derive(Debug)]
+pub(crate) enum Watch {
+    Add(String),
+    Remove(String),
+}
+
+/// The nicks we watch and whether they are online, kept up to date through
+/// `MONITOR` where the server supports it and by polling with `ISON` otherwise.
+#[derive(Debug, Default)]
+pub(crate) struct Presence {
+    // As first given, in the order they were added
+    watched: Vec<String>,
+    // Lowercased nicks on the server's MONITOR list, the rest are polled
+    monitored: HashSet<String>,
+    // Lowercased nicks a full MONITOR list refused, polled until reconnecting
+    refused: HashSet<String>,
+    // Whether the MONITOR list was sent for this connection
+    synced: bool,
+    // Nicks in each of our ISONs that wasn't sent yet, oldest first
+    asking: VecDeque<Vec<String>>,
+    // Every ISON sent and not answered yet, oldest first, with the nicks it
+    // asked about if it was ours. Plugins and `send_raw` can send them too
+    polling: VecDeque<Option<Vec<String>>>,
+    last_poll: Option<Instant>,
+    /// Watched nicks with whether they are online, once the server said.
+    pub(crate) online: HashMap<String, bool>,
+}
+
+impl Presence {
+    pub(crate) fn new(nicks: &[String]) -> Self {
+        let mut presence = Presence::default();
+        for nick in nicks {
+            presence.watch(nick);
+        }
+        presence
+    }
+
+    /// Starts watching `nick`, returning whether it is new.
+    pub(crate) fn watch(&mut self, nick: &str) -> bool {
+        if self.find(nick).is_some() {
+            return false;
+        }
+        self.watched.push(nick.to_string());
+        // Polled right away rather than at the next interval
+        self.last_poll = None;
+        true
+    }
+
+    /// Stops watching `nick`, returning whether the server monitored it.
+    pub(crate) fn unwatch(&mut self, nick: &str) -> bool {
+        let Some(watched) = self.find(nick).map(str::to_string) else {
+            return false;
+        };
+        self.watched.retain(|other| *other != watched);
+        self.online.remove(&watched);
+        self.refused.remove(&watched.to_ascii_lowercase());
+        self.monitored.remove(&watched.to_ascii_lowercase())
+    }
+
+    pub(crate) fn synced(&self) -> bool {
+        self.synced
+    }
+
+    /// `MONITOR + ...` lines for the watched nicks, as many as the server's
+    /// list has room for. Those that don't fit, or were refused, are polled
+    /// instead.
+    pub(crate) fn sync(&mut self, limit: Option<usize>) -> Vec<String> {
+        self.synced = true;
+        let room = limit
+            .unwrap_or(usize::MAX)
+            .saturating_sub(self.monitored.len());
+        let nicks: Vec<String> = self
+            .watched
+            .iter()
+            .filter(|nick| {
+                let nick = nick.to_ascii_lowercase();
+                !self.monitored.contains(&nick) && !self.refused.contains(&nick)
+            })
+            .take(room)
+            .cloned()
+            .collect();
+        self.monitored
+            .extend(nicks.iter().map(|nick| nick.to_ascii_lowercase()));
+
+        batch(&nicks, "MONITOR + ", ',')
+    }
+
+    /// Goes back to polling the nicks a full MONITOR list refused (`734`).
+    pub(crate) fn refused(&mut self, nicks: &str) {
+        for nick in nicks.split(',') {
+            let nick = nick.to_ascii_lowercase();
+            self.monitored.remove(&nick);
+            self.refused.insert(nick);
+        }
+        self.last_poll = None;
+    }
+
+    /// `ISON` lines for the nicks MONITOR doesn't cover, once `interval` has
+    /// passed since the last poll.
+    pub(crate) fn poll(&mut self, now: Instant, interval: Duration) -> Vec<String> {
+        if self
+            .last_poll
+            .is_some_and(|last| now.duration_since(last) < interval)
+        {
+            return Vec::new();
+        }
+        let nicks: Vec<String> = self
+            .watched
+            .iter()
+            .filter(|nick| !self.monitored.contains(&nick.to_ascii_lowercase()))
+            .cloned()
+            .collect();
+        if nicks.is_empty() {
+            return Vec::new();
+        }
+
+        self.last_poll = Some(now);
+        let lines = batch(&nicks, "ISON ", ' ');
+        for line in &lines {
+            let asked = line["ISON ".len()..].split(' ').map(str::to_string);
+            self.asking.push_back(asked.collect());
+        }
+        lines
+    }
+
+    /// Notes an `ISON` going out, whoever sent it, so each `303` is matched
+    /// with the `ISON` it answers.
+    pub(crate) fn sent_ison(&mut self, nicks: &[String]) {
+        let ours = self.asking.front().is_some_and(|asked| asked == nicks);
+        let asked = if ours { self.asking.pop_front() } else { None };
+        self.polling.push_back(asked);
+    }
+
+    /// Takes in a `303` reply: of the nicks the oldest `ISON` asked about,
+    /// those listed are online and the others aren't. Replies to `ISON`s we
+    /// didn't send change nothing.
+    pub(crate) fn apply_ison(&mut self, reply: &str) -> Vec<(String, bool)> {
+        let Some(Some(asked)) = self.polling.pop_front() else {
+            return Vec::new();
+        };
+        let online: Vec<&str> = reply.split_whitespace().collect();
+
+        asked
+            .iter()
+            .filter_map(|nick| {
+                let is_online = online.iter().any(|other| other.eq_ignore_ascii_case(nick));
+                self.set(nick, is_online)
+            })
+            .collect()
+    }
+
+    /// Takes in the targets of a `730` or `731` reply, which may be full
+    /// masks such as `nick!user@host`.
+    pub(crate) fn apply_monitor(&mut self, targets: &str, online: bool) -> Vec<(String, bool)> {
+        targets
+            .split(',')
+            .filter_map(|target| {
+                let nick = target.split_once('!').map_or(target, |(nick, _)| nick);
+                self.set(nick, online)
+            })
+            .collect()
+    }
+
+    // Records the status, returning it if it changed
+    fn set(&mut self, nick: &str, online: bool) -> Option<(String, bool)> {
+        let watched = self.find(nick)?.to_string();
+        match self.online.insert(watched.clone(), online) {
+            Some(before) if before == online => None,
+            _ => Some((watched, online)),
+        }
+    }
+
+    /// Forgets what belonged to a lost connection, but not who is online:
+    /// the new connection reports changes against it.
+    pub(crate) fn reset(&mut self) {
+        self.monitored.clear();
+        self.refused.clear();
+        self.synced = false;
+        self.asking.clear();
+        self.polling.clear();
+        self.last_poll = None;
+    }
+
+    /// Whether a watched nick is online, `None` until the server has said.
+    pub(crate) fn is_online(&self, nick: &str) -> Option<bool> {
+        self.online.get(self.find(nick)?).copied()
+    }
+
+    // Nicks are compared case-insensitively, as servers do
+    fn find(&self, nick: &str) -> Option<&str> {
+        self.watched
+            .iter()
+            .find(|watched| watched.eq_ignore_ascii_case(nick))
+            .map(String::as_str)
+    }
+}
+
+// Joins `nicks` into as few lines starting with `command` as fit
+fn batch(nicks: &[String], command: &str, separator: char) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+
+    for nick in nicks {
+        if !line.is_empty() && line.len() + 1 + nick.len() > MAX_LINE {
+            lines.push(std::mem::take(&mut line));
+        }
+        if line.is_empty() {
+            line.push_str(command);
+        } else {
+            line.push(separator);
+        }
+        line.push_str(nick);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nicks(nicks: &[&str]) -> Vec<String> {
+        nicks.iter().map(|nick| nick.to_string()).collect()
+    }
+
+    #[test]
+    fn test_monitor() {
+        let mut presence = Presence::new(&nicks(&["alice", "bob", "carol"]));
+        assert!(!presence.watch("Alice"));
+
+        assert_eq!(presence.sync(Some(2)), vec!["MONITOR + alice,bob"]);
+        assert_eq!(
+            presence.apply_monitor("Alice!al@host,bob!bob@host", true),
+            vec![("alice".to_string(), true), ("bob".to_string(), true)]
+        );
+        assert!(presence.apply_monitor("alice", true).is_empty());
+        assert_eq!(
+            presence.apply_monitor("bob", false),
+            vec![("bob".to_string(), false)]
+        );
+
+        // What didn't fit on the list is polled
+        let now = Instant::now();
+        assert_eq!(
+            presence.poll(now, Duration::from_secs(60)),
+            vec!["ISON carol"]
+        );
+        assert!(presence.poll(now, Duration::from_secs(60)).is_empty());
+
+        assert!(presence.unwatch("BOB"));
+        assert!(!presence.online.contains_key("bob"));
+        assert!(!presence.unwatch("carol"));
+        assert!(!presence.unwatch("dave"));
+    }
+
+    #[test]
+    fn test_ison() {
+        let mut presence = Presence::new(&nicks(&["alice", "bob"]));
+        let interval = Duration::from_secs(60);
+        let now = Instant::now();
+
+        assert_eq!(presence.poll(now, interval), vec!["ISON alice bob"]);
+        presence.sent_ison(&nicks(&["alice", "bob"]));
+        assert_eq!(
+            presence.apply_ison("ALICE"),
+            vec![("alice".to_string(), true), ("bob".to_string(), false)]
+        );
+
+        assert!(presence.poll(now + interval / 2, interval).is_empty());
+        assert_eq!(
+            presence.poll(now + interval, interval),
+            vec!["ISON alice bob"]
+        );
+        // Someone else's ISON goes out first, its reply isn't ours
+        presence.sent_ison(&nicks(&["alice"]));
+        presence.sent_ison(&nicks(&["alice", "bob"]));
+        assert!(presence.apply_ison("").is_empty());
+        assert_eq!(presence.apply_ison(""), vec![("alice".to_string(), false)]);
+        // Replies nobody asked for change nothing
+        assert!(presence.apply_ison("alice bob").is_empty());
+
+        // A full MONITOR list sends nicks back to polling
+        presence.reset();
+        assert_eq!(presence.sync(None), vec!["MONITOR + alice,bob"]);
+        assert!(presence.poll(now, interval).is_empty());
+        presence.refused("bob");
+        assert_eq!(presence.poll(now, interval), vec!["ISON bob"]);
+        // and keeps them there when more are watched
+        assert!(presence.watch("carol"));
+        assert_eq!(presence.sync(None), vec!["MONITOR + carol"]);
+        assert_eq!(presence.is_online("ALICE"), Some(false));
+        assert_eq!(presence.is_online("carol"), None);
+    }
+
+    #[test]
+    fn test_batch() {
+        let many: Vec<String> = (0..100).map(|n| format!("nick{n:05}")).collect();
+        let lines = batch(&many, "ISON ", ' ');
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE));
+        assert!(lines.iter().all(|line| line.starts_with("ISON nick")));
+        assert_eq!(
+            lines
+                .iter()
+                .map(|line| line.split(' ').count() - 1)
+                .sum::<usize>(),
+            100
+        );
+    }
+}
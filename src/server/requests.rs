@@ -0,0 +1,702 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use super::error::{Error, Result};
+use super::{
+    channel::ModeTypes,
+    user::{Statuses, User},
+};
+use crate::message::{Command, IrcMessage, Param};
+
+/// What the server told us about a nick in reply to `WHOIS`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WhoisInfo {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub realname: String,
+    pub server: String,
+    pub server_info: String,
+    pub operator: bool,
+    pub secure: bool,
+    pub idle: Option<Duration>,
+    /// Seconds since the epoch.
+    pub signon: Option<u64>,
+    /// Channels, with any status prefix the server sent such as `@`.
+    pub channels: Vec<String>,
+    pub account: Option<String>,
+    pub away: Option<String>,
+}
+
+/// A single `WHO` reply line.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WhoEntry {
+    pub channel: String,
+    pub user: String,
+    pub host: String,
+    pub server: String,
+    pub nick: String,
+    /// `H` or `G` for here or gone, followed by any status such as `*` or `@`.
+    pub flags: String,
+    pub hops: u32,
+    pub realname: String,
+}
+
+/// A channel listed in reply to `LIST`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListEntry {
+    pub channel: String,
+    pub users: usize,
+    pub topic: String,
+}
+
+/// A channel's modes, such as `+ntk` with `["key"]` as arguments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelModes {
+    pub channel: String,
+    pub modes: String,
+    pub args: Vec<String>,
+}
+
+/// The eventual reply to a request made through the `Client`.
+///
+/// Block on it with `wait`, or `.await` it from async code. Requests that get
+/// no reply within the configured `request_timeout` fail with `Error::Timeout`.
+///
+/// Once the server enables `labeled-response`, requests are labeled and get
+/// exactly the replies carrying their label. Otherwise replies are matched to
+/// requests by the target they name and by the order the server answers in.
+///
+/// ```rust,no_run
+/// # fn run(client: &irc_lib::Client) -> irc_lib::Result<()> {
+/// let info = client.whois("someone").wait()?;
+/// println!("{} is {}@{}", info.nick, info.user, info.host);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Pending<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    done: Condvar,
+}
+
+struct State<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Pending<T> {
+    /// Blocks until the reply arrives or the request fails.
+    pub fn wait(self) -> crate::Result<T> {
+        let mut state = self.shared.state.lock().map_err(|_| Error::LockPoisoned)?;
+        loop {
+            if let Some(result) = state.result.take() {
+                return Ok(result?);
+            }
+            state = self
+                .shared
+                .done
+                .wait(state)
+                .map_err(|_| Error::LockPoisoned)?;
+        }
+    }
+}
+
+impl<T> Future for Pending<T> {
+    type Output = crate::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Ok(mut state) = self.shared.state.lock() else {
+            return Poll::Ready(Err(Error::LockPoisoned.into()));
+        };
+
+        match state.result.take() {
+            Some(result) => Poll::Ready(Ok(result?)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for Pending<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let done = self
+            .shared
+            .state
+            .lock()
+            .is_ok_and(|state| state.result.is_some());
+        f.debug_struct("Pending").field("done", &done).finish()
+    }
+}
+
+// The worker's half of a `Pending`, failing it if dropped unanswered
+struct Completer<T> {
+    shared: Option<Arc<Shared<T>>>,
+}
+
+impl<T> Completer<T> {
+    fn complete(&mut self, result: Result<T>) {
+        let Some(shared) = self.shared.take() else {
+            return;
+        };
+        if let Ok(mut state) = shared.state.lock() {
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+        shared.done.notify_all();
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        self.complete(Err(Error::Disconnected));
+    }
+}
+
+fn pending<T>() -> (Completer<T>, Pending<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            result: None,
+            waker: None,
+        }),
+        done: Condvar::new(),
+    });
+
+    (
+        Completer {
+            shared: Some(shared.clone()),
+        },
+        Pending { shared },
+    )
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Offer {
+    Ignored,
+    Taken,
+    Done,
+}
+
+/// A request waiting on the server, fed every numeric until it has its answer.
+pub(crate) trait Query: Send + fmt::Debug {
+    /// The message to send, typing the target by the server's `CHANTYPES`.
+    fn message(&self, chantypes: &str) -> IrcMessage;
+
+    fn offer(&mut self, code: u16, args: &[String], chantypes: &str) -> Offer;
+
+    fn label(&self) -> Option<&str>;
+
+    /// Labels the request, after which every reply it is offered is its own.
+    fn set_label(&mut self, label: String);
+
+    /// Answers with what was collected, for a labeled response that ended.
+    fn finish(&mut self);
+
+    fn created(&self) -> Instant;
+
+    fn fail(&mut self, error: Error);
+}
+
+// Numerics collected so far, by code, with their arguments
+type Replies = [(u16, Vec<String>)];
+type Parser<T> = Box<dyn Fn(&str, &Replies) -> T + Send>;
+
+// Which numerics make up the answer to a request
+struct Spec {
+    replies: &'static [u16],
+    end: &'static [u16],
+    errors: &'static [u16],
+    /// Where replies name the request's target, if they do.
+    target_arg: Option<usize>,
+    /// Whether they only do when the target is a channel.
+    channel_only: bool,
+}
+
+struct Request<T> {
+    command: Command,
+    target: String,
+    spec: Spec,
+    collected: Vec<(u16, Vec<String>)>,
+    parse: Parser<T>,
+    completer: Completer<T>,
+    created: Instant,
+    label: Option<String>,
+}
+
+impl<T: Send> Query for Request<T> {
+    fn message(&self, chantypes: &str) -> IrcMessage {
+        let params = match self.target.as_str() {
+            "" => Vec::new(),
+            target if is_channel(target, chantypes) => {
+                vec![Param::Channel(target.to_string())]
+            }
+            target => vec![Param::Unknown(target.to_string())],
+        };
+        IrcMessage::new(None, self.command.clone(), params)
+    }
+
+    fn offer(&mut self, code: u16, args: &[String], chantypes: &str) -> Offer {
+        // Numerics name the target right after our own nick, labeled ones
+        // are ours whatever they name
+        let names_target = |index: usize| {
+            self.label.is_some()
+                || args
+                    .get(index)
+                    .is_some_and(|arg| arg.eq_ignore_ascii_case(&self.target))
+        };
+
+        if self.spec.errors.contains(&code) && names_target(1) {
+            let text = args.last().cloned().unwrap_or_default();
+            self.fail(Error::RequestFailed(format!("{}: {}", self.target, text)));
+            return Offer::Done;
+        }
+
+        if self.spec.end.contains(&code) && (self.target.is_empty() || names_target(1)) {
+            self.collected.push((code, args.to_vec()));
+            let reply = (self.parse)(&self.target, &self.collected);
+            self.completer.complete(Ok(reply));
+            return Offer::Done;
+        }
+
+        let target_arg = self
+            .spec
+            .target_arg
+            .filter(|_| !self.spec.channel_only || is_channel(&self.target, chantypes));
+        if self.spec.replies.contains(&code) && target_arg.is_none_or(names_target) {
+            self.collected.push((code, args.to_vec()));
+            return Offer::Taken;
+        }
+
+        Offer::Ignored
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    fn set_label(&mut self, label: String) {
+        self.label = Some(label);
+    }
+
+    fn finish(&mut self) {
+        let reply = (self.parse)(&self.target, &self.collected);
+        self.completer.complete(Ok(reply));
+    }
+
+    fn created(&self) -> Instant {
+        self.created
+    }
+
+    fn fail(&mut self, error: Error) {
+        self.completer.complete(Err(error));
+    }
+}
+
+impl<T> fmt::Debug for Request<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("command", &self.command)
+            .field("target", &self.target)
+            .field("collected", &self.collected.len())
+            .finish()
+    }
+}
+
+fn is_channel(target: &str, chantypes: &str) -> bool {
+    target.starts_with(|prefix| chantypes.contains(prefix))
+}
+
+fn request<T: Send + 'static>(
+    command: Command,
+    target: &str,
+    spec: Spec,
+    parse: impl Fn(&str, &Replies) -> T + Send + 'static,
+) -> (Box<dyn Query>, Pending<T>) {
+    let (completer, pending) = pending();
+    let request = Request {
+        command,
+        target: target.to_string(),
+        spec,
+        collected: Vec::new(),
+        parse: Box::new(parse),
+        completer,
+        created: Instant::now(),
+        label: None,
+    };
+
+    (Box::new(request), pending)
+}
+
+pub(crate) fn whois(nick: &str) -> (Box<dyn Query>, Pending<WhoisInfo>) {
+    let spec = Spec {
+        replies: &[301, 311, 312, 313, 317, 319, 330, 671],
+        end: &[318],
+        errors: &[401, 402],
+        target_arg: Some(1),
+        channel_only: false,
+    };
+
+    request(Command::Whois, nick, spec, |nick, replies| {
+        let mut info = WhoisInfo {
+            nick: nick.to_string(),
+            ..Default::default()
+        };
+
+        for (code, args) in replies {
+            let arg = |index: usize| args.get(index).cloned().unwrap_or_default();
+            match code {
+                301 => info.away = Some(arg(2)),
+                311 => {
+                    info.nick = arg(1);
+                    info.user = arg(2);
+                    info.host = arg(3);
+                    info.realname = arg(5);
+                }
+                312 => {
+                    info.server = arg(2);
+                    info.server_info = arg(3);
+                }
+                313 => info.operator = true,
+                317 => {
+                    info.idle = arg(2).parse().ok().map(Duration::from_secs);
+                    info.signon = arg(3).parse().ok();
+                }
+                319 => info
+                    .channels
+                    .extend(arg(2).split_whitespace().map(str::to_string)),
+                330 => info.account = Some(arg(2)),
+                671 => info.secure = true,
+                _ => (),
+            }
+        }
+
+        info
+    })
+}
+
+pub(crate) fn who(mask: &str) -> (Box<dyn Query>, Pending<Vec<WhoEntry>>) {
+    // Replies name the channel asked about, but not the mask matched
+    let spec = Spec {
+        replies: &[352],
+        end: &[315],
+        errors: &[],
+        target_arg: Some(1),
+        channel_only: true,
+    };
+
+    request(
+        Command::Unknown("WHO".to_string()),
+        mask,
+        spec,
+        |_, replies| {
+            replies
+                .iter()
+                .filter(|(code, _)| *code == 352)
+                .map(|(_, args)| {
+                    let arg = |index: usize| args.get(index).cloned().unwrap_or_default();
+                    // The last argument is "<hops> <realname>"
+                    let last = arg(7);
+                    let (hops, realname) = last.split_once(' ').unwrap_or((&last, ""));
+                    WhoEntry {
+                        channel: arg(1),
+                        user: arg(2),
+                        host: arg(3),
+                        server: arg(4),
+                        nick: arg(5),
+                        flags: arg(6),
+                        hops: hops.parse().unwrap_or_default(),
+                        realname: realname.to_string(),
+                    }
+                })
+                .collect()
+        },
+    )
+}
+
+// Each nick with their statuses in the channel
+type Names = Vec<(User, Statuses)>;
+
+pub(crate) fn names(channel: &str, types: ModeTypes) -> (Box<dyn Query>, Pending<Names>) {
+    let spec = Spec {
+        replies: &[353],
+        end: &[366],
+        errors: &[403],
+        target_arg: Some(2),
+        channel_only: false,
+    };
+
+    request(
+        Command::Unknown("NAMES".to_string()),
+        channel,
+        spec,
+        move |_, replies| {
+            replies
+                .iter()
+                .filter(|(code, _)| *code == 353)
+                .filter_map(|(_, args)| args.get(3))
+                .flat_map(|names| names.split_whitespace())
+                .map(|entry| User::from_names(entry, &types))
+                .collect()
+        },
+    )
+}
+
+pub(crate) fn list() -> (Box<dyn Query>, Pending<Vec<ListEntry>>) {
+    let spec = Spec {
+        replies: &[321, 322],
+        end: &[323],
+        errors: &[],
+        target_arg: None,
+        channel_only: false,
+    };
+
+    request(
+        Command::Unknown("LIST".to_string()),
+        "",
+        spec,
+        |_, replies| {
+            replies
+                .iter()
+                .filter(|(code, _)| *code == 322)
+                .map(|(_, args)| ListEntry {
+                    channel: args.get(1).cloned().unwrap_or_default(),
+                    users: args.get(2).and_then(|n| n.parse().ok()).unwrap_or_default(),
+                    topic: args.get(3).cloned().unwrap_or_default(),
+                })
+                .collect()
+        },
+    )
+}
+
+pub(crate) fn channel_modes(channel: &str) -> (Box<dyn Query>, Pending<ChannelModes>) {
+    let spec = Spec {
+        replies: &[],
+        end: &[324],
+        errors: &[403, 442],
+        target_arg: None,
+        channel_only: false,
+    };
+
+    request(Command::Mode, channel, spec, |channel, replies| {
+        let args = replies
+            .last()
+            .map(|(_, args)| args.as_slice())
+            .unwrap_or_default();
+        ChannelModes {
+            channel: channel.to_string(),
+            modes: args.get(2).cloned().unwrap_or_default(),
+            args: args.iter().skip(3).cloned().collect(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn feed(query: &mut dyn Query, lines: &[&str]) -> Vec<Offer> {
+        lines
+            .iter()
+            .map(|line| {
+                let message: IrcMessage = line.parse().unwrap();
+                let Command::Numeric(code) = message.command else {
+                    panic!("{line} is not a numeric");
+                };
+                query.offer(code, &message.args(), "#&")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_whois() {
+        let (mut query, pending) = whois("Alice");
+        assert_eq!(query.message("#&").to_string(), "WHOIS Alice");
+
+        let offers = feed(
+            query.as_mut(),
+            &[
+                ":srv 311 me alice ali host.example * :Alice Liddell",
+                ":srv 311 me bob bob host.example * :Not Alice",
+                ":srv 319 me alice :@#rust #wonderland",
+                ":srv 312 me alice irc.example.net :Example server",
+                ":srv 317 me alice 42 1700000000 :seconds idle, signon time",
+                ":srv 330 me alice alice_acct :is logged in as",
+                ":srv 671 me alice :is using a secure connection",
+                ":srv 318 me alice :End of /WHOIS list.",
+            ],
+        );
+        assert_eq!(offers[1], Offer::Ignored);
+        assert_eq!(offers.last(), Some(&Offer::Done));
+
+        assert_eq!(
+            pending.wait().unwrap(),
+            WhoisInfo {
+                nick: "alice".to_string(),
+                user: "ali".to_string(),
+                host: "host.example".to_string(),
+                realname: "Alice Liddell".to_string(),
+                server: "irc.example.net".to_string(),
+                server_info: "Example server".to_string(),
+                operator: false,
+                secure: true,
+                idle: Some(Duration::from_secs(42)),
+                signon: Some(1_700_000_000),
+                channels: vec!["@#rust".to_string(), "#wonderland".to_string()],
+                account: Some("alice_acct".to_string()),
+                away: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_whois_no_such_nick() {
+        let (mut query, pending) = whois("ghost");
+        feed(query.as_mut(), &[":srv 401 me ghost :No such nick/channel"]);
+
+        assert!(matches!(
+            pending.wait(),
+            Err(crate::Error::Server(Error::RequestFailed(reason))) if reason == "ghost: No such nick/channel"
+        ));
+    }
+
+    #[test]
+    fn test_who_names_list_and_modes() {
+        let (mut query, who) = who("#rust");
+        feed(
+            query.as_mut(),
+            &[
+                ":srv 352 me #rust ali host.example irc.example.net alice H@ :0 Alice Liddell",
+                ":srv 315 me #rust :End of /WHO list.",
+            ],
+        );
+        let entries = who.wait().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].nick, "alice");
+        assert_eq!(entries[0].flags, "H@");
+        assert_eq!(entries[0].realname, "Alice Liddell");
+
+        let (mut query, names) = names("#rust", ModeTypes::new(&HashMap::new()));
+        assert_eq!(query.message("#&").to_string(), "NAMES #rust");
+        feed(
+            query.as_mut(),
+            &[
+                ":srv 353 me = #rust :@alice bob",
+                ":srv 353 me = #other :carol",
+                ":srv 366 me #rust :End of /NAMES list.",
+            ],
+        );
+        let nicks: Vec<_> = names
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|(user, statuses)| (user.nick, statuses.highest()))
+            .collect();
+        assert_eq!(
+            nicks,
+            vec![("alice".into(), Some('@')), ("bob".into(), None)]
+        );
+
+        let (mut query, list) = list();
+        assert_eq!(query.message("#&").to_string(), "LIST");
+        feed(
+            query.as_mut(),
+            &[
+                ":srv 321 me Channel :Users Name",
+                ":srv 322 me #rust 42 :All things Rust",
+                ":srv 323 me :End of /LIST",
+            ],
+        );
+        assert_eq!(
+            list.wait().unwrap(),
+            vec![ListEntry {
+                channel: "#rust".to_string(),
+                users: 42,
+                topic: "All things Rust".to_string(),
+            }]
+        );
+
+        let (mut query, modes) = channel_modes("#rust");
+        assert_eq!(query.message("#&").to_string(), "MODE #rust");
+        feed(query.as_mut(), &[":srv 324 me #rust +ntk secret"]);
+        assert_eq!(
+            modes.wait().unwrap(),
+            ChannelModes {
+                channel: "#rust".to_string(),
+                modes: "+ntk".to_string(),
+                args: vec!["secret".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_who_targets() {
+        let replies = [
+            ":srv 352 me #other bob host.example irc.example.net bob H :0 Bob",
+            ":srv 352 me #rust ali host.example irc.example.net alice H :0 Alice",
+        ];
+
+        let (mut query, _) = who("#rust");
+        assert_eq!(
+            feed(query.as_mut(), &replies),
+            [Offer::Ignored, Offer::Taken]
+        );
+
+        let (mut query, _) = who("*.example");
+        assert_eq!(feed(query.as_mut(), &replies), [Offer::Taken, Offer::Taken]);
+
+        // Only a channel where the server says so
+        let local = ":srv 352 me * ali host.example irc.example.net alice H :0 Alice";
+        let local: IrcMessage = local.parse().unwrap();
+        let (mut query, _) = who("&local");
+        assert_eq!(query.offer(352, &local.args(), "#&"), Offer::Ignored);
+        assert_eq!(query.offer(352, &local.args(), "#"), Offer::Taken);
+        assert_eq!(
+            query.message("#&").params,
+            vec![Param::Channel("&local".to_string())]
+        );
+        assert_eq!(
+            query.message("#").params,
+            vec![Param::Unknown("&local".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_labeled_request() {
+        let (mut query, pending) = whois("alice");
+        query.set_label("7".to_string());
+
+        // Whatever the reply names, it carries our label
+        feed(
+            query.as_mut(),
+            &[":srv 311 me Alice_ ali host.example * :Alice Liddell"],
+        );
+        query.finish();
+        assert_eq!(pending.wait().unwrap().nick, "Alice_");
+    }
+
+    #[test]
+    fn test_dropped_request_fails_pending() {
+        let (query, pending) = whois("alice");
+        drop(query);
+
+        assert!(matches!(
+            pending.wait(),
+            Err(crate::Error::Server(Error::Disconnected))
+        ));
+    }
+}
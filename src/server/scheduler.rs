@@ -0,0 +1,454 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::Server;
+use super::error::{Error, Result};
+
+type Task = Box<dyn FnMut(&Server) + Send>;
+
+// Far enough out to never come, near enough for `Instant` to hold
+const NEVER: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// `delay` after `now`, where delays too long to represent never come.
+pub(crate) fn after(now: Instant, delay: Duration) -> Instant {
+    now + delay.min(NEVER)
+}
+
+/// Lets whoever scheduled a timer cancel it later on.
+#[derive(Clone, Debug)]
+pub struct TimerHandle(Arc<AtomicBool>);
+
+impl TimerHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) enum Schedule {
+    Once,
+    Every(Duration),
+    Cron(CronSchedule),
+}
+
+pub(crate) struct Timer {
+    next: Instant,
+    schedule: Schedule,
+    task: Task,
+    handle: TimerHandle,
+    // The plugin that scheduled it, whose code the task may be
+    owner: Option<u64>,
+}
+
+impl Timer {
+    pub(crate) fn new(
+        first: Instant,
+        schedule: Schedule,
+        task: impl FnMut(&Server) + Send + 'static,
+    ) -> Self {
+        Timer {
+            next: first,
+            schedule,
+            task: Box::new(task),
+            handle: TimerHandle(Arc::new(AtomicBool::new(false))),
+            owner: None,
+        }
+    }
+
+    pub(crate) fn owned_by(mut self, owner: Option<u64>) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    pub(crate) fn owner(&self) -> Option<u64> {
+        self.owner
+    }
+
+    pub(crate) fn handle(&self) -> TimerHandle {
+        self.handle.clone()
+    }
+
+    pub(crate) fn run(&mut self, server: &Server) {
+        (self.task)(server)
+    }
+
+    // When this timer should fire again after firing at `now`, if ever
+    fn following(&self, now: Instant) -> Option<Instant> {
+        match &self.schedule {
+            Schedule::Once => None,
+            // Missed ticks are skipped rather than fired in a burst
+            Schedule::Every(interval) => {
+                Some(after(self.next, *interval).max(after(now, *interval)))
+            }
+            Schedule::Cron(cron) => cron
+                .until_next(SystemTime::now())
+                .map(|wait| after(now, wait)),
+        }
+    }
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timer")
+            .field("next", &self.next)
+            .field("cancelled", &self.handle.is_cancelled())
+            .finish()
+    }
+}
+
+/// Timers waiting to fire, driven by the server loop's tick.
+///
+/// New timers arrive through a channel so plugins can schedule them through a
+/// shared `&Server`.
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    sender: Sender<Timer>,
+    receiver: Receiver<Timer>,
+    timers: Vec<Timer>,
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Scheduler {
+            sender,
+            receiver,
+            timers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add(&self, timer: Timer) -> TimerHandle {
+        let handle = timer.handle();
+        // We own the receiver, so this can't fail
+        let _ = self.sender.send(timer);
+        handle
+    }
+
+    /// Takes out every timer due at `now`, earliest first.
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<Timer> {
+        self.timers.extend(self.receiver.try_iter());
+        self.timers.retain(|timer| !timer.handle.is_cancelled());
+
+        let (mut due, pending) = std::mem::take(&mut self.timers)
+            .into_iter()
+            .partition(|timer| timer.next <= now);
+        self.timers = pending;
+        due.sort_by_key(|timer| timer.next);
+        due
+    }
+
+    /// Drops the timers a plugin scheduled, which must happen while its code
+    /// is still loaded.
+    pub(crate) fn remove_owned(&mut self, owner: u64) {
+        self.timers.extend(self.receiver.try_iter());
+        self.timers.retain(|timer| timer.owner != Some(owner));
+    }
+
+    /// Puts a timer that just fired back in line, if it repeats.
+    pub(crate) fn fired(&mut self, mut timer: Timer, now: Instant) {
+        if timer.handle.is_cancelled() {
+            return;
+        }
+
+        if let Some(next) = timer.following(now) {
+            timer.next = next;
+            self.timers.push(timer);
+        }
+    }
+}
+
+/// A classic five field cron expression: minute, hour, day of month, month and
+/// day of week, evaluated in UTC.
+///
+/// Each field accepts `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps
+/// (`*/15`, `0-30/10`). Sunday is both `0` and `7`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidSchedule(s.to_string());
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(invalid());
+        };
+
+        let mut weekday_mask = parse_field(weekdays, 0, 7).ok_or_else(invalid)?;
+        if weekday_mask & (1 << 7) != 0 {
+            weekday_mask |= 1;
+        }
+
+        Ok(CronSchedule {
+            minutes: parse_field(minutes, 0, 59).ok_or_else(invalid)?,
+            hours: parse_field(hours, 0, 23).ok_or_else(invalid)?,
+            days: parse_field(days, 1, 31).ok_or_else(invalid)?,
+            months: parse_field(months, 1, 12).ok_or_else(invalid)?,
+            weekdays: weekday_mask,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Option<u64> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step) {
+            mask |= 1 << value;
+        }
+    }
+    Some(mask)
+}
+
+impl CronSchedule {
+    fn date_matches(&self, days_since_epoch: u64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch + 4) % 7;
+
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        // Like cron, a restricted day of month and day of week match if either does
+        let date_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        };
+
+        self.months & (1 << month) != 0 && date_matches
+    }
+
+    // The first matching minute of the day from `from` on
+    fn time_from(&self, from: u64) -> Option<u64> {
+        (from / 60..24)
+            .filter(|hour| self.hours & (1 << hour) != 0)
+            .find_map(|hour| {
+                let first = if hour == from / 60 { from % 60 } else { 0 };
+                let minutes = self.minutes >> first << first;
+                (minutes != 0).then(|| hour * 60 + u64::from(minutes.trailing_zeros()))
+            })
+    }
+
+    /// The next matching minute since the epoch after `minute`, searching
+    /// day by day up to four years ahead.
+    fn next_minute(&self, minute: u64) -> Option<u64> {
+        let start = minute + 1;
+        let first_day = start / MINUTES_PER_DAY;
+
+        (first_day..first_day + 4 * 366)
+            .filter(|day| self.date_matches(*day))
+            .find_map(|day| {
+                let from = if day == first_day {
+                    start % MINUTES_PER_DAY
+                } else {
+                    0
+                };
+                Some(day * MINUTES_PER_DAY + self.time_from(from)?)
+            })
+    }
+
+    /// How long after `now` the next matching minute starts, if one comes
+    /// within four years.
+    pub(crate) fn until_next(&self, now: SystemTime) -> Option<Duration> {
+        let since_epoch = now.duration_since(UNIX_EPOCH).ok()?;
+        let minute = self.next_minute(since_epoch.as_secs() / 60)?;
+        Some(Duration::from_secs(minute * 60) - since_epoch)
+    }
+}
+
+// Howard Hinnant's days-to-civil algorithm, returning (year, month, day)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cron(expression: &str) -> CronSchedule {
+        expression.parse().unwrap()
+    }
+
+    // Minutes since the epoch for a UTC date and time
+    fn at(days_since_epoch: u64, hour: u64, minute: u64) -> u64 {
+        (days_since_epoch * 24 + hour) * 60 + minute
+    }
+
+    fn matches(expression: &str, minute: u64) -> bool {
+        cron(expression).next_minute(minute - 1) == Some(minute)
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(20_454), (2026, 1, 1));
+    }
+
+    #[test]
+    fn test_parse_cron() {
+        assert!("* * * * *".parse::<CronSchedule>().is_ok());
+        assert!("*/15 9-17 * * 1-5".parse::<CronSchedule>().is_ok());
+        assert!("0 0 1,15 * 7".parse::<CronSchedule>().is_ok());
+
+        for invalid in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(
+                matches!(
+                    invalid.parse::<CronSchedule>(),
+                    Err(Error::InvalidSchedule(_))
+                ),
+                "{invalid:?} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn test_cron_matches() {
+        // 2026-01-01 is a Thursday
+        let thursday = 20_454;
+
+        assert!(matches("* * * * *", at(thursday, 13, 37)));
+        assert!(matches("*/15 * * * *", at(thursday, 13, 45)));
+        assert!(!matches("*/15 * * * *", at(thursday, 13, 46)));
+        assert!(matches("30 9 * * 4", at(thursday, 9, 30)));
+        assert!(!matches("30 9 * * 1-5", at(thursday + 2, 9, 30)));
+        assert!(matches("0 0 1 1 *", at(thursday, 0, 0)));
+        assert!(matches("0 0 * * 0", at(thursday + 3, 0, 0)));
+        assert!(matches("0 0 * * 7", at(thursday + 3, 0, 0)));
+        // Day of month or day of week
+        assert!(matches("0 0 16 * 4", at(thursday + 7, 0, 0)));
+        assert!(matches("0 0 16 * 4", at(thursday + 15, 0, 0)));
+        assert!(!matches("0 0 16 * 4", at(thursday + 1, 0, 0)));
+    }
+
+    #[test]
+    fn test_cron_until_next() {
+        // 2026-01-01 is a Thursday
+        let thursday = 20_454;
+        let now = UNIX_EPOCH + Duration::from_secs(at(thursday, 13, 37) * 60 + 15);
+
+        let wait = |expression: &str| cron(expression).until_next(now);
+        assert_eq!(wait("* * * * *"), Some(Duration::from_secs(45)));
+        assert_eq!(
+            wait("37 13 * * *"),
+            Some(Duration::from_secs(24 * 3600 - 15))
+        );
+        assert_eq!(wait("0 14 * * *"), Some(Duration::from_secs(22 * 60 + 45)));
+        // Next Monday, then March 1st
+        assert_eq!(
+            cron("0 9 * * 1").next_minute(at(thursday, 13, 37)),
+            Some(at(thursday + 4, 9, 0))
+        );
+        assert_eq!(
+            cron("30 0 1 3 *").next_minute(at(thursday, 13, 37)),
+            Some(at(thursday + 59, 0, 30))
+        );
+        assert_eq!(wait("0 0 30 2 *"), None);
+    }
+
+    #[test]
+    fn test_delays_never_overflow() {
+        let now = Instant::now();
+        assert_eq!(after(now, Duration::MAX), now + NEVER);
+
+        let timer = Timer::new(now, Schedule::Every(Duration::MAX), |_| {});
+        assert!(timer.following(now).is_some_and(|next| next > now));
+    }
+
+    #[test]
+    fn test_due_and_fired() {
+        let mut scheduler = Scheduler::new();
+        let now = Instant::now();
+
+        scheduler.add(Timer::new(
+            now + Duration::from_secs(5),
+            Schedule::Once,
+            |_| {},
+        ));
+        let every = scheduler.add(Timer::new(
+            now,
+            Schedule::Every(Duration::from_secs(10)),
+            |_| {},
+        ));
+        let cancelled = scheduler.add(Timer::new(now, Schedule::Once, |_| {}));
+        cancelled.cancel();
+
+        let due = scheduler.due(now);
+        assert_eq!(due.len(), 1);
+        assert_eq!(
+            due[0].handle().0.as_ref() as *const _,
+            every.0.as_ref() as *const _
+        );
+        for timer in due {
+            scheduler.fired(timer, now);
+        }
+
+        assert_eq!(scheduler.due(now + Duration::from_secs(5)).len(), 1);
+        let repeated = scheduler.due(now + Duration::from_secs(10));
+        assert_eq!(repeated.len(), 1);
+
+        // A timer cancelled while running doesn't come back
+        every.cancel();
+        for timer in repeated {
+            scheduler.fired(timer, now + Duration::from_secs(10));
+        }
+        assert!(scheduler.due(now + Duration::from_secs(60)).is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use super::channel::Channel;
+use super::user::User;
+
+/// A consistent copy of what the worker knows, taken after the last message
+/// that changed it.
+///
+/// ```rust,no_run
+/// # fn run(client: &irc_lib::Client) {
+/// let state = client.state();
+/// for channel in state.channels.values() {
+///     let (name, users) = (&channel.name, channel.users.len());
+///     println!("{name}: {users} users, topic {:?}", channel.topic);
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct StateSnapshot {
+    pub nick: String,
+    pub registered: bool,
+    /// Our channels, by lowercased name.
+    pub channels: HashMap<String, Channel>,
+    /// Everyone in our channels, by lowercased nick, however many of them
+    /// they are in.
+    pub users: HashMap<String, User>,
+    /// Watched nicks with whether they are online, once the server has said.
+    pub presence: HashMap<String, bool>,
+    /// Capabilities the server offers, with their values if any, from `CAP LS`.
+    pub available_caps: HashMap<String, String>,
+    /// Capabilities the server acknowledged enabling.
+    pub capabilities: HashSet<String>,
+    /// `RPL_ISUPPORT` tokens, with an empty value for tokens without one.
+    pub isupport: HashMap<String, String>,
+}
+
+impl StateSnapshot {
+    /// Looks `name` up in any case.
+    pub fn channel(&self, name: &str) -> Option<&Channel> {
+        self.channels.get(&casefold(name))
+    }
+
+    /// Looks `nick` up in any case.
+    pub fn user(&self, nick: &str) -> Option<&User> {
+        self.users.get(&casefold(nick))
+    }
+
+    /// Every nick seen in any of our channels, as they last wrote it.
+    pub fn nicks(&self) -> HashSet<&str> {
+        self.users.values().map(|user| user.nick.as_str()).collect()
+    }
+
+    /// Whether a watched nick is online, `None` until the server has said.
+    pub fn is_online(&self, nick: &str) -> Option<bool> {
+        self.presence
+            .iter()
+            .find_map(|(watched, online)| watched.eq_ignore_ascii_case(nick).then_some(*online))
+    }
+
+    pub fn isupport(&self, token: &str) -> Option<&str> {
+        self.isupport.get(token).map(String::as_str)
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+/// Where the worker publishes snapshots for the `Client` to read.
+pub(crate) type SharedState = Arc<RwLock<Arc<StateSnapshot>>>;
+
+/// Applies the tokens of a `005` reply, whose first argument is our nick and
+/// last is the human readable "are supported by this server".
+pub(crate) fn apply_isupport(isupport: &mut HashMap<String, String>, args: &[String]) {
+    let Some(tokens) = args.get(1..args.len().saturating_sub(1)) else {
+        return;
+    };
+
+    for token in tokens {
+        if let Some(removed) = token.strip_prefix('-') {
+            isupport.remove(removed);
+            continue;
+        }
+        let (key, value) = token.split_once('=').unwrap_or((token, ""));
+        isupport.insert(key.to_string(), unescape(value));
+    }
+}
+
+// ISUPPORT values escape awkward characters as \xHH
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(index) = rest.find("\\x") {
+        unescaped.push_str(&rest[..index]);
+        let escaped = rest
+            .get(index + 2..index + 4)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                unescaped.push(char::from(byte));
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push_str("\\x");
+                rest = &rest[index + 2..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// The key channels and nicks are tracked under: servers compare them ASCII
+/// case-insensitively, so `#Rust` and `#rust` are the same channel.
+pub(crate) fn casefold(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// The prefixes channel names start with on this server, `#&` unless it says
+/// otherwise in `CHANTYPES`.
+pub(crate) fn chantypes(isupport: &HashMap<String, String>) -> &str {
+    isupport.get("CHANTYPES").map_or("#&", String::as_str)
+}
+
+/// The longest nick the server takes according to `NICKLEN`. Few servers
+/// say before registering us, so it's 30 as on most networks until then.
+pub(crate) fn nick_len(isupport: &HashMap<String, String>) -> usize {
+    isupport
+        .get("NICKLEN")
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(30)
+}
+
+/// How many targets one `command` line may carry according to `TARGMAX`, if
+/// the server sets a limit.
+pub(crate) fn max_targets(isupport: &HashMap<String, String>, command: &str) -> Option<usize> {
+    isupport.get("TARGMAX")?.split(',').find_map(|entry| {
+        let (name, limit) = entry.split_once(':')?;
+        if !name.eq_ignore_ascii_case(command) {
+            return None;
+        }
+        limit.parse().ok()
+    })
+}
+
+/// Whether a `CAP` reply is the last line of `CAP LS`, which continues
+/// with `CAP <nick> LS * :<capabilities>`.
+pub(crate) fn cap_list_ends(args: &[String]) -> bool {
+    args.get(1)
+        .is_some_and(|subcommand| subcommand.eq_ignore_ascii_case("LS"))
+        && !(args.len() > 3 && args[2] == "*")
+}
+
+/// Applies a `CAP` reply: `CAP <nick> <subcommand> [*] :<capabilities>`.
+pub(crate) fn apply_cap(
+    available: &mut HashMap<String, String>,
+    enabled: &mut HashSet<String>,
+    args: &[String],
+) {
+    let (Some(subcommand), Some(list)) = (args.get(1), args.last()) else {
+        return;
+    };
+    let caps = list.split_whitespace();
+
+    match subcommand.to_uppercase().as_str() {
+        "LS" | "NEW" => {
+            for cap in caps {
+                let (name, value) = cap.split_once('=').unwrap_or((cap, ""));
+                available.insert(name.to_string(), value.to_string());
+            }
+        }
+        "ACK" => {
+            for cap in caps {
+                match cap.strip_prefix('-') {
+                    Some(disabled) => enabled.remove(disabled),
+                    None => enabled.insert(cap.to_string()),
+                };
+            }
+        }
+        "DEL" => {
+            for cap in caps {
+                available.remove(cap);
+                enabled.remove(cap);
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IrcMessage;
+
+    fn args(line: &str) -> Vec<String> {
+        line.parse::<IrcMessage>().unwrap().args()
+    }
+
+    #[test]
+    fn test_apply_isupport() {
+        let mut isupport = HashMap::new();
+        apply_isupport(
+            &mut isupport,
+            &args(
+                ":srv 005 me CHANTYPES=#& EXCEPTS NETWORK=Example\\x20Net :are supported by this server",
+            ),
+        );
+        assert_eq!(isupport["CHANTYPES"], "#&");
+        assert_eq!(isupport["EXCEPTS"], "");
+        assert_eq!(isupport["NETWORK"], "Example Net");
+
+        apply_isupport(&mut isupport, &args(":srv 005 me -EXCEPTS :are supported"));
+        assert!(!isupport.contains_key("EXCEPTS"));
+    }
+
+    #[test]
+    fn test_max_targets() {
+        let mut isupport = HashMap::new();
+        assert_eq!(max_targets(&isupport, "JOIN"), None);
+
+        isupport.insert("TARGMAX".to_string(), "NAMES:1,JOIN:,PRIVMSG:4".to_string());
+        assert_eq!(max_targets(&isupport, "PRIVMSG"), Some(4));
+        assert_eq!(max_targets(&isupport, "names"), Some(1));
+        assert_eq!(max_targets(&isupport, "JOIN"), None);
+        assert_eq!(max_targets(&isupport, "KICK"), None);
+    }
+
+    #[test]
+    fn test_apply_cap() {
+        let mut available = HashMap::new();
+        let mut enabled = HashSet::new();
+
+        for line in [
+            ":srv CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL",
+            ":srv CAP * LS :away-notify",
+            ":srv CAP me ACK :multi-prefix away-notify",
+            ":srv CAP me DEL :away-notify",
+        ] {
+            apply_cap(&mut available, &mut enabled, &args(line));
+        }
+
+        assert!(!cap_list_ends(&args(":srv CAP * LS * :multi-prefix")));
+        assert!(cap_list_ends(&args(":srv CAP * LS :away-notify")));
+        assert!(cap_list_ends(&args(":srv CAP * LS :")));
+        assert!(!cap_list_ends(&args(":srv CAP me ACK :away-notify")));
+
+        assert_eq!(available["sasl"], "PLAIN,EXTERNAL");
+        assert!(!available.contains_key("away-notify"));
+        assert_eq!(enabled, HashSet::from(["multi-prefix".to_string()]));
+    }
+}
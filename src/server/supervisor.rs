@@ -0,0 +1,261 @@
+use std::{
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+    },
+};
+
+use super::error::{Error, Result};
+use crate::{IrcMessage, IrcPlugin, PluginFilter};
+
+/// Reported to the `Client` whenever a plugin panics.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PluginFailure {
+    pub plugin: String,
+    pub message: String,
+    /// How many times this plugin has failed so far.
+    pub failures: usize,
+    /// Whether the plugin has been disabled because of this failure.
+    pub disabled: bool,
+}
+
+/// Refers to one registered plugin, to remove, enable or disable just that
+/// one later on rather than every plugin of its name.
+#[derive(Clone, Debug)]
+pub struct PluginHandle {
+    id: u64,
+    commands: Sender<PluginCommand>,
+}
+
+impl PluginHandle {
+    pub(crate) fn new(id: u64, commands: Sender<PluginCommand>) -> Self {
+        PluginHandle { id, commands }
+    }
+
+    /// Removes the plugin, see `Server::remove_plugin`.
+    pub fn remove(&self) -> Result<()> {
+        self.send(PluginCommand::Remove(PluginRef::Id(self.id)))
+    }
+
+    /// See `Server::enable_plugin`.
+    pub fn enable(&self) -> Result<()> {
+        self.send(PluginCommand::Enable(PluginRef::Id(self.id)))
+    }
+
+    /// See `Server::disable_plugin`.
+    pub fn disable(&self) -> Result<()> {
+        self.send(PluginCommand::Disable(PluginRef::Id(self.id)))
+    }
+
+    fn send(&self, command: PluginCommand) -> Result<()> {
+        self.commands.send(command).map_err(|_| Error::Send)
+    }
+}
+
+/// Which plugins a command applies to.
+#[derive(Clone, Debug)]
+pub(crate) enum PluginRef {
+    Named(String),
+    Id(u64),
+}
+
+/// Hands out the ids of a server's plugins. The counter lives with the
+/// `Server` rather than in a static, of which a plugin library built against
+/// this crate would have its own copy.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PluginIds(Arc<AtomicU64>);
+
+impl PluginIds {
+    /// A fresh id for a plugin about to be registered.
+    #[inline(never)]
+    pub(crate) fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// A plugin along with its dispatch settings and how well it has been behaving.
+#[derive(Debug)]
+pub(crate) struct PluginSlot {
+    pub(crate) id: u64,
+    /// The plugin that registered this one, which it is removed along with.
+    pub(crate) owner: Option<u64>,
+    pub(crate) plugin: Box<dyn IrcPlugin>,
+    pub(crate) priority: i32,
+    pub(crate) filter: Option<PluginFilter>,
+    pub(crate) failures: usize,
+    pub(crate) enabled: bool,
+}
+
+impl PluginSlot {
+    pub(crate) fn new(plugin: Box<dyn IrcPlugin>, id: u64) -> Self {
+        PluginSlot {
+            id,
+            owner: None,
+            priority: plugin.priority(),
+            filter: plugin.filter(),
+            plugin,
+            failures: 0,
+            enabled: true,
+        }
+    }
+
+    /// Matches either the full name or, for type names, just the last path segment.
+    pub(crate) fn is_named(&self, name: &str) -> bool {
+        let full = self.plugin.name();
+        full == name || full.rsplit("::").next() == Some(name)
+    }
+
+    pub(crate) fn is(&self, plugin: &PluginRef) -> bool {
+        match plugin {
+            PluginRef::Named(name) => self.is_named(name),
+            PluginRef::Id(id) => self.id == *id,
+        }
+    }
+
+    pub(crate) fn wants(&self, message: &IrcMessage) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(message))
+    }
+
+    /// Runs `hook` against the plugin, containing any panic.
+    ///
+    /// Once a plugin has failed `max_failures` times it is disabled and no
+    /// longer called; disabled plugins yield `R::default()`.
+    pub(crate) fn call<R: Default>(
+        &mut self,
+        max_failures: usize,
+        hook: impl FnOnce(&mut dyn IrcPlugin) -> R,
+    ) -> std::result::Result<R, PluginFailure> {
+        if !self.enabled {
+            return Ok(R::default());
+        }
+
+        let plugin = self.plugin.as_mut();
+        panic::catch_unwind(AssertUnwindSafe(|| hook(plugin))).map_err(|payload| {
+            self.failures += 1;
+            self.enabled = self.failures < max_failures;
+
+            PluginFailure {
+                plugin: self.plugin.name().to_string(),
+                message: panic_message(payload.as_ref()),
+                failures: self.failures,
+                disabled: !self.enabled,
+            }
+        })
+    }
+}
+
+pub(crate) type PluginFactory = Box<dyn FnOnce() -> crate::Result<Box<dyn IrcPlugin>> + Send>;
+
+/// Changes to the plugin list requested while the worker is running.
+pub(crate) enum PluginCommand {
+    Register {
+        plugin: Box<dyn IrcPlugin>,
+        id: u64,
+        /// The plugin that registered it, if any.
+        owner: Option<u64>,
+    },
+    Remove(PluginRef),
+    Enable(PluginRef),
+    Disable(PluginRef),
+    /// Removes the named plugins, and only then builds their replacement.
+    Replace(String, PluginFactory),
+}
+
+impl fmt::Debug for PluginCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginCommand::Register { plugin, id, owner } => f
+                .debug_struct("Register")
+                .field("plugin", plugin)
+                .field("id", id)
+                .field("owner", owner)
+                .finish(),
+            PluginCommand::Remove(plugin) => f.debug_tuple("Remove").field(plugin).finish(),
+            PluginCommand::Enable(plugin) => f.debug_tuple("Enable").field(plugin).finish(),
+            PluginCommand::Disable(plugin) => f.debug_tuple("Disable").field(plugin).finish(),
+            PluginCommand::Replace(name, _) => f.debug_tuple("Replace").field(name).finish(),
+        }
+    }
+}
+
+/// Orders plugins by descending priority, keeping registration order for ties.
+pub(crate) fn sort_by_priority(plugins: &mut [PluginSlot]) {
+    plugins.sort_by_key(|slot| std::cmp::Reverse(slot.priority));
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Flow, Server};
+
+    #[derive(Debug)]
+    struct Flaky;
+
+    impl IrcPlugin for Flaky {
+        fn message(&mut self, _server: &Server, message: &IrcMessage) -> Flow {
+            panic!("cannot handle {}", message.command);
+        }
+    }
+
+    #[test]
+    fn test_call_disables_after_max_failures() {
+        let mut slot = PluginSlot::new(Box::new(Flaky), 1);
+        let message: IrcMessage = "PING :1".parse().unwrap();
+        let server = Server::new(
+            crate::Config::new("localhost"),
+            Box::new(crate::connection::MockIrcConnection::new()),
+        );
+
+        let failure = slot
+            .call(2, |plugin| plugin.message(&server, &message))
+            .unwrap_err();
+        assert_eq!(failure.message, "cannot handle PING");
+        assert_eq!(failure.failures, 1);
+        assert!(!failure.disabled);
+        assert!(failure.plugin.ends_with("Flaky"));
+
+        let failure = slot
+            .call(2, |plugin| plugin.message(&server, &message))
+            .unwrap_err();
+        assert!(failure.disabled);
+
+        assert_eq!(
+            slot.call(2, |_| -> Flow { unreachable!() }),
+            Ok(Flow::Continue)
+        );
+    }
+
+    #[test]
+    fn test_plugin_ids_are_shared_by_clones() {
+        let ids = PluginIds::default();
+        let client_ids = ids.clone();
+
+        assert_eq!(ids.next(), 1);
+        assert_eq!(client_ids.next(), 2);
+        assert_eq!(PluginIds::default().next(), 1);
+    }
+
+    #[test]
+    fn test_is_named() {
+        let slot = PluginSlot::new(Box::new(Flaky), 1);
+        assert!(slot.is_named("Flaky"));
+        assert!(slot.is_named(std::any::type_name::<Flaky>()));
+        assert!(!slot.is_named("laky"));
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use crate::config::FloodLimit;
+
+/// Keeps queued messages under the configured flood limit, like the penalty
+/// clocks servers use: every message moves the clock `interval` ahead, and
+/// nothing goes out that would move it more than `burst` intervals past now.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    limit: Option<FloodLimit>,
+    clock: Instant,
+}
+
+impl Throttle {
+    pub(crate) fn new(limit: Option<FloodLimit>) -> Self {
+        Throttle {
+            limit,
+            clock: Instant::now(),
+        }
+    }
+
+    pub(crate) fn permits(&self, now: Instant) -> bool {
+        match self.limit {
+            Some(limit) => {
+                self.clock.saturating_duration_since(now) + limit.interval
+                    <= limit.interval * limit.burst
+            }
+            None => true,
+        }
+    }
+
+    pub(crate) fn record(&mut self, now: Instant) {
+        let interval = self.limit.map_or(Duration::ZERO, |limit| limit.interval);
+        self.clock = self.clock.max(now) + interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::new(Some(FloodLimit {
+            burst: 3,
+            interval: Duration::from_secs(2),
+        }));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(throttle.permits(start));
+            throttle.record(start);
+        }
+        assert!(!throttle.permits(start));
+        assert!(!throttle.permits(start + Duration::from_secs(1)));
+        assert!(throttle.permits(start + Duration::from_secs(2)));
+
+        let unlimited = Throttle::new(None);
+        assert!(unlimited.permits(start));
+    }
+}
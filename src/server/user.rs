@@ -1,66 +1,176 @@
-#[derive(Clone, Debug)]
+use super::channel::ModeTypes;
+
+/// Someone we share a channel with, one record however many channels that is.
+///
+/// What's known beyond the nick depends on what the server tells us: the
+/// hostmask comes with their messages and the `WHO` sent on joining, the
+/// account needs WHOX, `account-notify`, `extended-join` or `account-tag`, the
+/// away status `away-notify`.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct User {
     pub nick: String,
-    pub r#type: UserType,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    /// The services account they are logged in to.
+    pub account: Option<String>,
+    /// Their away message, while they are away. Empty when only a `WHO`
+    /// reply told us they are.
+    pub away: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum UserType {
-    Regular,
-    Op,
-    HalfOp,
+/// Someone's statuses in one channel, as the prefixes the server lists in
+/// ISUPPORT `PREFIX` such as `@` and `+`, highest first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Statuses(String);
+
+impl Statuses {
+    /// Whether they have the status shown as `prefix`, e.g. `@` for op.
+    pub fn has(&self, prefix: char) -> bool {
+        self.0.contains(prefix)
+    }
+
+    /// Their highest status, the prefix shown in front of their nick.
+    pub fn highest(&self) -> Option<char> {
+        self.0.chars().next()
+    }
+
+    /// Every status they have, highest first.
+    pub fn prefixes(&self) -> &str {
+        &self.0
+    }
+
+    pub(crate) fn set(&mut self, prefix: char, adding: bool, types: &ModeTypes) {
+        self.0 = types
+            .prefixes()
+            .filter(|&status| {
+                if status == prefix {
+                    adding
+                } else {
+                    self.has(status)
+                }
+            })
+            .collect();
+    }
 }
 
 impl User {
     pub(crate) fn new(nick: &str) -> Self {
-        let (perm, nick) = Self::parse_nick(nick);
-
-        let perm = match perm {
-            Some('@') => UserType::Op,
-            Some('%') => UserType::HalfOp,
-            _ => UserType::Regular,
-        };
-
         User {
             nick: nick.to_string(),
-            r#type: perm,
+            ..Default::default()
         }
     }
 
-    fn parse_nick(input: &str) -> (Option<char>, &str) {
-        let mut chars = input.chars();
-        let prefix = match chars.next() {
-            Some(c) if c == '@' || c == '%' => Some(c),
-            Some(_c) => return (None, input),
-            None => return (None, ""),
+    /// Parses an entry of a `353` reply: a nick behind any status prefixes,
+    /// followed by the hostmask with `userhost-in-names`.
+    pub(crate) fn from_names(entry: &str, types: &ModeTypes) -> (Self, Statuses) {
+        let (prefixes, mask) = Self::parse_nick(entry, types);
+        let mut statuses = Statuses::default();
+        for prefix in prefixes.chars() {
+            statuses.set(prefix, true, types);
+        }
+
+        let (nick, hostmask) = mask.split_once('!').unwrap_or((mask, ""));
+        let (user, host) = match hostmask.split_once('@') {
+            Some((user, host)) => (Some(user.to_string()), Some(host.to_string())),
+            None => (None, None),
+        };
+
+        let user = User {
+            user,
+            host,
+            ..User::new(nick)
         };
-        (prefix, chars.as_str())
+        (user, statuses)
+    }
+
+    // Splits off the status prefixes, several of them with `multi-prefix`
+    fn parse_nick<'a>(input: &'a str, types: &ModeTypes) -> (&'a str, &'a str) {
+        let nick = input.trim_start_matches(|prefix| types.prefixes().any(|p| p == prefix));
+        (&input[..input.len() - nick.len()], nick)
+    }
+
+    /// Takes in what a message from them says about them, from its prefix
+    /// and `account` tag.
+    pub(crate) fn update(
+        &mut self,
+        user: Option<&String>,
+        host: Option<&String>,
+        account: Option<&str>,
+    ) {
+        if let Some(user) = user {
+            self.user = Some(user.clone());
+        }
+        if let Some(host) = host {
+            self.host = Some(host.clone());
+        }
+        if let Some(account) = account {
+            self.account = Some(account.to_string());
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use super::*;
 
+    fn prefix(prefix: &str) -> ModeTypes {
+        ModeTypes::new(&HashMap::from([("PREFIX".to_string(), prefix.to_string())]))
+    }
+
     #[test]
     fn test_parse_nick() {
-        assert_eq!(User::parse_nick("nick"), (None, "nick"));
-        assert_eq!(User::parse_nick("@nick"), (Some('@'), "nick"));
-        assert_eq!(User::parse_nick("%nick"), (Some('%'), "nick"));
+        let types = prefix("(qaohv)~&@%+");
+        assert_eq!(User::parse_nick("nick", &types), ("", "nick"));
+        assert_eq!(User::parse_nick("@nick", &types), ("@", "nick"));
+        assert_eq!(User::parse_nick("%nick", &types), ("%", "nick"));
+        assert_eq!(User::parse_nick("@+nick", &types), ("@+", "nick"));
+        assert_eq!(User::parse_nick("~&nick", &types), ("~&", "nick"));
     }
 
     #[test]
-    fn test_new() {
-        let user = User::new("nick");
+    fn test_from_names() {
+        let types = prefix("(qaohv)~&@%+");
+        let (user, statuses) = User::from_names("nick", &types);
+        assert_eq!(user.nick, "nick");
+        assert_eq!(statuses, Statuses::default());
+
+        let (user, statuses) = User::from_names("@nick", &types);
         assert_eq!(user.nick, "nick");
-        assert_eq!(user.r#type, UserType::Regular);
+        assert_eq!(statuses.highest(), Some('@'));
 
-        let user = User::new("@nick");
+        let (user, statuses) = User::from_names("+%nick", &types);
         assert_eq!(user.nick, "nick");
-        assert_eq!(user.r#type, UserType::Op);
+        assert_eq!(statuses.prefixes(), "%+");
 
-        let user = User::new("%nick");
+        let (user, statuses) = User::from_names("+nick!ident@host.example", &types);
         assert_eq!(user.nick, "nick");
-        assert_eq!(user.r#type, UserType::HalfOp);
+        assert_eq!(user.user.as_deref(), Some("ident"));
+        assert_eq!(user.host.as_deref(), Some("host.example"));
+        assert!(statuses.has('+'));
+
+        // Only what the server lists in PREFIX is a status
+        let (user, statuses) = User::from_names("!~nick", &prefix("(Yov)!@+"));
+        assert_eq!(user.nick, "~nick");
+        assert_eq!(statuses.prefixes(), "!");
+    }
+
+    #[test]
+    fn test_set_status() {
+        let types = prefix("(ohv)@%+");
+        let mut statuses = Statuses::default();
+        statuses.set('+', true, &types);
+        statuses.set('@', true, &types);
+        statuses.set('%', true, &types);
+        assert_eq!(statuses.prefixes(), "@%+");
+
+        statuses.set('@', false, &types);
+        assert_eq!(statuses.prefixes(), "%+");
+        statuses.set('+', false, &types);
+        statuses.set('%', false, &types);
+        assert_eq!(statuses, Statuses::default());
     }
 }
@@ -0,0 +1,118 @@
+//! Thin wrappers around `tracing` so call sites compile to nothing when the
+//! `tracing` feature is off.
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)*);
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::error!($($arg)*);
+    };
+}
+
+// `NICKSERV` and `NS` are the aliases many servers have for messaging services
+#[cfg(feature = "tracing")]
+const SENSITIVE_COMMANDS: [&str; 5] = ["PASS", "AUTHENTICATE", "OPER", "NICKSERV", "NS"];
+
+#[cfg(feature = "tracing")]
+const SERVICES_NICKS: [&str; 2] = ["NickServ", "NS"];
+
+/// Hides the arguments of commands carrying credentials before they reach a subscriber.
+#[cfg(feature = "tracing")]
+pub(crate) fn redact(line: &str) -> std::borrow::Cow<'_, str> {
+    let line = line.trim_end();
+    let command_start = match line.strip_prefix(':') {
+        Some(rest) => line.len() - rest.len() + rest.find(' ').map_or(rest.len(), |i| i + 1),
+        None => 0,
+    };
+    let command = line[command_start..].split(' ').next().unwrap_or_default();
+
+    let mut kept = command_start + command.len();
+    // `IDENTIFY` and the like go to services as ordinary messages, to
+    // `NickServ@services.example` on some networks, or to other services bots
+    if command.eq_ignore_ascii_case("PRIVMSG") {
+        let rest = line[kept..].trim_start();
+        let (target, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        let nick = target.split('@').next().unwrap_or_default();
+        let identify = text.trim_start_matches(':').split(' ').next();
+        if !SERVICES_NICKS
+            .iter()
+            .any(|services| nick.eq_ignore_ascii_case(services))
+            && !identify.is_some_and(|word| word.eq_ignore_ascii_case("IDENTIFY"))
+        {
+            return line.into();
+        }
+        kept += line[kept..].find(target).unwrap_or(0) + target.len();
+    } else if !SENSITIVE_COMMANDS
+        .iter()
+        .any(|sensitive| command.eq_ignore_ascii_case(sensitive))
+    {
+        return line.into();
+    }
+
+    format!("{} <redacted>", &line[..kept]).into()
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("PASS hunter2\r\n"), "PASS <redacted>");
+        assert_eq!(
+            redact("AUTHENTICATE dXNlcgB1c2VyAHBhc3M="),
+            "AUTHENTICATE <redacted>"
+        );
+        assert_eq!(redact("oper admin secret"), "oper <redacted>");
+        assert_eq!(
+            redact(":server AUTHENTICATE +"),
+            ":server AUTHENTICATE <redacted>"
+        );
+        assert_eq!(
+            redact("PRIVMSG NickServ :IDENTIFY rusty hunter2"),
+            "PRIVMSG NickServ <redacted>"
+        );
+        assert_eq!(
+            redact("privmsg nickserv :identify hunter2"),
+            "privmsg nickserv <redacted>"
+        );
+        assert_eq!(
+            redact("PRIVMSG NS :IDENTIFY hunter2"),
+            "PRIVMSG NS <redacted>"
+        );
+        assert_eq!(
+            redact("PRIVMSG NickServ@services.example :IDENTIFY hunter2"),
+            "PRIVMSG NickServ@services.example <redacted>"
+        );
+        assert_eq!(
+            redact("PRIVMSG AuthServ :identify rusty hunter2"),
+            "PRIVMSG AuthServ <redacted>"
+        );
+        assert_eq!(redact("NS IDENTIFY hunter2"), "NS <redacted>");
+        assert_eq!(redact("NICKSERV IDENTIFY hunter2"), "NICKSERV <redacted>");
+        assert_eq!(
+            redact("PRIVMSG #channel :PASS it on"),
+            "PRIVMSG #channel :PASS it on"
+        );
+    }
+}
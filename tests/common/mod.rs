@@ -56,7 +56,11 @@ impl Drop for TestHarness {
     fn drop(&mut self) {
         while let Some(client) = self.clients.pop() {
             match Rc::try_unwrap(client) {
-                Ok(client) => client.shutdown(),
+                Ok(client) => {
+                    if let Err(err) = client.shutdown() {
+                        eprintln!("Client did not shut down cleanly: {:?}", err)
+                    }
+                }
                 Err(err) => eprintln!(
                     "======== Could not shutdown client ========\n {:?} \n ===========================",
                     err
@@ -15,6 +15,7 @@ fn send_message() {
             .nick("sender")
             .channel(channel)
             .build()
+            .expect("valid config")
             .run(),
     );
     let irc_receiver = harness.register_client(
@@ -22,6 +23,7 @@ fn send_message() {
             .nick("receiver")
             .channel(channel)
             .build()
+            .expect("valid config")
             .run(),
     );
 
@@ -66,6 +68,7 @@ fn plugin_replies_to_messages() {
             .nick("sender")
             .channel(channel)
             .build()
+            .expect("valid config")
             .run(),
     );
     let _replier = harness.register_client(
@@ -74,6 +77,7 @@ fn plugin_replies_to_messages() {
             .channel(channel)
             .register_plugin(common::EchoPlugin)
             .build()
+            .expect("valid config")
             .run(),
     );
 
@@ -117,6 +121,7 @@ fn send_multiple_messages() {
             .nick("sender")
             .channel(channel)
             .build()
+            .expect("valid config")
             .run(),
     );
     let irc_receiver = harness.register_client(
@@ -124,6 +129,7 @@ fn send_multiple_messages() {
             .nick("receiver")
             .channel(channel)
             .build()
+            .expect("valid config")
             .run(),
     );
 